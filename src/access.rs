@@ -0,0 +1,608 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use libp2p::{
+    core::Endpoint,
+    gossipsub::{self, TopicHash, TopicSubscriptionFilter},
+    identity::Keypair,
+    request_response::{self, json, ProtocolSupport},
+    swarm::{
+        ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent, THandlerOutEvent,
+        ToSwarm,
+    },
+    Multiaddr, PeerId, StreamProtocol,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error as StdError,
+    fmt, fs, io,
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::{direct_message, history};
+
+/// Peers hand each other the capabilities they hold over this protocol.
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/hippius/capability/1.0.0");
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const PRIVATE_PREFIX: &str = "private/";
+/// Refused subscriptions remembered per peer until it presents capabilities
+const MAX_REFUSED_PER_PEER: usize = 64;
+/// Capabilities remembered per peer; further topics are ignored
+const MAX_KNOWN_PER_PEER: usize = 64;
+/// Private topics whose payloads are sealed with a group key
+const ENCRYPTED_PREFIX: &str = "encrypted/";
+
+/// Capabilities presented by the requester; the answer lists the topics it
+/// should subscribe to again because its earlier subscription was refused.
+pub type Behaviour = json::Behaviour<Vec<Capability>, Vec<String>>;
+pub type Event = request_response::Event<Vec<Capability>, Vec<String>>;
+
+pub fn behaviour() -> Behaviour {
+    json::Behaviour::new(
+        [(PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+    )
+}
+
+/// Name of a private topic, which carries the owner's peer id so every node
+/// can check capabilities without any other configuration.
+pub fn private_topic(owner: &PeerId, name: &str) -> String {
+    format!("{}{}/{}", PRIVATE_PREFIX, owner, name)
+}
+
+pub fn encrypted_topic(owner: &PeerId, name: &str) -> String {
+    format!("{}{}/{}", ENCRYPTED_PREFIX, owner, name)
+}
+
+/// The owner of a private or encrypted topic, or `None` for open topics.
+pub fn topic_owner(topic: &str) -> Option<PeerId> {
+    let rest = topic
+        .strip_prefix(PRIVATE_PREFIX)
+        .or_else(|| topic.strip_prefix(ENCRYPTED_PREFIX))?;
+    rest.split_once('/')?.0.parse().ok()
+}
+
+/// Encrypted topics are private too: the same capabilities decide who takes part.
+pub fn is_private(topic: &str) -> bool {
+    topic.starts_with(PRIVATE_PREFIX) || is_encrypted(topic)
+}
+
+pub fn is_encrypted(topic: &str) -> bool {
+    topic.starts_with(ENCRYPTED_PREFIX)
+}
+
+#[derive(Debug)]
+pub enum AccessError {
+    /// Topics only have an owner when named with `private_topic`
+    NotPrivate(String),
+    Malformed(&'static str),
+    BadSignature,
+    Expired,
+    /// Granting on someone else's topic, or acting beyond a capability
+    NotAllowed,
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessError::NotPrivate(topic) => write!(f, "{} is not a private topic", topic),
+            AccessError::Malformed(what) => write!(f, "malformed {}", what),
+            AccessError::BadSignature => write!(f, "capability is not signed by the topic owner"),
+            AccessError::Expired => write!(f, "capability has expired"),
+            AccessError::NotAllowed => write!(f, "not allowed on this topic"),
+        }
+    }
+}
+
+impl StdError for AccessError {}
+
+/// What a capability allows. Publishing includes subscribing: gossipsub
+/// only takes messages on a topic from peers it lets subscribe to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rights {
+    Subscribe,
+    Publish,
+}
+
+impl fmt::Display for Rights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rights::Subscribe => write!(f, "subscribe"),
+            Rights::Publish => write!(f, "publish and subscribe"),
+        }
+    }
+}
+
+/// A topic owner's signed grant of rights on a private topic to one member.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capability {
+    pub topic: String,
+    pub member: String,
+    pub rights: Rights,
+    /// Seconds since the Unix epoch after which the grant lapses
+    pub expires: Option<u64>,
+    /// The owner's signature over everything above, base64
+    pub signature: String,
+}
+
+fn signed_bytes(topic: &str, member: &str, rights: &Rights, expires: Option<u64>) -> Vec<u8> {
+    serde_json::to_vec(&(PROTOCOL.as_ref(), topic, member, rights, expires)).expect("capability fields serialize")
+}
+
+impl Capability {
+    pub fn grant(owner: &Keypair, topic: &str, member: PeerId, rights: Rights, expires: Option<u64>) -> Result<Self, AccessError> {
+        match topic_owner(topic) {
+            None => return Err(AccessError::NotPrivate(topic.to_string())),
+            Some(topic_owner) if topic_owner != owner.public().to_peer_id() => return Err(AccessError::NotAllowed),
+            Some(_) => {}
+        }
+        let member = member.to_base58();
+        let signature = owner
+            .sign(&signed_bytes(topic, &member, &rights, expires))
+            .map_err(|_| AccessError::BadSignature)?;
+        Ok(Self {
+            topic: topic.to_string(),
+            member,
+            rights,
+            expires,
+            signature: BASE64.encode(signature),
+        })
+    }
+
+    /// Checks the owner's signature and expiry, returning the member.
+    pub fn verify(&self) -> Result<PeerId, AccessError> {
+        let owner = topic_owner(&self.topic).ok_or_else(|| AccessError::NotPrivate(self.topic.clone()))?;
+        let member = self.member.parse().map_err(|_| AccessError::Malformed("member"))?;
+        let signature = BASE64.decode(&self.signature).map_err(|_| AccessError::Malformed("signature"))?;
+        let key = direct_message::identity_key(&owner).map_err(|_| AccessError::BadSignature)?;
+        if !key.verify(&signed_bytes(&self.topic, &self.member, &self.rights, self.expires), &signature) {
+            return Err(AccessError::BadSignature);
+        }
+        if self.expires.is_some_and(|expires| expires <= history::now()) {
+            return Err(AccessError::Expired);
+        }
+        Ok(member)
+    }
+
+    /// The capability as a single string that can be passed to the member.
+    pub fn to_token(&self) -> String {
+        BASE64.encode(serde_json::to_vec(self).expect("capability serializes"))
+    }
+
+    pub fn from_token(token: &str) -> Result<Self, AccessError> {
+        let json = BASE64.decode(token).map_err(|_| AccessError::Malformed("token"))?;
+        serde_json::from_slice(&json).map_err(|_| AccessError::Malformed("token"))
+    }
+}
+
+/// What members publish on a private topic: the data together with the
+/// capability that allows its author to publish. The owner sends none.
+#[derive(Serialize, Deserialize)]
+struct Authorized {
+    capability: Option<Capability>,
+    /// Base64
+    data: String,
+}
+
+#[derive(Default)]
+struct State {
+    /// Capabilities held by this node, by topic
+    own: HashMap<String, Capability>,
+    /// Verified capabilities of other peers on topics we own or are members of
+    known: HashMap<PeerId, HashMap<String, Capability>>,
+    /// Peer whose gossipsub messages are being handled, set by `Gossipsub`
+    sender: Option<PeerId>,
+    /// Subscriptions refused for want of a capability, by peer
+    refused: HashMap<PeerId, HashSet<String>>,
+    /// Members removed from our own topics, whose capabilities no longer count
+    revoked: HashSet<(String, PeerId)>,
+}
+
+/// Who may publish and subscribe on private topics, shared between the
+/// node and gossipsub's subscription filter.
+#[derive(Clone)]
+pub struct AccessControl {
+    local: PeerId,
+    /// Our own capabilities are kept here so they survive restarts
+    path: PathBuf,
+    /// Revocations on our own topics, next to our capabilities
+    revoked_path: PathBuf,
+    state: Arc<Mutex<State>>,
+}
+
+impl AccessControl {
+    pub fn open(local: PeerId, path: PathBuf) -> io::Result<Self> {
+        let mut state = State::default();
+        if path.exists() {
+            let own: Vec<Capability> = serde_json::from_slice(&fs::read(&path)?)?;
+            for capability in own {
+                if capability.verify().is_ok_and(|member| member == local) {
+                    state.own.insert(capability.topic.clone(), capability);
+                }
+            }
+        }
+        let revoked_path = path.with_file_name("revoked.json");
+        if revoked_path.exists() {
+            let revoked: Vec<(String, String)> = serde_json::from_slice(&fs::read(&revoked_path)?)?;
+            for (topic, member) in revoked {
+                if let Ok(member) = member.parse() {
+                    state.revoked.insert((topic, member));
+                }
+            }
+        }
+        Ok(Self {
+            local,
+            path,
+            revoked_path,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("access control lock poisoned")
+    }
+
+    fn allows(&self, state: &State, topic: &str, peer: &PeerId, right: fn(&Rights) -> bool) -> bool {
+        let Some(owner) = topic_owner(topic) else {
+            return !is_private(topic);
+        };
+        if *peer == owner {
+            return true;
+        }
+        // Only the owner knows what it revoked; other nodes go by the capability
+        if state.revoked.contains(&(topic.to_string(), *peer)) {
+            return false;
+        }
+        let capability = if *peer == self.local {
+            state.own.get(topic)
+        } else {
+            state.known.get(peer).and_then(|known| known.get(topic))
+        };
+        capability.is_some_and(|capability| right(&capability.rights) && capability.verify().is_ok())
+    }
+
+    pub fn may_subscribe(&self, topic: &str, peer: &PeerId) -> bool {
+        self.allows(&self.state(), topic, peer, |_| true)
+    }
+
+    pub fn may_publish(&self, topic: &str, peer: &PeerId) -> bool {
+        self.allows(&self.state(), topic, peer, |rights| *rights == Rights::Publish)
+    }
+
+    /// Stores a capability granted to this node.
+    pub fn add_own(&self, capability: Capability) -> Result<(), Box<dyn StdError + Send + Sync>> {
+        if capability.verify()? != self.local {
+            return Err(AccessError::NotAllowed.into());
+        }
+        let mut state = self.state();
+        state.own.insert(capability.topic.clone(), capability);
+        let own: Vec<_> = state.own.values().collect();
+        fs::write(&self.path, serde_json::to_vec_pretty(&own)?)?;
+        Ok(())
+    }
+
+    /// Revokes, or reinstates, what `member` was granted on one of our
+    /// topics. Capabilities already handed out stop counting here, though
+    /// they still verify elsewhere until they expire.
+    pub fn set_revoked(&self, topic: &str, member: PeerId, revoked: bool) -> Result<(), Box<dyn StdError + Send + Sync>> {
+        match topic_owner(topic) {
+            None => return Err(AccessError::NotPrivate(topic.to_string()).into()),
+            Some(owner) if owner != self.local => return Err(AccessError::NotAllowed.into()),
+            Some(_) => {}
+        }
+        let mut state = self.state();
+        let key = (topic.to_string(), member);
+        let changed = if revoked {
+            if let Some(known) = state.known.get_mut(&member) {
+                known.remove(topic);
+            }
+            state.revoked.insert(key)
+        } else {
+            state.revoked.remove(&key)
+        };
+        if changed {
+            let revoked: Vec<_> = state
+                .revoked
+                .iter()
+                .map(|(topic, member)| (topic, member.to_base58()))
+                .collect();
+            fs::write(&self.revoked_path, serde_json::to_vec_pretty(&revoked)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn own(&self) -> Vec<Capability> {
+        self.state().own.values().cloned().collect()
+    }
+
+    /// Records capabilities presented by `peer` for topics we own or hold a
+    /// capability for; others could never be checked against anything here.
+    /// Returns the topics whose subscription from `peer` was refused and is
+    /// now allowed, and whether any capability was new to us.
+    pub fn present(&self, peer: PeerId, capabilities: Vec<Capability>) -> (Vec<String>, bool) {
+        let mut state = self.state();
+        let mut learned = false;
+        for capability in capabilities {
+            let relevant =
+                topic_owner(&capability.topic) == Some(self.local) || state.own.contains_key(&capability.topic);
+            if !relevant || !capability.verify().is_ok_and(|member| member == peer) {
+                continue;
+            }
+            let known = state.known.entry(peer).or_default();
+            if known.len() < MAX_KNOWN_PER_PEER || known.contains_key(&capability.topic) {
+                learned |= known.insert(capability.topic.clone(), capability).is_none();
+            }
+        }
+        let admitted: Vec<String> = state
+            .refused
+            .get(&peer)
+            .into_iter()
+            .flatten()
+            .filter(|topic| self.allows(&state, topic, &peer, |_| true))
+            .cloned()
+            .collect();
+        if let Some(refused) = state.refused.get_mut(&peer) {
+            refused.retain(|topic| !admitted.contains(topic));
+        }
+        (admitted, learned)
+    }
+
+    /// Drops what `peer` presented; it presents again when it reconnects.
+    pub fn forget_peer(&self, peer: &PeerId) {
+        let mut state = self.state();
+        state.refused.remove(peer);
+        state.known.remove(peer);
+    }
+
+    /// Wraps data for a private topic with our capability to publish it.
+    pub fn wrap(&self, topic: &str, data: &[u8]) -> Result<Vec<u8>, AccessError> {
+        if !is_private(topic) {
+            return Ok(data.to_vec());
+        }
+        if !self.may_publish(topic, &self.local) {
+            return Err(AccessError::NotAllowed);
+        }
+        let capability = match topic_owner(topic) {
+            Some(owner) if owner == self.local => None,
+            _ => self.state().own.get(topic).cloned(),
+        };
+        Ok(serde_json::to_vec(&Authorized {
+            capability,
+            data: BASE64.encode(data),
+        })
+        .expect("authorized message serializes"))
+    }
+
+    /// Checks that `source` may publish `data` on `topic` and returns the
+    /// data it carries. A capability attached to the message is remembered.
+    pub fn unwrap(&self, topic: &str, source: Option<PeerId>, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if !is_private(topic) {
+            return Ok(data.to_vec());
+        }
+        // Anonymous messages cannot be tied to a capability
+        let source = source.ok_or("unauthorized")?;
+        let message: Authorized = serde_json::from_slice(data).map_err(|_| "malformed")?;
+        if let Some(capability) = message.capability {
+            if capability.topic != topic {
+                return Err("unauthorized");
+            }
+            self.present(source, vec![capability]);
+        }
+        if !self.may_publish(topic, &source) {
+            return Err("unauthorized");
+        }
+        BASE64.decode(message.data).map_err(|_| "malformed")
+    }
+}
+
+/// Refuses subscriptions to private topics from peers, including this node,
+/// that hold no capability for them. Gossipsub asks the same question of
+/// messages it receives, so non-members' messages are dropped as well.
+pub struct MembershipFilter(AccessControl);
+
+impl TopicSubscriptionFilter for MembershipFilter {
+    fn can_subscribe(&mut self, topic: &TopicHash) -> bool {
+        let topic = topic.as_str();
+        let mut state = self.0.state();
+        // Without a sender the question is about our own subscription
+        let peer = state.sender.unwrap_or(self.0.local);
+        if self.0.allows(&state, topic, &peer, |_| true) {
+            return true;
+        }
+        if peer != self.0.local {
+            // Admitted once the peer presents a capability; a peer asking for
+            // many topics it cannot join is not remembered past the cap
+            let refused = state.refused.entry(peer).or_default();
+            if refused.len() < MAX_REFUSED_PER_PEER {
+                refused.insert(topic.to_string());
+            }
+        }
+        false
+    }
+}
+
+/// Gossipsub with a subscription filter that knows which peer it is
+/// filtering. Derefs to the inner behaviour.
+pub struct Gossipsub {
+    inner: gossipsub::Behaviour<history::Signatures, MembershipFilter>,
+    access: AccessControl,
+}
+
+impl Gossipsub {
+    pub fn new(
+        authenticity: gossipsub::MessageAuthenticity,
+        config: gossipsub::Config,
+        access: AccessControl,
+        signatures: history::Signatures,
+    ) -> Result<Self, &'static str> {
+        let inner = gossipsub::Behaviour::new_with_subscription_filter_and_transform(
+            authenticity,
+            config,
+            None,
+            MembershipFilter(access.clone()),
+            signatures,
+        )?;
+        Ok(Self { inner, access })
+    }
+}
+
+impl Deref for Gossipsub {
+    type Target = gossipsub::Behaviour<history::Signatures, MembershipFilter>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for Gossipsub {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl NetworkBehaviour for Gossipsub {
+    type ConnectionHandler = THandler<gossipsub::Behaviour<history::Signatures, MembershipFilter>>;
+    type ToSwarm = gossipsub::Event;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner.handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(connection_id, peer, local_addr, remote_addr)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(connection_id, maybe_peer, addresses, effective_role)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_outbound_connection(connection_id, peer, addr, role_override)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        self.inner.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        // Subscriptions are filtered while gossipsub handles this event
+        self.access.state().sender = Some(peer);
+        self.inner.on_connection_handler_event(peer, connection_id, event);
+        self.access.state().sender = None;
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        self.inner.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revoked_members_lose_access_until_granted_again() {
+        let owner = Keypair::generate_ed25519();
+        let local = owner.public().to_peer_id();
+        let dir = std::env::temp_dir().join(format!("hippius-access-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let access = AccessControl::open(local, dir.join("capabilities.json")).unwrap();
+
+        let topic = private_topic(&local, "board");
+        let member = Keypair::generate_ed25519().public().to_peer_id();
+        let capability = Capability::grant(&owner, &topic, member, Rights::Publish, None).unwrap();
+        access.present(member, vec![capability.clone()]);
+        assert!(access.may_publish(&topic, &member));
+
+        access.set_revoked(&topic, member, true).unwrap();
+        access.present(member, vec![capability.clone()]);
+        assert!(!access.may_subscribe(&topic, &member), "capability still counted after revocation");
+
+        // Revocations outlive a restart
+        let access = AccessControl::open(local, dir.join("capabilities.json")).unwrap();
+        access.present(member, vec![capability]);
+        assert!(!access.may_subscribe(&topic, &member));
+
+        access.set_revoked(&topic, member, false).unwrap();
+        assert!(access.may_publish(&topic, &member));
+
+        let elsewhere = private_topic(&PeerId::random(), "board");
+        assert!(access.set_revoked(&elsewhere, member, true).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn only_capabilities_for_our_topics_are_kept() {
+        let identity = Keypair::generate_ed25519();
+        let local = identity.public().to_peer_id();
+        let dir = std::env::temp_dir().join(format!("hippius-access-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let access = AccessControl::open(local, dir.join("capabilities.json")).unwrap();
+        let member = Keypair::generate_ed25519().public().to_peer_id();
+
+        // Someone else's topic means nothing here until we are a member too
+        let stranger = Keypair::generate_ed25519();
+        let theirs = private_topic(&stranger.public().to_peer_id(), "lounge");
+        let grant = |topic: &str, member| Capability::grant(&stranger, topic, member, Rights::Publish, None).unwrap();
+        assert_eq!(access.present(member, vec![grant(&theirs, member)]), (Vec::new(), false));
+        assert!(!access.may_publish(&theirs, &member));
+        access.add_own(grant(&theirs, local)).unwrap();
+        assert_eq!(access.present(member, vec![grant(&theirs, member)]), (Vec::new(), true));
+        assert!(access.may_publish(&theirs, &member));
+        assert_eq!(access.present(member, vec![grant(&theirs, member)]), (Vec::new(), false));
+
+        // Our own topics count, up to a limit per peer
+        let ours: Vec<_> = (0..MAX_KNOWN_PER_PEER + 1)
+            .map(|n| {
+                let topic = private_topic(&local, &format!("room-{}", n));
+                Capability::grant(&identity, &topic, member, Rights::Subscribe, None).unwrap()
+            })
+            .collect();
+        access.present(member, ours.clone());
+        let subscribed = ours.iter().filter(|capability| access.may_subscribe(&capability.topic, &member));
+        assert_eq!(subscribed.count(), MAX_KNOWN_PER_PEER - 1);
+
+        // Capabilities for another peer are not taken from this one
+        let other = PeerId::random();
+        access.present(other, ours.clone());
+        assert!(!access.may_subscribe(&ours[0].topic, &other));
+
+        access.forget_peer(&member);
+        assert!(!access.may_publish(&theirs, &member));
+        assert!(access.state().known.is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,679 @@
+use cid::{multihash::Multihash, Cid, Version};
+use futures::{channel::oneshot, AsyncReadExt, AsyncWriteExt, StreamExt};
+use libp2p::{PeerId, Stream, StreamProtocol};
+use libp2p_stream::{Control, OpenStreamError};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error as StdError,
+    fmt, io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::blockstore::{self, BlockError, Blockstore};
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/ipfs/bitswap/1.2.0");
+
+/// Largest block we hand out or accept, the limit Kubo enforces
+pub const MAX_BLOCK_SIZE: usize = 2 * 1024 * 1024;
+/// A message carries at least one block plus framing
+const MAX_MESSAGE_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WantType {
+    Block,
+    Have,
+}
+
+#[derive(Debug, PartialEq)]
+struct WantEntry {
+    cid: Cid,
+    cancel: bool,
+    want_type: WantType,
+    send_dont_have: bool,
+}
+
+/// The parts of a Bitswap 1.2 message this node uses. Wantlists are always
+/// sent as updates rather than full lists, and pending bytes are ignored.
+#[derive(Debug, Default, PartialEq)]
+struct Message {
+    wants: Vec<WantEntry>,
+    blocks: Vec<(Cid, Vec<u8>)>,
+    have: Vec<Cid>,
+    dont_have: Vec<Cid>,
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos).ok_or_else(|| invalid("truncated varint"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, value);
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Splits a protobuf message into its fields, skipping fixed-width ones.
+fn fields(buf: &[u8]) -> io::Result<Vec<(u64, Field<'_>)>> {
+    let mut pos = 0;
+    let mut fields = Vec::new();
+    while pos < buf.len() {
+        let key = get_varint(buf, &mut pos)?;
+        let field = match key & 7 {
+            0 => Field::Varint(get_varint(buf, &mut pos)?),
+            2 => {
+                let len = get_varint(buf, &mut pos)? as usize;
+                let bytes = buf.get(pos..pos.saturating_add(len)).ok_or_else(|| invalid("truncated field"))?;
+                pos += len;
+                Field::Bytes(bytes)
+            }
+            1 => {
+                pos += 8;
+                continue;
+            }
+            5 => {
+                pos += 4;
+                continue;
+            }
+            _ => return Err(invalid("unsupported wire type")),
+        };
+        fields.push((key >> 3, field));
+    }
+    Ok(fields)
+}
+
+/// Rebuilds a block's CID from the prefix Bitswap sends in its place.
+fn cid_from_prefix(prefix: &[u8], data: &[u8]) -> io::Result<Cid> {
+    let mut pos = 0;
+    let version = get_varint(prefix, &mut pos)?;
+    let codec = get_varint(prefix, &mut pos)?;
+    let hash_code = get_varint(prefix, &mut pos)?;
+    let hash_len = get_varint(prefix, &mut pos)? as usize;
+    let hash = blockstore::multihash(hash_code, data).map_err(|e| invalid(&e.to_string()))?;
+    // Truncated digests are legal in multihash but not something we produce or check
+    if hash.size() as usize != hash_len {
+        return Err(invalid("unsupported multihash length"));
+    }
+    match Version::try_from(version).map_err(|_| invalid("unknown CID version"))? {
+        Version::V0 => Cid::new_v0(hash).map_err(|_| invalid("bad CIDv0")),
+        Version::V1 => Ok(Cid::new_v1(codec, hash)),
+    }
+}
+
+fn cid_prefix(cid: &Cid) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    put_varint(&mut prefix, u64::from(cid.version()));
+    put_varint(&mut prefix, cid.codec());
+    put_varint(&mut prefix, cid.hash().code());
+    put_varint(&mut prefix, cid.hash().size() as u64);
+    prefix
+}
+
+impl Message {
+    fn is_empty(&self) -> bool {
+        self.wants.is_empty() && self.blocks.is_empty() && self.have.is_empty() && self.dont_have.is_empty()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if !self.wants.is_empty() {
+            let mut wantlist = Vec::new();
+            for want in &self.wants {
+                let mut entry = Vec::new();
+                put_bytes_field(&mut entry, 1, &want.cid.to_bytes());
+                put_varint_field(&mut entry, 2, 1);
+                put_varint_field(&mut entry, 3, want.cancel as u64);
+                put_varint_field(&mut entry, 4, (want.want_type == WantType::Have) as u64);
+                put_varint_field(&mut entry, 5, want.send_dont_have as u64);
+                put_bytes_field(&mut wantlist, 1, &entry);
+            }
+            put_bytes_field(&mut buf, 1, &wantlist);
+        }
+        for (cid, data) in &self.blocks {
+            let mut block = Vec::new();
+            put_bytes_field(&mut block, 1, &cid_prefix(cid));
+            put_bytes_field(&mut block, 2, data);
+            put_bytes_field(&mut buf, 3, &block);
+        }
+        for (cids, presence) in [(&self.have, 0), (&self.dont_have, 1)] {
+            for cid in cids {
+                let mut entry = Vec::new();
+                put_bytes_field(&mut entry, 1, &cid.to_bytes());
+                put_varint_field(&mut entry, 2, presence);
+                put_bytes_field(&mut buf, 4, &entry);
+            }
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let parse_cid = |bytes: &[u8]| Cid::try_from(bytes).map_err(|_| invalid("bad CID"));
+        let mut message = Message::default();
+        for (field, value) in fields(buf)? {
+            match (field, value) {
+                (1, Field::Bytes(wantlist)) => {
+                    for (field, value) in fields(wantlist)? {
+                        let (1, Field::Bytes(entry)) = (field, value) else { continue };
+                        let mut want = WantEntry {
+                            cid: Cid::default(),
+                            cancel: false,
+                            want_type: WantType::Block,
+                            send_dont_have: false,
+                        };
+                        for (field, value) in fields(entry)? {
+                            match (field, value) {
+                                (1, Field::Bytes(cid)) => want.cid = parse_cid(cid)?,
+                                (3, Field::Varint(cancel)) => want.cancel = cancel != 0,
+                                (4, Field::Varint(kind)) => {
+                                    want.want_type = if kind == 1 { WantType::Have } else { WantType::Block }
+                                }
+                                (5, Field::Varint(send)) => want.send_dont_have = send != 0,
+                                _ => {}
+                            }
+                        }
+                        message.wants.push(want);
+                    }
+                }
+                // Bitswap 1.0 blocks carry no prefix and are always CIDv0
+                (2, Field::Bytes(data)) => {
+                    let cid = cid_from_prefix(&[0x00, 0x70, 0x12, 0x20], data)?;
+                    message.blocks.push((cid, data.to_vec()));
+                }
+                (3, Field::Bytes(block)) => {
+                    let (mut prefix, mut data) = (&[][..], &[][..]);
+                    for (field, value) in fields(block)? {
+                        match (field, value) {
+                            (1, Field::Bytes(bytes)) => prefix = bytes,
+                            (2, Field::Bytes(bytes)) => data = bytes,
+                            _ => {}
+                        }
+                    }
+                    message.blocks.push((cid_from_prefix(prefix, data)?, data.to_vec()));
+                }
+                (4, Field::Bytes(presence)) => {
+                    let (mut cid, mut dont_have) = (None, false);
+                    for (field, value) in fields(presence)? {
+                        match (field, value) {
+                            (1, Field::Bytes(bytes)) => cid = Some(parse_cid(bytes)?),
+                            (2, Field::Varint(kind)) => dont_have = kind == 1,
+                            _ => {}
+                        }
+                    }
+                    let cid = cid.ok_or_else(|| invalid("block presence without CID"))?;
+                    if dont_have {
+                        message.dont_have.push(cid);
+                    } else {
+                        message.have.push(cid);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(message)
+    }
+}
+
+/// Reads one length-prefixed message, or `None` once the peer closes the stream.
+async fn read_message(stream: &mut Stream) -> io::Result<Option<Message>> {
+    let mut len = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        match stream.read_exact(&mut byte).await {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            result => result?,
+        }
+        len |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    if len > MAX_MESSAGE_SIZE {
+        return Err(invalid("message too large"));
+    }
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Message::decode(&buf).map(Some)
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    NoPeers,
+    NotFound,
+    Timeout,
+    Store(BlockError),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::NoPeers => write!(f, "no connected peer speaks Bitswap"),
+            FetchError::NotFound => write!(f, "no connected peer has the block"),
+            FetchError::Timeout => write!(f, "timed out waiting for the block"),
+            FetchError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for FetchError {}
+
+/// A block we are waiting for, keyed by multihash so CIDv0 and CIDv1
+/// answers both count.
+struct Want {
+    cid: Cid,
+    waiters: Vec<oneshot::Sender<Option<Vec<u8>>>>,
+    asked: HashSet<PeerId>,
+    dont_have: HashSet<PeerId>,
+}
+
+#[derive(Default)]
+struct State {
+    peers: HashSet<PeerId>,
+    wants: HashMap<Multihash<64>, Want>,
+}
+
+/// Enough of Bitswap 1.2 to fetch blocks from and serve blocks to Kubo
+/// peers: want-block and want-have entries are answered straight from the
+/// blockstore, and our own wants go to every peer that speaks Bitswap.
+#[derive(Clone)]
+pub struct Bitswap {
+    control: Control,
+    store: Arc<Blockstore>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Bitswap {
+    pub fn new(mut control: Control, store: Blockstore) -> Self {
+        let mut incoming = control.accept(PROTOCOL).expect("Bitswap is registered once");
+        let bitswap = Self {
+            control,
+            store: Arc::new(store),
+            state: Arc::default(),
+        };
+        let inbound = bitswap.clone();
+        tokio::spawn(async move {
+            while let Some((peer, stream)) = incoming.next().await {
+                tokio::spawn(inbound.clone().handle_inbound(peer, stream));
+            }
+        });
+        bitswap
+    }
+
+    pub fn store(&self) -> &Blockstore {
+        &self.store
+    }
+
+    /// A connected peer announced Bitswap over identify; it gets our open wants.
+    pub fn add_peer(&self, peer: PeerId) {
+        let mut state = self.state.lock().unwrap();
+        if !state.peers.insert(peer) {
+            return;
+        }
+        let wants: Vec<Cid> = state
+            .wants
+            .values_mut()
+            .filter_map(|want| want.asked.insert(peer).then_some(want.cid))
+            .collect();
+        drop(state);
+        if !wants.is_empty() {
+            self.send(peer, want_message(&wants, false));
+        }
+    }
+
+    pub fn remove_peer(&self, peer: &PeerId) {
+        self.state.lock().unwrap().peers.remove(peer);
+    }
+
+    /// Returns the block from the local store, or asks every Bitswap peer for it.
+    pub async fn get(&self, cid: Cid, timeout: Duration) -> Result<Vec<u8>, FetchError> {
+        if let Some(data) = self.store.get(&cid).map_err(|e| FetchError::Store(e.into()))? {
+            return Ok(data);
+        }
+        let (sender, receiver) = oneshot::channel();
+        let peers: Vec<PeerId> = {
+            let mut state = self.state.lock().unwrap();
+            // Nobody could answer, so there is no point waiting out the timeout
+            if state.peers.is_empty() {
+                return Err(FetchError::NoPeers);
+            }
+            let peers = state.peers.clone();
+            let want = state.wants.entry(*cid.hash()).or_insert_with(|| Want {
+                cid,
+                waiters: Vec::new(),
+                asked: HashSet::new(),
+                dont_have: HashSet::new(),
+            });
+            want.waiters.push(sender);
+            peers.into_iter().filter(|peer| want.asked.insert(*peer)).collect()
+        };
+        for peer in peers {
+            self.send(peer, want_message(&[cid], false));
+        }
+
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(Some(data))) => Ok(data),
+            Ok(_) => Err(FetchError::NotFound),
+            Err(_) => {
+                // Our receiver is gone now; others fetching the same block keep the want open
+                let want = {
+                    let mut state = self.state.lock().unwrap();
+                    let last = state.wants.get_mut(cid.hash()).is_some_and(|want| {
+                        want.waiters.retain(|waiter| !waiter.is_canceled());
+                        want.waiters.is_empty()
+                    });
+                    if last { state.wants.remove(cid.hash()) } else { None }
+                };
+                for peer in want.map(|want| want.asked).unwrap_or_default() {
+                    self.send(peer, want_message(&[cid], true));
+                }
+                Err(FetchError::Timeout)
+            }
+        }
+    }
+
+    /// Sends one message on a fresh stream, as Bitswap peers expect.
+    fn send(&self, peer: PeerId, message: Message) {
+        let mut control = self.control.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let mut stream = control.open_stream(peer, PROTOCOL).await?;
+                let body = message.encode();
+                let mut frame = Vec::with_capacity(body.len() + 10);
+                put_varint(&mut frame, body.len() as u64);
+                frame.extend_from_slice(&body);
+                stream.write_all(&frame).await?;
+                stream.close().await?;
+                Ok::<_, OpenStreamError>(())
+            }
+            .await;
+            match result {
+                Ok(()) | Err(OpenStreamError::UnsupportedProtocol(_)) => {}
+                Err(e) => eprintln!("Bitswap message to {} failed: {}", peer, e),
+            }
+        });
+    }
+
+    async fn handle_inbound(self, peer: PeerId, mut stream: Stream) {
+        loop {
+            match read_message(&mut stream).await {
+                Ok(Some(message)) => self.handle_message(peer, message),
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Bad Bitswap message from {}: {}", peer, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn handle_message(&self, peer: PeerId, message: Message) {
+        let mut reply = Message::default();
+        for want in message.wants.into_iter().filter(|want| !want.cancel) {
+            match self.store.get(&want.cid) {
+                Ok(Some(data)) if want.want_type == WantType::Block && data.len() <= MAX_BLOCK_SIZE => {
+                    reply.blocks.push((want.cid, data))
+                }
+                Ok(Some(_)) => reply.have.push(want.cid),
+                _ if want.send_dont_have => reply.dont_have.push(want.cid),
+                _ => {}
+            }
+        }
+        if !reply.is_empty() {
+            self.send(peer, reply);
+        }
+
+        for (cid, data) in message.blocks {
+            self.receive_block(peer, cid, data);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        for cid in message.dont_have {
+            let Some(want) = state.wants.get_mut(cid.hash()) else { continue };
+            want.dont_have.insert(peer);
+            // Everyone we asked has said no
+            if want.asked.is_subset(&want.dont_have) {
+                let want = state.wants.remove(cid.hash()).expect("looked up above");
+                for waiter in want.waiters {
+                    let _ = waiter.send(None);
+                }
+            }
+        }
+    }
+
+    fn receive_block(&self, peer: PeerId, cid: Cid, data: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        // Blocks nobody asked for are dropped rather than stored
+        let Some(want) = state.wants.remove(cid.hash()) else { return };
+        if let Err(e) = self.store.put(&want.cid, &data) {
+            eprintln!("Rejected block {} from {}: {}", cid, peer, e);
+            state.wants.insert(*cid.hash(), want);
+            return;
+        }
+        drop(state);
+        for waiter in want.waiters {
+            let _ = waiter.send(Some(data.clone()));
+        }
+        for other in want.asked.into_iter().filter(|other| *other != peer) {
+            self.send(other, want_message(&[want.cid], true));
+        }
+    }
+}
+
+fn want_message(cids: &[Cid], cancel: bool) -> Message {
+    Message {
+        wants: cids
+            .iter()
+            .map(|cid| WantEntry {
+                cid: *cid,
+                cancel,
+                want_type: WantType::Block,
+                send_dont_have: true,
+            })
+            .collect(),
+        ..Message::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const HELLO: &str = "bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq";
+    const WORLD: &str = "QmTDPv6TFivv9nGX3oiReXBiRcwtvDxkpARZUZC9Fwysre";
+
+    /// A message laid out the way Kubo's protobuf encoder writes it: a
+    /// want-have for HELLO, a cancel for WORLD, the HELLO block, DONT_HAVE
+    /// for WORLD, HAVE for HELLO (type left at its zero default) and pending bytes.
+    const KUBO_MESSAGE: &str = "0a600a300a24015512202cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b982410\
+        feffffff07200128010a2c0a221220486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a710fdffffff07\
+        18011a0d0a0401551220120568656c6c6f22260a221220486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9c\
+        b8a7100122260a24015512202cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824288020";
+
+    fn cid(s: &str) -> Cid {
+        s.parse().unwrap()
+    }
+
+    fn bitswap() -> (Bitswap, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("hippius-bitswap-{}", uuid::Uuid::new_v4()));
+        let control = libp2p_stream::Behaviour::new().new_control();
+        (Bitswap::new(control, Blockstore::open(dir.clone()).unwrap()), dir)
+    }
+
+    #[tokio::test]
+    async fn get_without_bitswap_peers_fails_at_once() {
+        let (bitswap, dir) = bitswap();
+        let result = bitswap.get(cid(HELLO), Duration::from_secs(3600)).await;
+        assert!(matches!(result, Err(FetchError::NoPeers)));
+        assert!(bitswap.state.lock().unwrap().wants.is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_get_leaves_other_waiters_wanting() {
+        let (bitswap, dir) = bitswap();
+        let peer = PeerId::random();
+        bitswap.add_peer(peer);
+        let hello = cid(HELLO);
+        let impatient = tokio::spawn({
+            let bitswap = bitswap.clone();
+            async move { bitswap.get(hello, Duration::from_secs(1)).await }
+        });
+        let patient = tokio::spawn({
+            let bitswap = bitswap.clone();
+            async move { bitswap.get(hello, Duration::from_secs(10)).await }
+        });
+
+        assert!(matches!(impatient.await.unwrap(), Err(FetchError::Timeout)));
+        assert_eq!(bitswap.state.lock().unwrap().wants[hello.hash()].waiters.len(), 1);
+
+        bitswap.receive_block(peer, hello, b"hello".to_vec());
+        assert_eq!(patient.await.unwrap().unwrap(), b"hello");
+        assert!(bitswap.state.lock().unwrap().wants.is_empty());
+
+        // The last waiter timing out withdraws the want
+        let world = cid(WORLD);
+        assert!(matches!(bitswap.get(world, Duration::from_secs(1)).await, Err(FetchError::Timeout)));
+        assert!(bitswap.state.lock().unwrap().wants.is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn decodes_a_kubo_message() {
+        let message = Message::decode(&hex::decode(KUBO_MESSAGE).unwrap()).unwrap();
+        assert_eq!(
+            message.wants,
+            [
+                WantEntry {
+                    cid: cid(HELLO),
+                    cancel: false,
+                    want_type: WantType::Have,
+                    send_dont_have: true,
+                },
+                WantEntry {
+                    cid: cid(WORLD),
+                    cancel: true,
+                    want_type: WantType::Block,
+                    send_dont_have: false,
+                },
+            ]
+        );
+        assert_eq!(message.blocks, [(cid(HELLO), b"hello".to_vec())]);
+        assert_eq!(message.dont_have, [cid(WORLD)]);
+        assert_eq!(message.have, [cid(HELLO)]);
+    }
+
+    #[test]
+    fn round_trips_every_part() {
+        let hello_v0 = cid_from_prefix(&[0x00, 0x70, 0x12, 0x20], b"hello").unwrap();
+        let message = Message {
+            wants: vec![
+                WantEntry {
+                    cid: cid(HELLO),
+                    cancel: false,
+                    want_type: WantType::Block,
+                    send_dont_have: true,
+                },
+                WantEntry {
+                    cid: cid(WORLD),
+                    cancel: true,
+                    want_type: WantType::Have,
+                    send_dont_have: false,
+                },
+            ],
+            blocks: vec![(cid(HELLO), b"hello".to_vec()), (hello_v0, b"hello".to_vec())],
+            have: vec![cid(WORLD)],
+            dont_have: vec![cid(HELLO), cid(WORLD)],
+        };
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        assert!(Message::default().encode().is_empty());
+        assert!(Message::decode(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rebuilds_cids_from_prefixes() {
+        let v1 = cid(HELLO);
+        assert_eq!(cid_from_prefix(&cid_prefix(&v1), b"hello").unwrap(), v1);
+        // Bitswap 1.0 blocks have no prefix and are taken as CIDv0
+        let mut legacy = Vec::new();
+        put_bytes_field(&mut legacy, 2, b"world");
+        assert_eq!(Message::decode(&legacy).unwrap().blocks, [(cid(WORLD), b"world".to_vec())]);
+
+        // Digest length other than the full hash, unknown version or hash, cut short
+        assert!(cid_from_prefix(&[0x01, 0x55, 0x12, 0x10], b"hello").is_err());
+        assert!(cid_from_prefix(&[0x02, 0x55, 0x12, 0x20], b"hello").is_err());
+        assert!(cid_from_prefix(&[0x01, 0x55, 0x13, 0x40], b"hello").is_err());
+        assert!(cid_from_prefix(&[0x01, 0x55, 0x12], b"hello").is_err());
+        assert!(cid_from_prefix(&[], b"hello").is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_and_malformed_input() {
+        let full = hex::decode(KUBO_MESSAGE).unwrap();
+        // Each cut inside the wantlist or block leaves a field shorter than its length
+        for len in [1, 2, 10, 50, full.len() - 1] {
+            assert!(Message::decode(&full[..len]).is_err(), "cut at {}", len);
+        }
+        // No cut may panic, wherever it lands
+        for len in 0..full.len() {
+            let _ = Message::decode(&full[..len]);
+        }
+
+        let mut block_with_bad_cid = Vec::new();
+        put_bytes_field(&mut block_with_bad_cid, 1, &[0x01]);
+        let mut wantlist = Vec::new();
+        put_bytes_field(&mut wantlist, 1, &block_with_bad_cid);
+        let mut bad_want = Vec::new();
+        put_bytes_field(&mut bad_want, 1, &wantlist);
+
+        let mut presence_without_cid = Vec::new();
+        put_bytes_field(&mut presence_without_cid, 4, &[0x10, 0x01]);
+
+        for (what, input) in [
+            ("want with a bad CID", bad_want),
+            ("presence without a CID", presence_without_cid),
+            ("varint longer than 64 bits", vec![0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]),
+            ("length past the end", vec![0x0a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]),
+            ("group wire type", vec![0x0b]),
+            ("block prefix with a short digest", {
+                let mut block = Vec::new();
+                put_bytes_field(&mut block, 1, &[0x00, 0x70, 0x12, 0x10]);
+                let mut message = Vec::new();
+                put_bytes_field(&mut message, 3, &block);
+                message
+            }),
+        ] {
+            assert!(Message::decode(&input).is_err(), "{}", what);
+        }
+    }
+}
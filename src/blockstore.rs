@@ -0,0 +1,103 @@
+use cid::{multihash::Multihash, Cid};
+use sha2::{Digest, Sha256};
+use std::{
+    error::Error as StdError,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Multicodec for blocks that are plain bytes
+pub const RAW: u64 = 0x55;
+const SHA2_256: u64 = 0x12;
+const IDENTITY: u64 = 0x00;
+
+#[derive(Debug)]
+pub enum BlockError {
+    /// Only sha2-256 and identity hashes can be verified
+    UnsupportedHash(u64),
+    HashMismatch(Cid),
+    Io(io::Error),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::UnsupportedHash(code) => write!(f, "unsupported multihash 0x{:x}", code),
+            BlockError::HashMismatch(cid) => write!(f, "data does not hash to {}", cid),
+            BlockError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for BlockError {}
+
+impl From<io::Error> for BlockError {
+    fn from(e: io::Error) -> Self {
+        BlockError::Io(e)
+    }
+}
+
+/// Hashes `data` with the multihash function `code`.
+pub fn multihash(code: u64, data: &[u8]) -> Result<Multihash<64>, BlockError> {
+    let digest: &[u8] = match code {
+        SHA2_256 => &Sha256::digest(data),
+        IDENTITY => data,
+        other => return Err(BlockError::UnsupportedHash(other)),
+    };
+    Multihash::wrap(code, digest).map_err(|_| BlockError::UnsupportedHash(code))
+}
+
+/// IPFS blocks, one file per block named by its CID. Every block is checked
+/// against its CID on the way in, so the store only holds what it claims.
+pub struct Blockstore {
+    dir: PathBuf,
+}
+
+impl Blockstore {
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// CIDv0 and CIDv1 of the same content share a file.
+    fn path(&self, cid: &Cid) -> PathBuf {
+        self.dir.join(Cid::new_v1(cid.codec(), *cid.hash()).to_string())
+    }
+
+    pub fn get(&self, cid: &Cid) -> io::Result<Option<Vec<u8>>> {
+        if cid.hash().code() == IDENTITY {
+            return Ok(Some(cid.hash().digest().to_vec()));
+        }
+        match fs::read(self.path(cid)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn put(&self, cid: &Cid, data: &[u8]) -> Result<(), BlockError> {
+        if multihash(cid.hash().code(), data)? != *cid.hash() {
+            return Err(BlockError::HashMismatch(*cid));
+        }
+        let path = self.path(cid);
+        if path.exists() {
+            return Ok(());
+        }
+        // Write aside and rename so a crash never leaves a truncated block under its CID
+        let partial = path.with_extension("tmp");
+        fs::write(&partial, data)?;
+        fs::rename(&partial, &path)?;
+        Ok(())
+    }
+
+    /// Stores `data` as a raw block and returns its CIDv1.
+    pub fn put_raw(&self, data: &[u8]) -> Result<Cid, BlockError> {
+        let cid = Cid::new_v1(RAW, multihash(SHA2_256, data)?);
+        self.put(&cid, data)?;
+        Ok(cid)
+    }
+}
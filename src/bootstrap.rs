@@ -0,0 +1,284 @@
+use libp2p::{
+    core::ConnectedPoint,
+    multiaddr::Protocol,
+    swarm::{dial_opts::DialOpts, ConnectionId, DialError},
+    Multiaddr, PeerId,
+};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::monitoring::{BootnodeStatus, BootstrapStats};
+
+/// Command-line options for staying connected to the network.
+#[derive(clap::Args, Debug, Clone)]
+pub struct BootstrapArgs {
+    /// Keep dialing bootnodes while fewer peers than this are connected
+    #[arg(long, default_value = "3")]
+    pub min_peers: usize,
+
+    /// Seconds before the first retry of an unreachable bootnode; doubles with each failure
+    #[arg(long, default_value = "1")]
+    pub bootstrap_initial_backoff: u64,
+
+    /// Longest wait between retries of a bootnode, in seconds
+    #[arg(long, default_value = "300")]
+    pub bootstrap_max_backoff: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connectivity {
+    Isolated,
+    Degraded,
+    Connected,
+}
+
+impl fmt::Display for Connectivity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Connectivity::Isolated => write!(f, "isolated"),
+            Connectivity::Degraded => write!(f, "degraded"),
+            Connectivity::Connected => write!(f, "connected"),
+        }
+    }
+}
+
+struct Bootnode {
+    address: Multiaddr,
+    /// Known from a trailing `/p2p` or from the first connection
+    peer: Option<PeerId>,
+    connected: bool,
+    failures: u32,
+    /// When to dial next; `None` while a dial is in flight or while connected
+    next_dial: Option<Instant>,
+}
+
+/// Redials bootnodes with exponential backoff and jitter whenever the node
+/// has fewer peers than it wants, so a bootnode that is down at startup or
+/// restarts later is reached once it is back.
+pub struct Bootstrap {
+    min_peers: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    bootnodes: Vec<Bootnode>,
+    /// Dials in flight, by connection, to the bootnode they are for
+    dialing: HashMap<ConnectionId, usize>,
+    connectivity: Option<Connectivity>,
+}
+
+impl Bootstrap {
+    pub fn new(args: &BootstrapArgs, addresses: Vec<Multiaddr>) -> Self {
+        let now = Instant::now();
+        let bootnodes = addresses
+            .into_iter()
+            .map(|address| Bootnode {
+                peer: match address.iter().last() {
+                    Some(Protocol::P2p(peer)) => Some(peer),
+                    _ => None,
+                },
+                address,
+                connected: false,
+                failures: 0,
+                next_dial: Some(now),
+            })
+            .collect();
+        Self {
+            min_peers: args.min_peers,
+            initial_backoff: Duration::from_secs(args.bootstrap_initial_backoff),
+            max_backoff: Duration::from_secs(args.bootstrap_max_backoff),
+            bootnodes,
+            dialing: HashMap::new(),
+            connectivity: None,
+        }
+    }
+
+    /// The dials that are due, when fewer than the minimum number of peers
+    /// are connected. Pass each to `Swarm::dial`, and its errors to `failed`.
+    pub fn due(&mut self, connected_peers: usize) -> Vec<DialOpts> {
+        if connected_peers >= self.min_peers {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        let mut dials = Vec::new();
+        for (index, bootnode) in self.bootnodes.iter_mut().enumerate() {
+            if bootnode.connected || bootnode.next_dial.is_none_or(|at| at > now) {
+                continue;
+            }
+            let opts = DialOpts::from(bootnode.address.clone());
+            self.dialing.insert(opts.connection_id(), index);
+            bootnode.next_dial = None;
+            dials.push(opts);
+        }
+        dials
+    }
+
+    /// Marks a bootnode connected, whether we dialed it or it reached us
+    /// some other way, e.g. through mDNS.
+    pub fn connected(&mut self, connection_id: ConnectionId, peer: PeerId, endpoint: &ConnectedPoint) {
+        let dialed = self.dialing.remove(&connection_id);
+        for (index, bootnode) in self.bootnodes.iter_mut().enumerate() {
+            let ours = match bootnode.peer {
+                Some(known) => known == peer,
+                None => dialed == Some(index),
+            };
+            if !ours || bootnode.connected {
+                continue;
+            }
+            bootnode.peer = Some(peer);
+            bootnode.connected = true;
+            bootnode.failures = 0;
+            bootnode.next_dial = None;
+            println!("Connected to bootnode {} at {}", peer, endpoint.get_remote_address());
+        }
+    }
+
+    /// Schedules the next dial of a bootnode that could not be reached.
+    /// Returns whether the connection was one of ours.
+    pub fn failed(&mut self, connection_id: ConnectionId, error: &DialError) -> bool {
+        let Some(index) = self.dialing.remove(&connection_id) else {
+            return false;
+        };
+        let delay = self.backoff(self.bootnodes[index].failures);
+        let bootnode = &mut self.bootnodes[index];
+        bootnode.failures += 1;
+        if !bootnode.connected {
+            bootnode.next_dial = Some(Instant::now() + delay);
+        }
+        eprintln!(
+            "Bootnode {} unreachable ({} failure(s)); retrying in {:.1}s: {}",
+            bootnode.address,
+            bootnode.failures,
+            delay.as_secs_f64(),
+            error
+        );
+        true
+    }
+
+    /// A peer's last connection closed; if it was a bootnode, dial it again
+    /// after the initial backoff, so a restarting bootnode has time to come up.
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        let delay = self.backoff(0);
+        for bootnode in self.bootnodes.iter_mut().filter(|bootnode| bootnode.peer.as_ref() == Some(peer)) {
+            if bootnode.connected {
+                bootnode.connected = false;
+                bootnode.next_dial = Some(Instant::now() + delay);
+                println!("Lost bootnode {}; redialing in {:.1}s", peer, delay.as_secs_f64());
+            }
+        }
+    }
+
+    /// The wait before the next dial after `failures` failed ones: the
+    /// initial backoff doubled per failure up to the maximum, of which a
+    /// random half is dropped so nodes that lost the same bootnode do not
+    /// all come back at once.
+    fn backoff(&self, failures: u32) -> Duration {
+        let full = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(failures))
+            .min(self.max_backoff);
+        let half = full / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=full - half)
+    }
+
+    /// Logs a change of connectivity and returns the state for export.
+    pub fn update(&mut self, connected_peers: usize) -> BootstrapStats {
+        let connectivity = match connected_peers {
+            0 => Connectivity::Isolated,
+            n if n < self.min_peers => Connectivity::Degraded,
+            _ => Connectivity::Connected,
+        };
+        if self.connectivity != Some(connectivity) {
+            let bootnodes = self.bootnodes.iter().filter(|bootnode| bootnode.connected).count();
+            println!(
+                "Connectivity: {} ({} of {} wanted peers, {} of {} bootnodes)",
+                connectivity,
+                connected_peers,
+                self.min_peers,
+                bootnodes,
+                self.bootnodes.len()
+            );
+            self.connectivity = Some(connectivity);
+        }
+        let now = Instant::now();
+        BootstrapStats {
+            state: connectivity.to_string(),
+            connected_peers,
+            min_peers: self.min_peers,
+            bootnodes: self
+                .bootnodes
+                .iter()
+                .map(|bootnode| BootnodeStatus {
+                    address: bootnode.address.to_string(),
+                    connected: bootnode.connected,
+                    failures: bootnode.failures,
+                    retry_in_secs: bootnode.next_dial.map(|at| at.saturating_duration_since(now).as_secs()),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bootstrap(addresses: &[&str]) -> Bootstrap {
+        let args = BootstrapArgs {
+            min_peers: 2,
+            bootstrap_initial_backoff: 4,
+            bootstrap_max_backoff: 60,
+        };
+        Bootstrap::new(&args, addresses.iter().map(|address| address.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_maximum() {
+        let bootstrap = bootstrap(&[]);
+        for (failures, full) in [(0, 4), (1, 8), (2, 16), (3, 32), (4, 60), (40, 60)] {
+            for _ in 0..20 {
+                let delay = bootstrap.backoff(failures);
+                let full = Duration::from_secs(full);
+                assert!(delay >= full / 2 && delay <= full, "{:?} outside {:?}", delay, full);
+            }
+        }
+    }
+
+    #[test]
+    fn dials_only_when_short_of_peers_and_backs_off_after_failure() {
+        let mut bootstrap = bootstrap(&["/ip4/127.0.0.1/tcp/4002"]);
+        assert!(bootstrap.due(2).is_empty());
+
+        let dials = bootstrap.due(0);
+        assert_eq!(dials.len(), 1);
+        // In flight, so not dialed twice
+        assert!(bootstrap.due(0).is_empty());
+
+        let failed = bootstrap.failed(dials[0].connection_id(), &DialError::Aborted);
+        assert!(failed);
+        assert_eq!(bootstrap.bootnodes[0].failures, 1);
+        assert!(bootstrap.due(0).is_empty(), "retried before its backoff");
+        assert_eq!(bootstrap.update(0).state, "isolated");
+    }
+
+    #[test]
+    fn redials_a_bootnode_that_goes_away() {
+        let mut bootstrap = bootstrap(&["/ip4/127.0.0.1/tcp/4002"]);
+        let dial = bootstrap.due(0).remove(0);
+        let peer = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/4002".parse().unwrap(),
+            role_override: libp2p::core::Endpoint::Dialer,
+        };
+        bootstrap.connected(dial.connection_id(), peer, &endpoint);
+        assert!(bootstrap.bootnodes[0].connected);
+        assert_eq!(bootstrap.update(1).state, "degraded");
+        assert!(bootstrap.due(1).is_empty());
+
+        bootstrap.disconnected(&peer);
+        let next = bootstrap.bootnodes[0].next_dial.expect("redial scheduled");
+        assert!(next > Instant::now() && next <= Instant::now() + Duration::from_secs(4));
+    }
+}
@@ -0,0 +1,353 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use hkdf::Hkdf;
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    request_response::{self, json, OutboundRequestId, ProtocolSupport},
+    PeerId, StreamProtocol,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error as StdError,
+    fmt,
+    time::{Duration, Instant},
+};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey, StaticSecret};
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/hippius/dm/1.0.0");
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Attempts before an undeliverable message is dropped, about 8 minutes of backoff
+const MAX_ATTEMPTS: u32 = 8;
+const FIRST_RETRY: Duration = Duration::from_secs(2);
+const MAX_RETRY: Duration = Duration::from_secs(120);
+/// Message ids remembered to drop retried duplicates
+const SEEN_CAPACITY: usize = 1024;
+
+pub type Behaviour = json::Behaviour<Envelope, Receipt>;
+pub type Event = request_response::Event<Envelope, Receipt>;
+
+pub fn behaviour() -> Behaviour {
+    json::Behaviour::new(
+        [(PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+    )
+}
+
+/// A direct message sealed to one recipient. Only the holder of the
+/// recipient's identity key can open it, whichever nodes carry the stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Chosen by the sender, echoed in the receipt and kept across retries
+    pub id: String,
+    /// The sender's one-time X25519 key, base64
+    pub ephemeral_key: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// The recipient's answer to an envelope.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Receipt {
+    Delivered { id: String },
+    Rejected { id: String, reason: String },
+}
+
+/// Plaintext inside an envelope. The signature binds the body to the
+/// sender's identity, the recipient and the message id.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    body: String,
+    signature: String,
+}
+
+#[derive(Debug)]
+pub enum DmError {
+    /// Peer ids that hash their key instead of embedding an Ed25519 key
+    UnsupportedKey(PeerId),
+    Malformed(&'static str),
+    Decryption,
+    BadSignature,
+}
+
+impl fmt::Display for DmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DmError::UnsupportedKey(peer) => write!(f, "{} has no Ed25519 identity key", peer),
+            DmError::Malformed(what) => write!(f, "malformed envelope: {}", what),
+            DmError::Decryption => write!(f, "envelope could not be decrypted"),
+            DmError::BadSignature => write!(f, "signature does not match the sender"),
+        }
+    }
+}
+
+impl StdError for DmError {}
+
+pub fn identity_key(peer: &PeerId) -> Result<PublicKey, DmError> {
+    let multihash = peer.as_ref();
+    // Ed25519 keys are short enough to be inlined with the identity hash (code 0)
+    if multihash.code() != 0 {
+        return Err(DmError::UnsupportedKey(*peer));
+    }
+    PublicKey::try_decode_protobuf(multihash.digest()).map_err(|_| DmError::UnsupportedKey(*peer))
+}
+
+/// The X25519 form of a peer's Ed25519 identity key.
+fn x25519_public(peer: &PeerId) -> Result<X25519PublicKey, DmError> {
+    let key = identity_key(peer)?
+        .try_into_ed25519()
+        .map_err(|_| DmError::UnsupportedKey(*peer))?;
+    let point = CompressedEdwardsY(key.to_bytes())
+        .decompress()
+        .ok_or(DmError::UnsupportedKey(*peer))?;
+    Ok(X25519PublicKey::from(point.to_montgomery().to_bytes()))
+}
+
+/// The X25519 secret matching our Ed25519 identity, expanded as in RFC 8032.
+fn x25519_secret(identity: &Keypair) -> Result<StaticSecret, DmError> {
+    let keypair = identity
+        .clone()
+        .try_into_ed25519()
+        .map_err(|_| DmError::UnsupportedKey(identity.public().to_peer_id()))?;
+    let hash = Sha512::digest(keypair.secret().as_ref());
+    let mut scalar = [0u8; 32];
+    scalar.copy_from_slice(&hash[..32]);
+    Ok(StaticSecret::from(scalar))
+}
+
+fn cipher(shared: &[u8; 32], ephemeral: &X25519PublicKey, recipient: &X25519PublicKey) -> ChaCha20Poly1305 {
+    let info = [PROTOCOL.as_ref().as_bytes(), ephemeral.as_bytes(), recipient.as_bytes()].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(&key.into())
+}
+
+fn signed_bytes(id: &str, to: &PeerId, body: &str) -> Vec<u8> {
+    serde_json::to_vec(&(PROTOCOL.as_ref(), id, to.to_base58(), body)).expect("tuple of strings serializes")
+}
+
+/// Encrypts and signs `body` for `to` under a fresh message id.
+pub fn seal(identity: &Keypair, to: &PeerId, body: &str) -> Result<Envelope, DmError> {
+    let recipient = x25519_public(to)?;
+    let id = uuid::Uuid::new_v4().to_string();
+    let signature = identity
+        .sign(&signed_bytes(&id, to, body))
+        .map_err(|_| DmError::UnsupportedKey(identity.public().to_peer_id()))?;
+    let plaintext = serde_json::to_vec(&Sealed {
+        body: body.to_string(),
+        signature: BASE64.encode(signature),
+    })
+    .expect("sealed message serializes");
+
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_key = X25519PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&recipient);
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher(shared.as_bytes(), &ephemeral_key, &recipient)
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: &plaintext, aad: id.as_bytes() })
+        .map_err(|_| DmError::Decryption)?;
+
+    Ok(Envelope {
+        id,
+        ephemeral_key: BASE64.encode(ephemeral_key.as_bytes()),
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    })
+}
+
+/// Decrypts an envelope addressed to us and checks it was signed by `from`.
+pub fn open(identity: &Keypair, from: &PeerId, envelope: &Envelope) -> Result<String, DmError> {
+    let decode = |field: &str, what| BASE64.decode(field).map_err(|_| DmError::Malformed(what));
+    let ephemeral_key: [u8; 32] = decode(&envelope.ephemeral_key, "ephemeral key")?
+        .try_into()
+        .map_err(|_| DmError::Malformed("ephemeral key"))?;
+    let nonce: [u8; 12] = decode(&envelope.nonce, "nonce")?
+        .try_into()
+        .map_err(|_| DmError::Malformed("nonce"))?;
+    let ciphertext = decode(&envelope.ciphertext, "ciphertext")?;
+
+    let secret = x25519_secret(identity)?;
+    let ephemeral_key = X25519PublicKey::from(ephemeral_key);
+    let shared = secret.diffie_hellman(&ephemeral_key);
+    let plaintext = cipher(shared.as_bytes(), &ephemeral_key, &X25519PublicKey::from(&secret))
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: envelope.id.as_bytes() })
+        .map_err(|_| DmError::Decryption)?;
+    let sealed: Sealed = serde_json::from_slice(&plaintext).map_err(|_| DmError::Malformed("payload"))?;
+
+    let signature = decode(&sealed.signature, "signature")?;
+    let local = identity.public().to_peer_id();
+    if !identity_key(from)?.verify(&signed_bytes(&envelope.id, &local, &sealed.body), &signature) {
+        return Err(DmError::BadSignature);
+    }
+    Ok(sealed.body)
+}
+
+/// An envelope waiting for its receipt.
+pub struct Pending {
+    pub to: PeerId,
+    pub envelope: Envelope,
+    pub attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Outgoing direct messages, retried with exponential backoff while the
+/// recipient cannot be reached.
+#[derive(Default)]
+pub struct Outbox {
+    waiting: Vec<Pending>,
+    in_flight: HashMap<OutboundRequestId, Pending>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, to: PeerId, envelope: Envelope) {
+        self.waiting.push(Pending {
+            to,
+            envelope,
+            attempts: 0,
+            next_attempt: Instant::now(),
+        });
+    }
+
+    /// Takes the messages whose next attempt is due. Each must be handed
+    /// back through `sent` with the id of its request.
+    pub fn due(&mut self) -> Vec<Pending> {
+        let now = Instant::now();
+        let (due, waiting) = self.waiting.drain(..).partition(|pending| pending.next_attempt <= now);
+        self.waiting = waiting;
+        due
+    }
+
+    pub fn sent(&mut self, request_id: OutboundRequestId, mut pending: Pending) {
+        pending.attempts += 1;
+        self.in_flight.insert(request_id, pending);
+    }
+
+    /// The recipient answered, whether it accepted the message or not.
+    pub fn answered(&mut self, request_id: &OutboundRequestId) -> Option<Pending> {
+        self.in_flight.remove(request_id)
+    }
+
+    /// Schedules a retry after a failed attempt. Returns the message when it
+    /// is given up on instead.
+    pub fn failed(&mut self, request_id: &OutboundRequestId, retryable: bool) -> Option<Pending> {
+        let mut pending = self.in_flight.remove(request_id)?;
+        if !retryable || pending.attempts >= MAX_ATTEMPTS {
+            return Some(pending);
+        }
+        let backoff = FIRST_RETRY.saturating_mul(1 << (pending.attempts - 1).min(16)).min(MAX_RETRY);
+        pending.next_attempt = Instant::now() + backoff;
+        self.waiting.push(pending);
+        None
+    }
+
+    /// Retries everything queued for `peer` right away, e.g. once it connects.
+    pub fn wake(&mut self, peer: &PeerId) {
+        let now = Instant::now();
+        for pending in self.waiting.iter_mut().filter(|pending| pending.to == *peer) {
+            pending.next_attempt = now;
+        }
+    }
+}
+
+/// Message ids already shown, so a retry whose receipt was lost is only
+/// acknowledged again.
+#[derive(Default)]
+pub struct SeenMessages {
+    order: VecDeque<(PeerId, String)>,
+    seen: HashSet<(PeerId, String)>,
+}
+
+impl SeenMessages {
+    pub fn first_time(&mut self, from: PeerId, id: &str) -> bool {
+        let key = (from, id.to_string());
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tamper(field: &str) -> String {
+        let mut bytes = BASE64.decode(field).unwrap();
+        bytes[0] ^= 1;
+        BASE64.encode(bytes)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let envelope = seal(&alice, &bob.public().to_peer_id(), "hello bob").unwrap();
+        assert!(!envelope.ciphertext.contains("hello"));
+        assert_eq!(open(&bob, &alice.public().to_peer_id(), &envelope).unwrap(), "hello bob");
+    }
+
+    #[test]
+    fn tampered_envelopes_do_not_open() {
+        let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let from = alice.public().to_peer_id();
+        let envelope = seal(&alice, &bob.public().to_peer_id(), "hello bob").unwrap();
+
+        let mut ciphertext = envelope.clone();
+        ciphertext.ciphertext = tamper(&envelope.ciphertext);
+        assert!(matches!(open(&bob, &from, &ciphertext), Err(DmError::Decryption)));
+
+        let mut nonce = envelope.clone();
+        nonce.nonce = tamper(&envelope.nonce);
+        assert!(matches!(open(&bob, &from, &nonce), Err(DmError::Decryption)));
+
+        // The id is authenticated data, so a replay under another id fails too
+        let mut id = envelope.clone();
+        id.id = uuid::Uuid::new_v4().to_string();
+        assert!(matches!(open(&bob, &from, &id), Err(DmError::Decryption)));
+
+        let mut short = envelope;
+        short.nonce = BASE64.encode([0u8; 8]);
+        assert!(matches!(open(&bob, &from, &short), Err(DmError::Malformed("nonce"))));
+    }
+
+    #[test]
+    fn wrong_sender_is_rejected() {
+        let (alice, bob, mallory) = (
+            Keypair::generate_ed25519(),
+            Keypair::generate_ed25519(),
+            Keypair::generate_ed25519(),
+        );
+        // Mallory seals the message but claims it came from Alice
+        let envelope = seal(&mallory, &bob.public().to_peer_id(), "hello bob").unwrap();
+        let result = open(&bob, &alice.public().to_peer_id(), &envelope);
+        assert!(matches!(result, Err(DmError::BadSignature)));
+    }
+
+    #[test]
+    fn only_the_recipient_can_open() {
+        let (alice, bob, carol) = (
+            Keypair::generate_ed25519(),
+            Keypair::generate_ed25519(),
+            Keypair::generate_ed25519(),
+        );
+        let envelope = seal(&alice, &bob.public().to_peer_id(), "hello bob").unwrap();
+        let result = open(&carol, &alice.public().to_peer_id(), &envelope);
+        assert!(matches!(result, Err(DmError::Decryption)));
+    }
+}
@@ -0,0 +1,284 @@
+use async_trait::async_trait;
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    system_conf, TokioAsyncResolver,
+};
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use std::{error::Error as StdError, fmt, io, net::SocketAddr};
+
+/// TXT records of `/dnsaddr/<domain>` live at this prefix of the domain.
+const DNSADDR_PREFIX: &str = "_dnsaddr.";
+/// Lookups made for one address, so records that point at each other end
+const MAX_LOOKUPS: usize = 32;
+/// Addresses one bootstrap entry may expand to
+const MAX_ADDRESSES: usize = 64;
+
+/// Command-line options for resolving DNS bootnode addresses.
+#[derive(clap::Args, Debug, Clone)]
+pub struct DnsArgs {
+    /// DNS server for `/dns*` and `/dnsaddr` addresses instead of the system's, e.g. 127.0.0.1:5353
+    #[arg(long)]
+    pub dns_server: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+pub enum DnsError {
+    Lookup { name: String, reason: String },
+    TooManyLookups(Multiaddr),
+    NoAddresses(Multiaddr),
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::Lookup { name, reason } => write!(f, "TXT lookup of {} failed: {}", name, reason),
+            DnsError::TooManyLookups(addr) => write!(f, "{} needs more than {} DNS lookups", addr, MAX_LOOKUPS),
+            DnsError::NoAddresses(addr) => write!(f, "{} has no dnsaddr records", addr),
+        }
+    }
+}
+
+impl StdError for DnsError {}
+
+/// The resolver settings shared by the DNS transport and `/dnsaddr`
+/// expansion: the system's, or a single server given on the command line.
+pub fn resolver_config(args: &DnsArgs) -> io::Result<(ResolverConfig, ResolverOpts)> {
+    match args.dns_server {
+        Some(server) => {
+            let servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+            Ok((ResolverConfig::from_parts(None, Vec::new(), servers), ResolverOpts::default()))
+        }
+        None => Ok(system_conf::read_system_conf()?),
+    }
+}
+
+/// Looks up the TXT records of a name, each as one string.
+#[async_trait]
+pub trait TxtResolver {
+    async fn txt(&self, name: &str) -> Result<Vec<String>, DnsError>;
+}
+
+#[async_trait]
+impl TxtResolver for TokioAsyncResolver {
+    async fn txt(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        let lookup = self.txt_lookup(name).await.map_err(|e| DnsError::Lookup {
+            name: name.to_string(),
+            reason: e.to_string(),
+        })?;
+        Ok(lookup
+            .iter()
+            .map(|txt| {
+                // Records longer than 255 bytes arrive as several character strings
+                txt.txt_data().iter().map(|chunk| String::from_utf8_lossy(chunk)).collect()
+            })
+            .collect())
+    }
+}
+
+/// Expands `/dnsaddr/<domain>` through the `dnsaddr=` TXT records at
+/// `_dnsaddr.<domain>`, following records that are `/dnsaddr` addresses in
+/// turn, so a single entry such as `/dnsaddr/bootstrap.hippius.network` can
+/// name every bootnode. A trailing `/p2p/<peer>` keeps only the records for
+/// that peer. Other addresses are returned as they are; the transport
+/// resolves `/dns`, `/dns4` and `/dns6` when dialing.
+pub async fn expand(resolver: &impl TxtResolver, addr: Multiaddr) -> Result<Vec<Multiaddr>, DnsError> {
+    let mut pending = vec![addr.clone()];
+    let mut expanded = Vec::new();
+    let mut lookups = 0;
+    while let Some(next) = pending.pop() {
+        let Some(Protocol::Dnsaddr(domain)) = next.iter().next() else {
+            if !expanded.contains(&next) {
+                expanded.push(next);
+            }
+            if expanded.len() == MAX_ADDRESSES {
+                break;
+            }
+            continue;
+        };
+        lookups += 1;
+        if lookups > MAX_LOOKUPS {
+            return Err(DnsError::TooManyLookups(addr));
+        }
+        let peer = match next.iter().last() {
+            Some(Protocol::P2p(peer)) => Some(peer),
+            _ => None,
+        };
+        let records = resolver.txt(&format!("{}{}", DNSADDR_PREFIX, domain)).await?;
+        // Records are pushed in reverse so they are expanded in the order served
+        for record in records.iter().rev() {
+            let Some(Ok(target)) = record.strip_prefix("dnsaddr=").map(str::parse::<Multiaddr>) else {
+                continue;
+            };
+            let matches = match (peer, target.iter().last()) {
+                (Some(peer), Some(Protocol::P2p(target_peer))) => peer == target_peer,
+                (Some(_), _) => false,
+                (None, _) => true,
+            };
+            if matches {
+                pending.push(target);
+            }
+        }
+    }
+    if expanded.is_empty() {
+        return Err(DnsError::NoAddresses(addr));
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{rdata::TXT, RData, Record, RecordType},
+    };
+    use std::{collections::HashMap, sync::Arc};
+    use tokio::net::UdpSocket;
+
+    const PEER_A: &str = "12D3KooWJYFpdGTqpAAvE9Q5qy5YnxRBKYdWnN9VVHpmvyizqsAV";
+    const PEER_B: &str = "12D3KooWJXK2ogiSsZ5BhQ41Ck5Xkps89kLeBPq78bkbSZ7gXatC";
+
+    /// Answers TXT lookups from a table, the way a zone would.
+    struct StubResolver(HashMap<String, Vec<String>>);
+
+    impl StubResolver {
+        fn new(records: &[(&str, &str)]) -> Self {
+            let mut zone: HashMap<String, Vec<String>> = HashMap::new();
+            for (name, record) in records {
+                zone.entry(name.to_string()).or_default().push(record.to_string());
+            }
+            Self(zone)
+        }
+    }
+
+    #[async_trait]
+    impl TxtResolver for StubResolver {
+        async fn txt(&self, name: &str) -> Result<Vec<String>, DnsError> {
+            self.0.get(name).cloned().ok_or_else(|| DnsError::Lookup {
+                name: name.to_string(),
+                reason: "no such name".to_string(),
+            })
+        }
+    }
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn expands_every_record() {
+        let resolver = StubResolver::new(&[
+            ("_dnsaddr.bootstrap.hippius.network", &format!("dnsaddr=/ip4/10.0.0.1/tcp/4002/p2p/{}", PEER_A)),
+            ("_dnsaddr.bootstrap.hippius.network", &format!("dnsaddr=/dns4/b.hippius.network/tcp/4002/p2p/{}", PEER_B)),
+            ("_dnsaddr.bootstrap.hippius.network", "not-a-dnsaddr-record"),
+        ]);
+        let expanded = expand(&resolver, addr("/dnsaddr/bootstrap.hippius.network")).await.unwrap();
+        assert_eq!(
+            expanded,
+            vec![
+                addr(&format!("/ip4/10.0.0.1/tcp/4002/p2p/{}", PEER_A)),
+                addr(&format!("/dns4/b.hippius.network/tcp/4002/p2p/{}", PEER_B)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_nested_records_and_filters_by_peer() {
+        let resolver = StubResolver::new(&[
+            ("_dnsaddr.bootstrap.hippius.network", "dnsaddr=/dnsaddr/eu.hippius.network"),
+            ("_dnsaddr.eu.hippius.network", &format!("dnsaddr=/ip4/10.0.0.1/tcp/4002/p2p/{}", PEER_A)),
+            ("_dnsaddr.eu.hippius.network", &format!("dnsaddr=/ip4/10.0.0.2/tcp/4002/p2p/{}", PEER_B)),
+        ]);
+        let all = expand(&resolver, addr("/dnsaddr/bootstrap.hippius.network")).await.unwrap();
+        assert_eq!(all.len(), 2);
+
+        let one = expand(&resolver, addr(&format!("/dnsaddr/eu.hippius.network/p2p/{}", PEER_B))).await.unwrap();
+        assert_eq!(one, vec![addr(&format!("/ip4/10.0.0.2/tcp/4002/p2p/{}", PEER_B))]);
+    }
+
+    #[tokio::test]
+    async fn passes_other_addresses_through() {
+        let resolver = StubResolver::new(&[]);
+        let plain = addr("/dns4/boot.hippius.network/tcp/4002");
+        assert_eq!(expand(&resolver, plain.clone()).await.unwrap(), vec![plain]);
+    }
+
+    #[tokio::test]
+    async fn stops_on_records_that_loop() {
+        let resolver = StubResolver::new(&[
+            ("_dnsaddr.a.hippius.network", "dnsaddr=/dnsaddr/b.hippius.network"),
+            ("_dnsaddr.b.hippius.network", "dnsaddr=/dnsaddr/a.hippius.network"),
+        ]);
+        let result = expand(&resolver, addr("/dnsaddr/a.hippius.network")).await;
+        assert!(matches!(result, Err(DnsError::TooManyLookups(_))));
+    }
+
+    #[tokio::test]
+    async fn reports_missing_records() {
+        let resolver = StubResolver::new(&[("_dnsaddr.empty.hippius.network", "v=spf1 -all")]);
+        let result = expand(&resolver, addr("/dnsaddr/empty.hippius.network")).await;
+        assert!(matches!(result, Err(DnsError::NoAddresses(_))));
+        let result = expand(&resolver, addr("/dnsaddr/unknown.hippius.network")).await;
+        assert!(matches!(result, Err(DnsError::Lookup { .. })));
+    }
+
+    /// Serves TXT records over UDP on a local port, like a stub DNS server.
+    async fn serve_zone(zone: HashMap<String, Vec<String>>) -> SocketAddr {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let local = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let Ok(query) = Message::from_vec(&buf[..len]) else {
+                    continue;
+                };
+                let mut response = Message::new();
+                response.set_id(query.id()).set_message_type(MessageType::Response).set_recursion_available(true);
+                response.set_recursion_desired(query.recursion_desired());
+                for question in query.queries() {
+                    response.add_query(question.clone());
+                    let name = question.name().to_ascii();
+                    let records = zone.get(name.trim_end_matches('.'));
+                    match records {
+                        Some(records) if question.query_type() == RecordType::TXT => {
+                            for record in records {
+                                let rdata = RData::TXT(TXT::new(vec![record.clone()]));
+                                response.add_answer(Record::from_rdata(question.name().clone(), 60, rdata));
+                            }
+                        }
+                        _ => {
+                            response.set_response_code(ResponseCode::NXDomain);
+                        }
+                    }
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), from).await;
+            }
+        });
+        local
+    }
+
+    #[tokio::test]
+    async fn resolves_through_a_configured_dns_server() {
+        let mut zone = HashMap::new();
+        zone.insert(
+            "_dnsaddr.bootstrap.hippius.network".to_string(),
+            vec![
+                format!("dnsaddr=/ip4/10.0.0.1/tcp/4002/p2p/{}", PEER_A),
+                format!("dnsaddr=/ip4/10.0.0.2/tcp/4002/ws/p2p/{}", PEER_B),
+            ],
+        );
+        let server = serve_zone(zone).await;
+        let (config, mut opts) = resolver_config(&DnsArgs { dns_server: Some(server) }).unwrap();
+        opts.attempts = 1;
+        let resolver = TokioAsyncResolver::tokio(config, opts);
+
+        let expanded = expand(&resolver, addr("/dnsaddr/bootstrap.hippius.network")).await.unwrap();
+        assert_eq!(
+            expanded,
+            vec![
+                addr(&format!("/ip4/10.0.0.1/tcp/4002/p2p/{}", PEER_A)),
+                addr(&format!("/ip4/10.0.0.2/tcp/4002/ws/p2p/{}", PEER_B)),
+            ]
+        );
+    }
+}
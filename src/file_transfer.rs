@@ -0,0 +1,665 @@
+use libp2p::{
+    request_response::{self, cbor, OutboundRequestId, ProtocolSupport},
+    PeerId, StreamProtocol,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/hippius/file/1.0.0");
+
+pub const CHUNK_SIZE: u32 = 256 * 1024;
+/// Largest chunk size accepted from a manifest; a chunk has to fit in one response
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+/// Chunk requests kept outstanding per source peer
+const REQUESTS_PER_PEER: usize = 4;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type Behaviour = cbor::Behaviour<Request, Response>;
+pub type Event = request_response::Event<Request, Response>;
+
+pub fn behaviour() -> Behaviour {
+    cbor::Behaviour::new(
+        [(PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    Manifest { root: String },
+    Chunk { root: String, index: u32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    Manifest(Manifest),
+    Chunk(#[serde(with = "serde_bytes")] Vec<u8>),
+    NotFound,
+}
+
+/// A file described by the hashes of its chunks. The root hash names the
+/// file on the network and covers everything here except the name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    pub size: u64,
+    pub chunk_size: u32,
+    /// Hex SHA-256 of each chunk, in order
+    pub chunks: Vec<String>,
+}
+
+impl Manifest {
+    pub fn root(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.size.to_be_bytes());
+        hasher.update(self.chunk_size.to_be_bytes());
+        for chunk in &self.chunks {
+            hasher.update(chunk.as_bytes());
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    /// Chunk size and count agree with the file size.
+    fn is_consistent(&self) -> bool {
+        self.chunk_size > 0
+            && self.chunk_size <= MAX_CHUNK_SIZE
+            && self.chunks.len() as u64 == self.size.div_ceil(self.chunk_size as u64)
+    }
+
+    fn offset(&self, index: u32) -> u64 {
+        index as u64 * self.chunk_size as u64
+    }
+
+    fn chunk_len(&self, index: u32) -> usize {
+        (self.size - self.offset(index)).min(self.chunk_size as u64) as usize
+    }
+}
+
+fn hash_chunk(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn build_manifest(path: &Path) -> io::Result<Manifest> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?
+        .to_string();
+    let mut file = File::open(path)?;
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+        (&mut file).take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            break;
+        }
+        size += chunk.len() as u64;
+        chunks.push(hash_chunk(&chunk));
+    }
+    Ok(Manifest {
+        name,
+        size,
+        chunk_size: CHUNK_SIZE,
+        chunks,
+    })
+}
+
+/// Runs file IO on the blocking pool, so reading and writing chunks does not
+/// stall the swarm task.
+async fn blocking<T: Send + 'static>(io: impl FnOnce() -> io::Result<T> + Send + 'static) -> io::Result<T> {
+    tokio::task::spawn_blocking(io).await?
+}
+
+fn read_chunk(path: &Path, manifest: &Manifest, index: u32) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(manifest.offset(index)))?;
+    let mut data = vec![0; manifest.chunk_len(index)];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// What a transfer reports back to the node.
+#[derive(Debug)]
+pub enum TransferEvent {
+    Started { root: String, name: String, chunks: usize, have: usize },
+    Progress { root: String, done: usize, total: usize },
+    Completed { root: String, path: PathBuf },
+    Failed { root: String, reason: String },
+}
+
+struct Download {
+    manifest: Option<Manifest>,
+    /// Peers that sent the manifest and can be asked for chunks
+    sources: Vec<PeerId>,
+    /// Peers asked for the manifest that have not answered yet
+    asked: HashSet<PeerId>,
+    missing: VecDeque<u32>,
+    in_flight: HashMap<OutboundRequestId, (PeerId, Option<u32>)>,
+    done: usize,
+}
+
+/// Serves shared files and drives downloads. Chunks land in
+/// `<download dir>/<root>.part` next to the manifest, so an interrupted
+/// fetch resumes from the chunks already on disk.
+pub struct FileTransfer {
+    download_dir: PathBuf,
+    shared: HashMap<String, (PathBuf, Manifest)>,
+    downloads: HashMap<String, Download>,
+}
+
+impl FileTransfer {
+    pub fn new(download_dir: PathBuf) -> Self {
+        Self {
+            download_dir,
+            shared: HashMap::new(),
+            downloads: HashMap::new(),
+        }
+    }
+
+    /// Hashes `path` and starts serving it. Returns the manifest, whose
+    /// root is what peers pass to `/fetch`.
+    pub async fn share(&mut self, path: PathBuf) -> io::Result<Manifest> {
+        let path = path.canonicalize()?;
+        let manifest = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || build_manifest(&path)).await??
+        };
+        self.shared.insert(manifest.root(), (path, manifest.clone()));
+        Ok(manifest)
+    }
+
+    fn part_path(&self, root: &str) -> PathBuf {
+        self.download_dir.join(format!("{}.part", root))
+    }
+
+    fn manifest_path(&self, root: &str) -> PathBuf {
+        self.download_dir.join(format!("{}.manifest.json", root))
+    }
+
+    /// Asks `peers` for the file named by `root` and fetches its chunks from
+    /// every peer that has it.
+    pub async fn fetch(&mut self, behaviour: &mut Behaviour, root: String, peers: Vec<PeerId>) -> Vec<TransferEvent> {
+        let root = root.to_ascii_lowercase();
+        // The root becomes part of file names, so it has to be a plain hash
+        if root.len() != 64 || !root.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return vec![failed(&root, "not a file hash".to_string())];
+        }
+        if peers.is_empty() {
+            return vec![failed(&root, "no connected peers to fetch from".to_string())];
+        }
+        let mut events = Vec::new();
+        if !self.downloads.contains_key(&root) {
+            let mut download = Download {
+                manifest: None,
+                sources: Vec::new(),
+                asked: HashSet::new(),
+                missing: VecDeque::new(),
+                in_flight: HashMap::new(),
+                done: 0,
+            };
+            // A manifest left by an earlier attempt lets us resume before any peer answers
+            let manifest_path = self.manifest_path(&root);
+            let saved = blocking(move || fs::read(manifest_path))
+                .await
+                .ok()
+                .and_then(|bytes| serde_json::from_slice::<Manifest>(&bytes).ok())
+                .filter(|manifest| manifest.is_consistent() && manifest.root() == root);
+            if let Some(manifest) = saved {
+                events.extend(self.resume(&root, &mut download, manifest).await);
+            }
+            self.downloads.insert(root.clone(), download);
+        }
+
+        let download = self.downloads.get_mut(&root).expect("inserted above");
+        for peer in peers {
+            if download.sources.contains(&peer) || !download.asked.insert(peer) {
+                continue;
+            }
+            let request_id = behaviour.send_request(&peer, Request::Manifest { root: root.clone() });
+            download.in_flight.insert(request_id, (peer, None));
+        }
+        events
+    }
+
+    /// Adopts a verified manifest and works out which chunks are still missing.
+    async fn resume(&self, root: &str, download: &mut Download, manifest: Manifest) -> Vec<TransferEvent> {
+        let part = self.part_path(root);
+        let prepared = {
+            let (dir, manifest_path) = (self.download_dir.clone(), self.manifest_path(root));
+            let (part, manifest) = (part.clone(), manifest.clone());
+            blocking(move || {
+                let have: Vec<bool> = (0..manifest.chunks.len() as u32)
+                    .map(|index| {
+                        read_chunk(&part, &manifest, index)
+                            .is_ok_and(|data| hash_chunk(&data) == manifest.chunks[index as usize])
+                    })
+                    .collect();
+                fs::create_dir_all(&dir)?;
+                fs::write(manifest_path, serde_json::to_vec(&manifest)?)?;
+                OpenOptions::new().create(true).truncate(false).write(true).open(&part)?.set_len(manifest.size)?;
+                Ok(have)
+            })
+            .await
+        };
+        let have = match prepared {
+            Ok(have) => have,
+            Err(e) => return vec![failed(root, format!("cannot prepare {}: {}", part.display(), e))],
+        };
+
+        download.missing = (0..have.len() as u32).filter(|index| !have[*index as usize]).collect();
+        download.done = have.len() - download.missing.len();
+        let event = TransferEvent::Started {
+            root: root.to_string(),
+            name: manifest.name.clone(),
+            chunks: manifest.chunks.len(),
+            have: download.done,
+        };
+        download.manifest = Some(manifest);
+        vec![event]
+    }
+
+    pub async fn handle_event(&mut self, behaviour: &mut Behaviour, event: Event) -> Vec<TransferEvent> {
+        match event {
+            request_response::Event::Message {
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                let response = self.serve(request).await;
+                // The requester gave up; nothing to do
+                let _ = behaviour.send_response(channel, response);
+                Vec::new()
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+            } => self.handle_response(behaviour, peer, request_id, response).await,
+            request_response::Event::OutboundFailure { peer, request_id, .. } => {
+                self.handle_response(behaviour, peer, request_id, Response::NotFound).await
+            }
+            request_response::Event::InboundFailure { .. } | request_response::Event::ResponseSent { .. } => Vec::new(),
+        }
+    }
+
+    async fn serve(&self, request: Request) -> Response {
+        match request {
+            Request::Manifest { root } => match self.shared.get(&root) {
+                Some((_, manifest)) => Response::Manifest(manifest.clone()),
+                None => Response::NotFound,
+            },
+            Request::Chunk { root, index } => match self.shared.get(&root) {
+                Some((path, manifest)) if (index as usize) < manifest.chunks.len() => {
+                    let (path, manifest) = (path.clone(), manifest.clone());
+                    blocking(move || read_chunk(&path, &manifest, index))
+                        .await
+                        .map(Response::Chunk)
+                        .unwrap_or(Response::NotFound)
+                }
+                _ => Response::NotFound,
+            },
+        }
+    }
+
+    async fn handle_response(
+        &mut self,
+        behaviour: &mut Behaviour,
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        response: Response,
+    ) -> Vec<TransferEvent> {
+        let Some((root, (_, index))) = self
+            .downloads
+            .iter_mut()
+            .find_map(|(root, download)| Some((root.clone(), download.in_flight.remove(&request_id)?)))
+        else {
+            return Vec::new();
+        };
+        let mut download = self.downloads.remove(&root).expect("found above");
+        let mut events = Vec::new();
+
+        match (index, response) {
+            (None, Response::Manifest(manifest)) if manifest.is_consistent() && manifest.root() == root => {
+                download.asked.remove(&peer);
+                if download.manifest.is_none() {
+                    events.extend(self.resume(&root, &mut download, manifest).await);
+                }
+                download.sources.push(peer);
+            }
+            (Some(index), Response::Chunk(data)) => {
+                let manifest = download.manifest.as_ref().expect("chunks are only requested once the manifest is known");
+                if hash_chunk(&data) != manifest.chunks[index as usize] {
+                    eprintln!("Chunk {} of {} from {} failed verification", index, root, peer);
+                    download.sources.retain(|source| *source != peer);
+                    download.missing.push_front(index);
+                } else if let Err(e) = self.write_chunk(&root, manifest.offset(index), data).await {
+                    events.push(failed(&root, format!("cannot write chunk {}: {}", index, e)));
+                    return events;
+                } else {
+                    download.done += 1;
+                    let total = manifest.chunks.len();
+                    // Report every tenth of the file rather than every chunk
+                    if download.done * 10 / total != (download.done - 1) * 10 / total {
+                        events.push(TransferEvent::Progress {
+                            root: root.clone(),
+                            done: download.done,
+                            total,
+                        });
+                    }
+                }
+            }
+            // Not found, refused, timed out or an invalid manifest: stop asking this peer
+            (index, _) => {
+                download.asked.remove(&peer);
+                download.sources.retain(|source| *source != peer);
+                if let Some(index) = index {
+                    download.missing.push_front(index);
+                }
+            }
+        }
+
+        if let Some(manifest) = download.manifest.as_ref().filter(|manifest| download.done == manifest.chunks.len()) {
+            events.push(self.complete(&root, manifest.clone()).await);
+            return events;
+        }
+
+        Self::schedule(behaviour, &root, &mut download);
+        if download.in_flight.is_empty() {
+            // The partial file stays behind for a later resume
+            events.push(failed(&root, "no peer could supply the file; run /fetch again to resume".to_string()));
+        } else {
+            self.downloads.insert(root, download);
+        }
+        events
+    }
+
+    /// Spreads missing chunks over the sources, a few requests per peer.
+    fn schedule(behaviour: &mut Behaviour, root: &str, download: &mut Download) {
+        for source in download.sources.clone() {
+            let busy = download.in_flight.values().filter(|(peer, _)| *peer == source).count();
+            for _ in busy..REQUESTS_PER_PEER {
+                let Some(index) = download.missing.pop_front() else { return };
+                let request_id = behaviour.send_request(
+                    &source,
+                    Request::Chunk {
+                        root: root.to_string(),
+                        index,
+                    },
+                );
+                download.in_flight.insert(request_id, (source, Some(index)));
+            }
+        }
+    }
+
+    async fn write_chunk(&self, root: &str, offset: u64, data: Vec<u8>) -> io::Result<()> {
+        let part = self.part_path(root);
+        blocking(move || {
+            let mut file = OpenOptions::new().write(true).open(part)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&data)
+        })
+        .await
+    }
+
+    /// Moves the finished file into place and starts sharing it too.
+    async fn complete(&mut self, root: &str, manifest: Manifest) -> TransferEvent {
+        // Names come from the remote, so only the final component is used
+        let name = Path::new(&manifest.name)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| root.to_string());
+        let (dir, part, manifest_path) = (self.download_dir.clone(), self.part_path(root), self.manifest_path(root));
+        let renamed = format!("{}-{}", &root[..8], name);
+        let moved = blocking(move || {
+            let mut path = dir.join(&name);
+            if path.exists() {
+                path = dir.join(renamed);
+            }
+            fs::rename(part, &path)?;
+            let _ = fs::remove_file(manifest_path);
+            Ok(path)
+        })
+        .await;
+        let path = match moved {
+            Ok(path) => path,
+            Err(e) => return failed(root, format!("cannot move download into place: {}", e)),
+        };
+        self.shared.insert(root.to_string(), (path.clone(), manifest));
+        TransferEvent::Completed {
+            root: root.to_string(),
+            path,
+        }
+    }
+}
+
+fn failed(root: &str, reason: String) -> TransferEvent {
+    TransferEvent::Failed {
+        root: root.to_string(),
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A transfer downloading into its own directory, removed on drop.
+    struct TempTransfer {
+        transfer: FileTransfer,
+        behaviour: Behaviour,
+        dir: PathBuf,
+    }
+
+    impl TempTransfer {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("hippius-transfer-{}", uuid::Uuid::new_v4()));
+            Self {
+                transfer: FileTransfer::new(dir.clone()),
+                behaviour: behaviour(),
+                dir,
+            }
+        }
+
+        async fn fetch(&mut self, root: &str, peers: &[PeerId]) -> Vec<TransferEvent> {
+            self.transfer.fetch(&mut self.behaviour, root.to_string(), peers.to_vec()).await
+        }
+
+        async fn respond(&mut self, request_id: OutboundRequestId, peer: PeerId, response: Response) -> Vec<TransferEvent> {
+            self.transfer.handle_response(&mut self.behaviour, peer, request_id, response).await
+        }
+
+        /// Requests still waiting for an answer, in chunk order.
+        fn pending(&self, root: &str) -> Vec<(OutboundRequestId, PeerId, Option<u32>)> {
+            let mut pending: Vec<_> = self.transfer.downloads[root]
+                .in_flight
+                .iter()
+                .map(|(request_id, (peer, index))| (*request_id, *peer, *index))
+                .collect();
+            pending.sort_by_key(|(_, _, index)| *index);
+            pending
+        }
+
+        /// Answers every outstanding request from `data`, including ones
+        /// scheduled along the way, until the download leaves.
+        async fn serve_all(&mut self, root: &str, manifest: &Manifest, data: &[u8]) -> Vec<TransferEvent> {
+            let mut events = Vec::new();
+            while self.transfer.downloads.contains_key(root) {
+                let (request_id, peer, index) = self.pending(root)[0];
+                let response = match index {
+                    None => Response::Manifest(manifest.clone()),
+                    Some(index) => Response::Chunk(chunk(data, manifest, index).to_vec()),
+                };
+                events.extend(self.respond(request_id, peer, response).await);
+            }
+            events
+        }
+    }
+
+    impl Drop for TempTransfer {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Small chunks keep the files tiny while still spanning many requests.
+    fn manifest_of(data: &[u8], chunk_size: u32) -> Manifest {
+        Manifest {
+            name: "notes.txt".to_string(),
+            size: data.len() as u64,
+            chunk_size,
+            chunks: data.chunks(chunk_size as usize).map(hash_chunk).collect(),
+        }
+    }
+
+    fn chunk<'a>(data: &'a [u8], manifest: &Manifest, index: u32) -> &'a [u8] {
+        let offset = manifest.offset(index) as usize;
+        &data[offset..offset + manifest.chunk_len(index)]
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn completed(events: &[TransferEvent]) -> Option<&PathBuf> {
+        events.iter().find_map(|event| match event {
+            TransferEvent::Completed { path, .. } => Some(path),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn manifest_from_disk_matches_the_file() {
+        let dir = std::env::temp_dir().join(format!("hippius-transfer-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("big.bin");
+        let data = sample(CHUNK_SIZE as usize * 2 + 100);
+        fs::write(&path, &data).unwrap();
+
+        let manifest = build_manifest(&path).unwrap();
+        assert_eq!(manifest.name, "big.bin");
+        assert_eq!(manifest.chunks, manifest_of(&data, CHUNK_SIZE).chunks);
+        assert!(manifest.is_consistent());
+        assert_eq!(manifest.chunks.len(), 3);
+        assert_eq!(manifest.chunk_len(2), 100);
+        assert_eq!(read_chunk(&path, &manifest, 1).unwrap(), chunk(&data, &manifest, 1));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn resumes_from_a_partial_file() {
+        let mut t = TempTransfer::new();
+        let data = sample(40);
+        let manifest = manifest_of(&data, 4);
+        let root = manifest.root();
+
+        // An earlier attempt got the first six chunks; the rest is garbage
+        let mut part = data[..24].to_vec();
+        part.extend([0xff; 16]);
+        fs::create_dir_all(&t.dir).unwrap();
+        fs::write(t.transfer.part_path(&root), &part).unwrap();
+        fs::write(t.transfer.manifest_path(&root), serde_json::to_vec(&manifest).unwrap()).unwrap();
+
+        let peer = PeerId::random();
+        let events = t.fetch(&root, &[peer]).await;
+        assert!(matches!(events[..], [TransferEvent::Started { chunks: 10, have: 6, .. }]));
+
+        // Once the peer confirms the manifest only the missing chunks are asked for
+        let (request_id, _, _) = t.pending(&root)[0];
+        assert!(t.respond(request_id, peer, Response::Manifest(manifest.clone())).await.is_empty());
+        let indices: Vec<_> = t.pending(&root).iter().map(|(_, _, index)| *index).collect();
+        assert_eq!(indices, [Some(6), Some(7), Some(8), Some(9)]);
+
+        let events = t.serve_all(&root, &manifest, &data).await;
+        let path = completed(&events).expect("download completes");
+        assert_eq!(fs::read(path).unwrap(), data);
+        assert!(!t.transfer.part_path(&root).exists());
+        assert!(!t.transfer.manifest_path(&root).exists());
+    }
+
+    #[tokio::test]
+    async fn corrupted_chunk_drops_the_source_and_is_fetched_again() {
+        let mut t = TempTransfer::new();
+        let data = sample(32);
+        let manifest = manifest_of(&data, 4);
+        let root = manifest.root();
+        let (honest, liar) = (PeerId::random(), PeerId::random());
+
+        t.fetch(&root, &[liar, honest]).await;
+        for (request_id, peer, _) in t.pending(&root) {
+            t.respond(request_id, peer, Response::Manifest(manifest.clone())).await;
+        }
+        let (request_id, _, index) = *t.pending(&root).iter().find(|(_, peer, _)| *peer == liar).unwrap();
+        let index = index.unwrap();
+        let events = t.respond(request_id, liar, Response::Chunk(vec![0; 4])).await;
+        assert!(events.is_empty());
+
+        let download = &t.transfer.downloads[&root];
+        assert_eq!(download.sources, [honest]);
+        assert_eq!(download.missing.front(), Some(&index));
+        assert_eq!(download.done, 0);
+
+        // The liar's other answers never arrive; the honest peer picks up its chunks
+        for (request_id, peer, _) in t.pending(&root) {
+            if peer == liar {
+                t.respond(request_id, liar, Response::NotFound).await;
+            }
+        }
+        let events = t.serve_all(&root, &manifest, &data).await;
+        let path = completed(&events).expect("download completes");
+        assert_eq!(fs::read(path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn rejects_inconsistent_or_foreign_manifests() {
+        let mut t = TempTransfer::new();
+        // Its root is genuine, but the chunk list does not cover the size
+        let mut bad = manifest_of(&sample(32), 4);
+        bad.chunks.pop();
+        let root = bad.root();
+        let other = manifest_of(&sample(16), 4);
+        let (a, b) = (PeerId::random(), PeerId::random());
+
+        t.fetch(&root, &[a, b]).await;
+        let pending = t.pending(&root);
+        let request = |peer| pending.iter().find(|(_, p, _)| *p == peer).unwrap().0;
+        assert!(t.respond(request(a), a, Response::Manifest(bad)).await.is_empty());
+        let events = t.respond(request(b), b, Response::Manifest(other)).await;
+
+        assert!(matches!(events[..], [TransferEvent::Failed { .. }]));
+        assert!(!t.transfer.downloads.contains_key(&root));
+        assert!(!t.transfer.manifest_path(&root).exists());
+        assert!(!t.transfer.part_path(&root).exists());
+    }
+
+    #[tokio::test]
+    async fn spreads_chunks_across_sources() {
+        let mut t = TempTransfer::new();
+        let data = sample(46);
+        let manifest = manifest_of(&data, 4);
+        let root = manifest.root();
+        let peers = [PeerId::random(), PeerId::random(), PeerId::random()];
+
+        t.fetch(&root, &peers).await;
+        for (request_id, peer, _) in t.pending(&root) {
+            t.respond(request_id, peer, Response::Manifest(manifest.clone())).await;
+        }
+
+        let pending = t.pending(&root);
+        let indices: HashSet<_> = pending.iter().filter_map(|(_, _, index)| *index).collect();
+        assert_eq!(indices, (0..12).collect());
+        for peer in peers {
+            let asked = pending.iter().filter(|(_, p, _)| *p == peer).count();
+            assert_eq!(asked, REQUESTS_PER_PEER);
+        }
+
+        let events = t.serve_all(&root, &manifest, &data).await;
+        let path = completed(&events).expect("download completes");
+        assert_eq!(fs::read(path).unwrap(), data);
+    }
+}
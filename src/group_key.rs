@@ -0,0 +1,539 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use libp2p::{
+    identity::Keypair,
+    request_response::{self, json, ProtocolSupport},
+    PeerId, StreamProtocol,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error as StdError,
+    fmt, fs, io,
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    access,
+    direct_message::{self, DmError, Envelope, Receipt},
+    history,
+};
+
+/// Group keys travel sealed to each member, as direct messages do.
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/hippius/group-key/1.0.0");
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type Behaviour = json::Behaviour<KeyDelivery, Receipt>;
+pub type Event = request_response::Event<KeyDelivery, Receipt>;
+
+/// A direct-message envelope carrying a group key, kept apart from chat
+/// envelopes so each protocol has its own event type.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct KeyDelivery(pub Envelope);
+
+pub fn behaviour() -> Behaviour {
+    json::Behaviour::new(
+        [(PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+    )
+}
+
+/// Command-line options for encrypted topics.
+#[derive(clap::Args, Debug, Clone)]
+pub struct GroupKeyArgs {
+    /// Seconds a replaced group key still opens messages, for those sealed just before a rotation
+    #[arg(long, default_value = "300")]
+    pub group_key_grace: u64,
+}
+
+#[derive(Debug)]
+pub enum GroupKeyError {
+    /// No key for the topic has reached us yet
+    Missing(String),
+    /// We hold no key for this epoch, or it is past its grace window
+    NoKey { topic: String, epoch: u64 },
+    NotOwner(String),
+    Malformed(&'static str),
+    Decryption,
+    Envelope(DmError),
+    Io(io::Error),
+}
+
+impl fmt::Display for GroupKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupKeyError::Missing(topic) => write!(f, "no key for {} yet; its owner sends one once you are a member", topic),
+            GroupKeyError::NoKey { topic, epoch } => write!(f, "no key for epoch {} of {}", epoch, topic),
+            GroupKeyError::NotOwner(topic) => write!(f, "only the owner of {} hands out its keys", topic),
+            GroupKeyError::Malformed(what) => write!(f, "malformed {}", what),
+            GroupKeyError::Decryption => write!(f, "message could not be decrypted"),
+            GroupKeyError::Envelope(e) => write!(f, "{}", e),
+            GroupKeyError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for GroupKeyError {}
+
+impl From<io::Error> for GroupKeyError {
+    fn from(e: io::Error) -> Self {
+        GroupKeyError::Io(e)
+    }
+}
+
+impl From<DmError> for GroupKeyError {
+    fn from(e: DmError) -> Self {
+        GroupKeyError::Envelope(e)
+    }
+}
+
+/// The body of a key envelope.
+#[derive(Serialize, Deserialize)]
+struct KeyGrant {
+    topic: String,
+    epoch: u64,
+    /// Base64
+    key: String,
+}
+
+/// A payload on an encrypted topic.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    epoch: u64,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct EpochKey {
+    /// Base64
+    key: String,
+    /// When a newer epoch replaced this one, in seconds since the Unix epoch
+    retired_at: Option<u64>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct TopicKeys {
+    epochs: BTreeMap<u64, EpochKey>,
+    /// Owner only: members and the newest epoch each has confirmed
+    members: HashMap<String, u64>,
+}
+
+impl TopicKeys {
+    fn current(&self) -> Option<(u64, &EpochKey)> {
+        self.epochs.iter().next_back().map(|(epoch, key)| (*epoch, key))
+    }
+
+    /// Adds a key and retires every epoch but the newest, which a late
+    /// grant for an old epoch leaves current.
+    fn insert(&mut self, epoch: u64, key: String) {
+        let now = history::now();
+        self.epochs.insert(epoch, EpochKey { key, retired_at: None });
+        let newest = self.epochs.keys().next_back().copied();
+        for (_, old) in self.epochs.iter_mut().filter(|(epoch, _)| Some(**epoch) != newest) {
+            old.retired_at.get_or_insert(now);
+        }
+    }
+}
+
+fn cipher(key: &str) -> Result<ChaCha20Poly1305, GroupKeyError> {
+    let key: [u8; 32] = BASE64
+        .decode(key)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or(GroupKeyError::Malformed("group key"))?;
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+fn aad(topic: &str, epoch: u64) -> Vec<u8> {
+    [topic.as_bytes(), &epoch.to_be_bytes()].concat()
+}
+
+/// Whether `data` has the shape of a sealed payload, which relays can
+/// check without holding the key.
+pub fn is_sealed(data: &[u8]) -> bool {
+    serde_json::from_slice::<Sealed>(data).is_ok()
+}
+
+/// Symmetric keys for encrypted topics, by epoch. The owner of a topic
+/// makes a new key whenever its members change and seals it to each member;
+/// members keep replaced keys for a grace window so messages sealed just
+/// before a rotation can still be read.
+pub struct GroupKeys {
+    path: PathBuf,
+    grace: Duration,
+    topics: HashMap<String, TopicKeys>,
+    /// Key envelopes awaiting a receipt: envelope id to (topic, epoch, member)
+    in_flight: HashMap<String, (String, u64, PeerId)>,
+}
+
+impl GroupKeys {
+    pub fn open(path: PathBuf, grace: Duration) -> io::Result<Self> {
+        let topics = if path.exists() {
+            serde_json::from_slice(&fs::read(&path)?)?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            path,
+            grace,
+            topics,
+            in_flight: HashMap::new(),
+        })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let partial = self.path.with_extension("tmp");
+        fs::write(&partial, serde_json::to_vec(&self.topics)?)?;
+        fs::rename(&partial, &self.path)
+    }
+
+    /// Starts the first epoch of a topic we own.
+    pub fn create(&mut self, topic: &str) -> io::Result<()> {
+        if !self.topics.contains_key(topic) {
+            self.rotate(topic);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self, topic: &str) -> u64 {
+        let keys = self.topics.entry(topic.to_string()).or_default();
+        let epoch = keys.current().map_or(1, |(epoch, _)| epoch + 1);
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        keys.insert(epoch, BASE64.encode(key));
+        epoch
+    }
+
+    /// Adds or removes a member of a topic we own and rotates its key, so
+    /// newcomers cannot read earlier messages and leavers cannot read later
+    /// ones. Returns the new key sealed to each remaining member.
+    pub fn set_member(
+        &mut self,
+        identity: &Keypair,
+        topic: &str,
+        member: PeerId,
+        present: bool,
+    ) -> Result<Vec<(PeerId, Envelope)>, GroupKeyError> {
+        let owned = access::topic_owner(topic) == Some(identity.public().to_peer_id());
+        let keys = self
+            .topics
+            .get_mut(topic)
+            .filter(|_| owned)
+            .ok_or_else(|| GroupKeyError::NotOwner(topic.to_string()))?;
+        if present {
+            keys.members.insert(member.to_base58(), 0);
+        } else {
+            keys.members.remove(&member.to_base58());
+        }
+        self.rotate(topic);
+        self.save()?;
+        self.in_flight.retain(|_, (pending, _, _)| pending != topic);
+        let members: Vec<PeerId> = self.topics[topic]
+            .members
+            .keys()
+            .filter_map(|member| member.parse().ok())
+            .collect();
+        let mut envelopes = Vec::new();
+        for member in members {
+            envelopes.extend(self.grant(identity, topic, member)?);
+        }
+        Ok(envelopes)
+    }
+
+    /// The current key of every topic we own that `peer` has not confirmed
+    /// and is not already on its way, e.g. when the member reconnects.
+    pub fn pending_for(&mut self, identity: &Keypair, peer: PeerId) -> Vec<(PeerId, Envelope)> {
+        let topics: Vec<String> = self
+            .topics
+            .iter()
+            .filter(|(_, keys)| {
+                let confirmed = keys.members.get(&peer.to_base58());
+                confirmed.is_some_and(|confirmed| Some(*confirmed) < keys.current().map(|(epoch, _)| epoch))
+            })
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        let mut envelopes = Vec::new();
+        for topic in topics {
+            match self.grant(identity, &topic, peer) {
+                Ok(envelope) => envelopes.extend(envelope),
+                Err(e) => eprintln!("Could not seal the key of {} to {}: {}", topic, peer, e),
+            }
+        }
+        envelopes
+    }
+
+    fn grant(&mut self, identity: &Keypair, topic: &str, member: PeerId) -> Result<Option<(PeerId, Envelope)>, GroupKeyError> {
+        let Some((epoch, key)) = self.topics.get(topic).and_then(|keys| keys.current()) else {
+            return Ok(None);
+        };
+        if self.in_flight.values().any(|pending| *pending == (topic.to_string(), epoch, member)) {
+            return Ok(None);
+        }
+        let body = serde_json::to_string(&KeyGrant {
+            topic: topic.to_string(),
+            epoch,
+            key: key.key.clone(),
+        })
+        .expect("key grant serializes");
+        let envelope = direct_message::seal(identity, &member, &body)?;
+        self.in_flight.insert(envelope.id.clone(), (topic.to_string(), epoch, member));
+        Ok(Some((member, envelope)))
+    }
+
+    /// The member confirmed the key in envelope `id`.
+    pub fn delivered(&mut self, id: &str) {
+        if let Some((topic, epoch, member)) = self.in_flight.remove(id) {
+            if let Some(confirmed) = self.topics.get_mut(&topic).and_then(|keys| keys.members.get_mut(&member.to_base58())) {
+                *confirmed = (*confirmed).max(epoch);
+            }
+            if let Err(e) = self.save() {
+                eprintln!("Failed to save group keys: {}", e);
+            }
+        }
+    }
+
+    /// The envelope was refused or given up on; it is sent again when the
+    /// member next connects.
+    pub fn undelivered(&mut self, id: &str) {
+        self.in_flight.remove(id);
+    }
+
+    /// Opens a key envelope from `from`, who must own the topic, and stores
+    /// the key. Returns the topic and epoch.
+    pub fn receive(&mut self, identity: &Keypair, from: &PeerId, envelope: &Envelope) -> Result<(String, u64), GroupKeyError> {
+        let body = direct_message::open(identity, from, envelope)?;
+        let grant: KeyGrant = serde_json::from_str(&body).map_err(|_| GroupKeyError::Malformed("key grant"))?;
+        if access::topic_owner(&grant.topic) != Some(*from) || !access::is_encrypted(&grant.topic) {
+            return Err(GroupKeyError::NotOwner(grant.topic));
+        }
+        cipher(&grant.key)?;
+        let keys = self.topics.entry(grant.topic.clone()).or_default();
+        if !keys.epochs.contains_key(&grant.epoch) {
+            keys.insert(grant.epoch, grant.key);
+            self.save()?;
+        }
+        Ok((grant.topic, grant.epoch))
+    }
+
+    /// Seals `data` with the current key of `topic`.
+    pub fn seal(&self, topic: &str, data: &[u8]) -> Result<Vec<u8>, GroupKeyError> {
+        let (epoch, key) = self
+            .topics
+            .get(topic)
+            .and_then(|keys| keys.current())
+            .ok_or_else(|| GroupKeyError::Missing(topic.to_string()))?;
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = cipher(&key.key)?
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: &aad(topic, epoch) })
+            .map_err(|_| GroupKeyError::Decryption)?;
+        Ok(serde_json::to_vec(&Sealed {
+            epoch,
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        })
+        .expect("sealed payload serializes"))
+    }
+
+    /// Opens a payload sealed with the current key of `topic` or with one
+    /// replaced less than the grace window ago.
+    pub fn unseal(&self, topic: &str, data: &[u8]) -> Result<Vec<u8>, GroupKeyError> {
+        let sealed: Sealed = serde_json::from_slice(data).map_err(|_| GroupKeyError::Malformed("sealed payload"))?;
+        let no_key = || GroupKeyError::NoKey {
+            topic: topic.to_string(),
+            epoch: sealed.epoch,
+        };
+        let key = self
+            .topics
+            .get(topic)
+            .and_then(|keys| keys.epochs.get(&sealed.epoch))
+            .ok_or_else(no_key)?;
+        if key.retired_at.is_some_and(|retired_at| retired_at + self.grace.as_secs() < history::now()) {
+            return Err(no_key());
+        }
+        let nonce: [u8; 12] = BASE64
+            .decode(&sealed.nonce)
+            .ok()
+            .and_then(|nonce| nonce.try_into().ok())
+            .ok_or(GroupKeyError::Malformed("nonce"))?;
+        let ciphertext = BASE64.decode(&sealed.ciphertext).map_err(|_| GroupKeyError::Malformed("ciphertext"))?;
+        cipher(&key.key)?
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: &aad(topic, sealed.epoch) })
+            .map_err(|_| GroupKeyError::Decryption)
+    }
+
+    /// Forgets keys whose grace window has passed; run periodically.
+    pub fn expire(&mut self) {
+        let cutoff = history::now().saturating_sub(self.grace.as_secs());
+        let mut changed = false;
+        for keys in self.topics.values_mut() {
+            let before = keys.epochs.len();
+            keys.epochs.retain(|_, key| key.retired_at.is_none_or(|retired_at| retired_at >= cutoff));
+            changed |= keys.epochs.len() != before;
+        }
+        if changed {
+            if let Err(e) = self.save() {
+                eprintln!("Failed to save group keys: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Group keys of one node in a fresh directory, removed when dropped.
+    struct TempKeys {
+        keys: GroupKeys,
+        identity: Keypair,
+        dir: PathBuf,
+    }
+
+    impl TempKeys {
+        fn open() -> Self {
+            let dir = std::env::temp_dir().join(format!("hippius-group-key-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let keys = GroupKeys::open(dir.join("group_keys.json"), Duration::from_secs(300)).unwrap();
+            Self {
+                keys,
+                identity: Keypair::generate_ed25519(),
+                dir,
+            }
+        }
+
+        fn peer_id(&self) -> PeerId {
+            self.identity.public().to_peer_id()
+        }
+
+        /// Makes `member` a member of our `topic` and hands it the new key.
+        fn admit(&mut self, topic: &str, member: &mut TempKeys) -> u64 {
+            let envelopes = self.keys.set_member(&self.identity, topic, member.peer_id(), true).unwrap();
+            let (_, envelope) = envelopes.into_iter().find(|(to, _)| *to == member.peer_id()).unwrap();
+            let (received, epoch) = member.keys.receive(&member.identity, &self.peer_id(), &envelope).unwrap();
+            assert_eq!(received, topic);
+            epoch
+        }
+    }
+
+    impl Drop for TempKeys {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn sealed_payloads_open_only_with_the_right_key() {
+        let mut owner = TempKeys::open();
+        let topic = access::encrypted_topic(&owner.peer_id(), "vault");
+        owner.keys.create(&topic).unwrap();
+
+        let sealed = owner.keys.seal(&topic, b"secret").unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!is_sealed(b"secret"));
+        assert_eq!(owner.keys.unseal(&topic, &sealed).unwrap(), b"secret");
+
+        // Another node's key for the same topic and epoch does not open it
+        let mut impostor = TempKeys::open();
+        impostor.keys.create(&topic).unwrap();
+        assert!(matches!(impostor.keys.unseal(&topic, &sealed), Err(GroupKeyError::Decryption)));
+
+        // Neither does the right key for another topic, nor altered ciphertext
+        let elsewhere = access::encrypted_topic(&owner.peer_id(), "attic");
+        owner.keys.create(&elsewhere).unwrap();
+        assert!(matches!(owner.keys.unseal(&elsewhere, &sealed), Err(GroupKeyError::Decryption)));
+        let mut altered: Sealed = serde_json::from_slice(&sealed).unwrap();
+        let mut ciphertext = BASE64.decode(&altered.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        altered.ciphertext = BASE64.encode(ciphertext);
+        let altered = serde_json::to_vec(&altered).unwrap();
+        assert!(matches!(owner.keys.unseal(&topic, &altered), Err(GroupKeyError::Decryption)));
+
+        // Nothing to seal with before a key arrives
+        assert!(matches!(impostor.keys.seal("encrypted/none", b"x"), Err(GroupKeyError::Missing(_))));
+    }
+
+    #[test]
+    fn rotation_keeps_old_keys_for_the_grace_window() {
+        let (mut owner, mut member, mut newcomer) = (TempKeys::open(), TempKeys::open(), TempKeys::open());
+        let topic = access::encrypted_topic(&owner.peer_id(), "vault");
+        owner.keys.create(&topic).unwrap();
+        let epoch = owner.admit(&topic, &mut member);
+
+        let before = owner.keys.seal(&topic, b"before").unwrap();
+        assert_eq!(member.keys.unseal(&topic, &before).unwrap(), b"before");
+
+        // A newcomer gets a fresh key and cannot read what came before it
+        assert_eq!(owner.admit(&topic, &mut newcomer), epoch + 1);
+        assert!(matches!(
+            newcomer.keys.unseal(&topic, &before),
+            Err(GroupKeyError::NoKey { epoch: missing, .. }) if missing == epoch
+        ));
+        // Until the member has the new key, it cannot read what is sealed with it
+        let after = owner.keys.seal(&topic, b"after").unwrap();
+        assert!(matches!(member.keys.unseal(&topic, &after), Err(GroupKeyError::NoKey { .. })));
+        assert_eq!(owner.admit(&topic, &mut member), epoch + 2);
+        let latest = owner.keys.seal(&topic, b"latest").unwrap();
+        assert_eq!(member.keys.unseal(&topic, &latest).unwrap(), b"latest");
+
+        // The replaced key still opens messages sealed just before the rotation
+        assert_eq!(member.keys.unseal(&topic, &before).unwrap(), b"before");
+        let retired = member.keys.topics.get_mut(&topic).unwrap().epochs.get_mut(&epoch).unwrap();
+        retired.retired_at = Some(history::now() - 301);
+        assert!(matches!(member.keys.unseal(&topic, &before), Err(GroupKeyError::NoKey { .. })));
+
+        member.keys.expire();
+        assert!(!member.keys.topics[&topic].epochs.contains_key(&epoch));
+        assert_eq!(member.keys.unseal(&topic, &latest).unwrap(), b"latest");
+
+        // Keys survive a restart
+        let reopened = GroupKeys::open(member.dir.join("group_keys.json"), Duration::from_secs(300)).unwrap();
+        assert_eq!(reopened.unseal(&topic, &latest).unwrap(), b"latest");
+    }
+
+    #[test]
+    fn keys_are_only_taken_from_the_topic_owner() {
+        let (owner, mut member, stranger) = (TempKeys::open(), TempKeys::open(), TempKeys::open());
+        let grant = |topic: &str| {
+            serde_json::to_string(&KeyGrant {
+                topic: topic.to_string(),
+                epoch: 1,
+                key: BASE64.encode([7u8; 32]),
+            })
+            .unwrap()
+        };
+
+        // A stranger cannot hand out keys to the owner's topic
+        let topic = access::encrypted_topic(&owner.peer_id(), "vault");
+        let forged = direct_message::seal(&stranger.identity, &member.peer_id(), &grant(&topic)).unwrap();
+        let result = member.keys.receive(&member.identity, &stranger.peer_id(), &forged);
+        assert!(matches!(result, Err(GroupKeyError::NotOwner(_))));
+        // Nor can it pass the envelope off as the owner's
+        assert!(member.keys.receive(&member.identity, &owner.peer_id(), &forged).is_err());
+
+        // Private topics without encryption take no keys, even from their owner
+        let private = access::private_topic(&owner.peer_id(), "board");
+        let envelope = direct_message::seal(&owner.identity, &member.peer_id(), &grant(&private)).unwrap();
+        let result = member.keys.receive(&member.identity, &owner.peer_id(), &envelope);
+        assert!(matches!(result, Err(GroupKeyError::NotOwner(_))));
+        assert!(member.keys.topics.is_empty());
+
+        // Only the owner rotates and hands out keys
+        let mut not_owner = TempKeys::open();
+        not_owner.keys.create(&topic).unwrap();
+        let result = not_owner.keys.set_member(&not_owner.identity, &topic, member.peer_id(), true);
+        assert!(matches!(result, Err(GroupKeyError::NotOwner(_))));
+
+        let envelope = direct_message::seal(&owner.identity, &member.peer_id(), &grant(&topic)).unwrap();
+        assert_eq!(member.keys.receive(&member.identity, &owner.peer_id(), &envelope).unwrap(), (topic, 1));
+    }
+}
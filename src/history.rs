@@ -0,0 +1,744 @@
+use libp2p::{
+    gossipsub::{self, DataTransform, MessageId, RawMessage, TopicHash},
+    identity::{Keypair, PublicKey},
+    request_response::{self, cbor, ProtocolSupport},
+    PeerId, StreamProtocol,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/hippius/history/1.0.0");
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most messages sent in one history response, newest first
+const MAX_RESPONSE_MESSAGES: usize = 1000;
+const MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+/// Allowance for clocks that disagree when asking only for newer messages
+const CLOCK_SKEW: u64 = 60;
+/// Signatures of received messages waiting to be logged
+const MAX_PENDING_SIGNATURES: usize = 4096;
+/// Prepended by gossipsub to the message bytes it signs
+const SIGNING_PREFIX: &[u8] = b"libp2p-pubsub:";
+
+pub type Behaviour = cbor::Behaviour<Request, Response>;
+pub type Event = request_response::Event<Request, Response>;
+
+pub fn behaviour() -> Behaviour {
+    cbor::Behaviour::new(
+        [(PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+    )
+}
+
+/// Command-line options for the on-disk message log.
+#[derive(clap::Args, Debug, Clone)]
+pub struct HistoryArgs {
+    /// Keep a log of topic messages on disk and replay it to peers that join late
+    #[arg(long)]
+    pub history: bool,
+
+    /// Messages kept per topic
+    #[arg(long, default_value = "1000")]
+    pub history_max_messages: usize,
+
+    /// Seconds a message is kept
+    #[arg(long, default_value = "86400")]
+    pub history_max_age: u64,
+
+    /// Bytes of message data kept per topic
+    #[arg(long, default_value = "16777216")]
+    pub history_max_bytes: usize,
+}
+
+/// Asks for the messages a peer has logged on `topic` since `since`, in
+/// seconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub topic: String,
+    pub since: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub messages: Vec<StoredMessage>,
+}
+
+/// A gossipsub message as logged, keyed by its gossipsub message id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: String,
+    pub topic: String,
+    /// Author of the message, when it was signed
+    pub source: Option<String>,
+    #[serde(with = "base64_data")]
+    pub data: Vec<u8>,
+    /// When the logging node first saw it, in seconds since the Unix epoch
+    pub received_at: u64,
+    /// The author's signature, so peers replaying the message cannot alter it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed: Option<Signed>,
+}
+
+/// What gossipsub signed besides the topic and data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signed {
+    pub seqno: u64,
+    #[serde(with = "base64_data")]
+    pub signature: Vec<u8>,
+    /// The author's public key, when it is not inlined in the peer id
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "base64_key")]
+    pub key: Option<Vec<u8>>,
+}
+
+impl StoredMessage {
+    /// Checks that the source signed this message, under this id, the way
+    /// gossipsub checks it on arrival. Returns the verified source.
+    pub fn verify(&self) -> Result<PeerId, &'static str> {
+        let source: PeerId = self.source.as_deref().ok_or("unsigned")?.parse().map_err(|_| "bad_source")?;
+        let signed = self.signed.as_ref().ok_or("unsigned")?;
+        if self.id != message_id(&source, signed.seqno).to_string() {
+            return Err("bad_id");
+        }
+        let key = match signed.key.as_deref().map(PublicKey::try_decode_protobuf) {
+            Some(Ok(key)) => key,
+            _ => PublicKey::try_decode_protobuf(&source.to_bytes()[2..]).map_err(|_| "no_key")?,
+        };
+        if key.to_peer_id() != source {
+            return Err("wrong_key");
+        }
+        if !key.verify(&signed_bytes(&source, signed.seqno, &self.topic, &self.data), &signed.signature) {
+            return Err("bad_signature");
+        }
+        Ok(source)
+    }
+}
+
+/// Gossipsub's default message id: the source followed by the sequence number.
+fn message_id(source: &PeerId, seqno: u64) -> MessageId {
+    MessageId::from(format!("{}{}", source.to_base58(), seqno))
+}
+
+/// The protobuf encoding of a message without signature and key, as signed
+/// by gossipsub.
+fn signed_bytes(source: &PeerId, seqno: u64, topic: &str, data: &[u8]) -> Vec<u8> {
+    let mut bytes = SIGNING_PREFIX.to_vec();
+    let fields: [(u8, &[u8]); 4] = [
+        (0x0a, &source.to_bytes()),
+        (0x12, data),
+        (0x1a, &seqno.to_be_bytes()),
+        (0x22, topic.as_bytes()),
+    ];
+    for (tag, field) in fields {
+        bytes.push(tag);
+        let mut len = field.len();
+        while len >= 0x80 {
+            bytes.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        bytes.push(len as u8);
+        bytes.extend_from_slice(field);
+    }
+    bytes
+}
+
+/// Signs a message this node published, as gossipsub did. `id` is what
+/// `publish` returned; a message nobody was subscribed to has none and gets
+/// a fresh sequence number. Returns the id to log it under.
+pub fn sign(identity: &Keypair, id: Option<&MessageId>, topic: &TopicHash, data: &[u8]) -> Option<(MessageId, Signed)> {
+    let source = identity.public().to_peer_id();
+    let seqno = match id {
+        Some(id) => std::str::from_utf8(&id.0).ok()?.strip_prefix(&source.to_base58())?.parse().ok()?,
+        None => rand::random(),
+    };
+    let signature = identity.sign(&signed_bytes(&source, seqno, topic.as_str(), data)).ok()?;
+    // Gossipsub sends the key only when the peer id cannot carry it
+    let key = PublicKey::try_decode_protobuf(&source.to_bytes()[2..])
+        .is_err()
+        .then(|| identity.public().encode_protobuf());
+    Some((message_id(&source, seqno), Signed { seqno, signature, key }))
+}
+
+/// Keeps the signatures gossipsub checked on incoming messages, which it does
+/// not pass on to the application, until the messages are logged.
+#[derive(Clone, Default)]
+pub struct Signatures(Arc<Mutex<PendingSignatures>>);
+
+#[derive(Default)]
+struct PendingSignatures {
+    signed: HashMap<(PeerId, u64), Signed>,
+    order: VecDeque<(PeerId, u64)>,
+}
+
+impl Signatures {
+    /// Removes and returns the signature of a received message.
+    pub fn take(&self, message: &gossipsub::Message) -> Option<Signed> {
+        let key = (message.source?, message.sequence_number?);
+        self.0.lock().unwrap().signed.remove(&key)
+    }
+}
+
+impl DataTransform for Signatures {
+    fn inbound_transform(&self, raw: RawMessage) -> Result<gossipsub::Message, io::Error> {
+        if let (Some(source), Some(seqno), Some(signature)) = (raw.source, raw.sequence_number, raw.signature) {
+            let mut pending = self.0.lock().unwrap();
+            let signed = Signed { seqno, signature, key: raw.key };
+            if pending.signed.insert((source, seqno), signed).is_none() {
+                pending.order.push_back((source, seqno));
+            }
+            // Messages refused by validation are never taken; the oldest go first
+            while pending.order.len() > MAX_PENDING_SIGNATURES {
+                if let Some(oldest) = pending.order.pop_front() {
+                    pending.signed.remove(&oldest);
+                }
+            }
+        }
+        Ok(gossipsub::Message {
+            source: raw.source,
+            data: raw.data,
+            sequence_number: raw.sequence_number,
+            topic: raw.topic,
+        })
+    }
+
+    fn outbound_transform(&self, _topic: &TopicHash, data: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+        Ok(data)
+    }
+}
+
+/// Message data as base64 so the log stays readable JSON lines.
+mod base64_data {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        BASE64.decode(String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+mod base64_key {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(key: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match key {
+            Some(key) => serializer.serialize_some(&BASE64.encode(key)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|key| BASE64.decode(key).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub max_messages: usize,
+    pub max_age: Duration,
+    pub max_bytes: usize,
+}
+
+impl From<&HistoryArgs> for Retention {
+    fn from(args: &HistoryArgs) -> Self {
+        Self {
+            max_messages: args.history_max_messages,
+            max_age: Duration::from_secs(args.history_max_age),
+            max_bytes: args.history_max_bytes,
+        }
+    }
+}
+
+/// One topic's messages, oldest first, mirrored in an append-only file.
+struct TopicLog {
+    path: PathBuf,
+    messages: VecDeque<StoredMessage>,
+    ids: HashSet<String>,
+    bytes: usize,
+    /// Lines in the file, including messages already dropped from memory
+    lines_on_disk: usize,
+}
+
+impl TopicLog {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            messages: VecDeque::new(),
+            ids: HashSet::new(),
+            bytes: 0,
+            lines_on_disk: 0,
+        }
+    }
+
+    fn load(path: PathBuf) -> io::Result<Self> {
+        let mut log = Self::new(path);
+        for line in BufReader::new(File::open(&log.path)?).lines() {
+            log.lines_on_disk += 1;
+            // A line cut short by a crash is skipped, and dropped at the next compaction
+            if let Ok(message) = serde_json::from_str::<StoredMessage>(&line?) {
+                log.push(message);
+            }
+        }
+        Ok(log)
+    }
+
+    fn push(&mut self, message: StoredMessage) {
+        if !self.ids.insert(message.id.clone()) {
+            return;
+        }
+        self.bytes += message.data.len();
+        // Replayed messages can be older than ones already logged
+        let at = self.messages.partition_point(|logged| logged.received_at <= message.received_at);
+        self.messages.insert(at, message);
+    }
+
+    fn append(&mut self, message: &StoredMessage) -> io::Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(&line)?;
+        self.lines_on_disk += 1;
+        Ok(())
+    }
+
+    fn prune(&mut self, retention: &Retention, now: u64) {
+        let cutoff = now.saturating_sub(retention.max_age.as_secs());
+        while let Some(oldest) = self.messages.front() {
+            if self.messages.len() <= retention.max_messages
+                && self.bytes <= retention.max_bytes
+                && oldest.received_at >= cutoff
+            {
+                break;
+            }
+            if let Some(oldest) = self.messages.pop_front() {
+                self.bytes -= oldest.data.len();
+                self.ids.remove(&oldest.id);
+            }
+        }
+    }
+
+    /// Rewrites the file without pruned messages once they make up most of it.
+    fn compact(&mut self) -> io::Result<()> {
+        if self.lines_on_disk <= 2 * self.messages.len() + 64 {
+            return Ok(());
+        }
+        let mut contents = Vec::new();
+        for message in &self.messages {
+            contents.extend(serde_json::to_vec(message)?);
+            contents.push(b'\n');
+        }
+        let partial = self.path.with_extension("tmp");
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, &self.path)?;
+        self.lines_on_disk = self.messages.len();
+        Ok(())
+    }
+}
+
+/// Per-topic message logs on disk, served to peers over the history
+/// protocol, and the bookkeeping to fetch what this node missed.
+pub struct History {
+    dir: PathBuf,
+    retention: Retention,
+    logs: HashMap<String, TopicLog>,
+    /// Peers already asked about a topic while connected
+    synced: HashSet<(PeerId, TopicHash)>,
+}
+
+impl History {
+    pub fn open(dir: PathBuf, retention: Retention) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut logs = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "jsonl") {
+                let mut log = TopicLog::load(path)?;
+                log.prune(&retention, now());
+                match log.messages.front().map(|message| message.topic.clone()) {
+                    Some(topic) => {
+                        log.compact()?;
+                        logs.insert(topic, log);
+                    }
+                    None => fs::remove_file(&log.path)?,
+                }
+            }
+        }
+        Ok(Self {
+            dir,
+            retention,
+            logs,
+            synced: HashSet::new(),
+        })
+    }
+
+    fn log(&mut self, topic: &str) -> &mut TopicLog {
+        let dir = &self.dir;
+        self.logs.entry(topic.to_string()).or_insert_with(|| {
+            // Topic names can hold any character, so files are named by their hash
+            TopicLog::new(dir.join(format!("{}.jsonl", &hex::encode(Sha256::digest(topic))[..32])))
+        })
+    }
+
+    /// Logs a message unless it is already logged. Returns whether it was new.
+    pub fn insert(&mut self, message: StoredMessage) -> io::Result<bool> {
+        let retention = self.retention;
+        let log = self.log(&message.topic);
+        if log.ids.contains(&message.id) {
+            return Ok(false);
+        }
+        log.append(&message)?;
+        log.push(message);
+        log.prune(&retention, now());
+        Ok(true)
+    }
+
+    /// Drops expired messages and compacts files; run periodically.
+    pub fn expire(&mut self) {
+        let now = now();
+        for log in self.logs.values_mut() {
+            log.prune(&self.retention, now);
+            if let Err(e) = log.compact() {
+                eprintln!("Failed to compact {}: {}", log.path.display(), e);
+            }
+        }
+    }
+
+    /// Asks `peer` for what it logged on `topic`, once per connection.
+    pub fn sync(&mut self, behaviour: &mut Behaviour, peer: PeerId, topic: &TopicHash) {
+        if !self.synced.insert((peer, topic.clone())) {
+            return;
+        }
+        let since = self
+            .logs
+            .get(topic.as_str())
+            .and_then(|log| log.messages.back())
+            .map_or(0, |newest| newest.received_at.saturating_sub(CLOCK_SKEW));
+        behaviour.send_request(&peer, Request { topic: topic.to_string(), since });
+    }
+
+    /// Lets `peer` be asked again when it reconnects, for what was sent meanwhile.
+    pub fn forget_peer(&mut self, peer: &PeerId) {
+        self.synced.retain(|(synced, _)| synced != peer);
+    }
+
+    /// Serves requests from peers that `may_read` the topic and returns
+    /// messages from responses that were not already logged, oldest first.
+    /// Replayed messages are only logged when their author's signature holds
+    /// and they pass `accept`.
+    pub fn handle_event(
+        &mut self,
+        behaviour: &mut Behaviour,
+        event: Event,
+        may_read: impl Fn(&PeerId, &str) -> bool,
+        accept: impl Fn(&StoredMessage) -> bool,
+    ) -> Vec<StoredMessage> {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            } => {
+                let response = if may_read(&peer, &request.topic) {
+                    self.serve(&request)
+                } else {
+                    Response { messages: Vec::new() }
+                };
+                // The requester gave up; nothing to do
+                let _ = behaviour.send_response(channel, response);
+                Vec::new()
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { response, .. },
+            } => self.replay(&peer, response.messages, accept),
+            request_response::Event::OutboundFailure { peer, error, .. } => {
+                eprintln!("History sync with {} failed: {}", peer, error);
+                Vec::new()
+            }
+            request_response::Event::InboundFailure { .. } | request_response::Event::ResponseSent { .. } => Vec::new(),
+        }
+    }
+
+    /// Logs the messages `peer` sent back, oldest first. Their times come
+    /// from the peer, so none is taken to be later than now here: a future
+    /// one would outlive retention and move every later sync past what is
+    /// really new.
+    fn replay(
+        &mut self,
+        peer: &PeerId,
+        messages: Vec<StoredMessage>,
+        accept: impl Fn(&StoredMessage) -> bool,
+    ) -> Vec<StoredMessage> {
+        let now = now();
+        let mut replayed = Vec::new();
+        for mut message in messages {
+            if message.data.is_empty() || message.verify().is_err() || !accept(&message) {
+                continue;
+            }
+            message.received_at = message.received_at.min(now);
+            match self.insert(message.clone()) {
+                Ok(true) => replayed.push(message),
+                Ok(false) => {}
+                Err(e) => eprintln!("Failed to log message {} from {}: {}", message.id, peer, e),
+            }
+        }
+        replayed.sort_by_key(|message| message.received_at);
+        replayed
+    }
+
+    fn serve(&self, request: &Request) -> Response {
+        let mut messages = Vec::new();
+        let mut bytes = 0;
+        if let Some(log) = self.logs.get(&request.topic) {
+            for message in log.messages.iter().rev() {
+                if message.received_at < request.since
+                    || messages.len() == MAX_RESPONSE_MESSAGES
+                    || bytes + message.data.len() > MAX_RESPONSE_BYTES
+                {
+                    break;
+                }
+                bytes += message.data.len();
+                messages.push(message.clone());
+            }
+        }
+        messages.reverse();
+        Response { messages }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A history in a fresh directory, removed when dropped.
+    struct TempHistory {
+        history: History,
+        dir: PathBuf,
+    }
+
+    impl TempHistory {
+        fn open(retention: Retention) -> Self {
+            let dir = std::env::temp_dir().join(format!("hippius-history-{}", uuid::Uuid::new_v4()));
+            let history = History::open(dir.clone(), retention).unwrap();
+            Self { history, dir }
+        }
+
+        /// Loads the history again from its files, as after a restart.
+        fn reopen(&mut self) {
+            self.history = History::open(self.dir.clone(), self.history.retention).unwrap();
+        }
+
+        fn lines_on_disk(&self, topic: &str) -> usize {
+            let path = &self.history.logs[topic].path;
+            fs::read_to_string(path).unwrap().lines().count()
+        }
+    }
+
+    impl Drop for TempHistory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn retention(max_messages: usize, max_bytes: usize) -> Retention {
+        Retention {
+            max_messages,
+            max_age: Duration::from_secs(3600),
+            max_bytes,
+        }
+    }
+
+    fn message(n: usize, received_at: u64, data: &[u8]) -> StoredMessage {
+        StoredMessage {
+            id: format!("message-{}", n),
+            topic: "chat".to_string(),
+            source: None,
+            data: data.to_vec(),
+            received_at,
+            signed: None,
+        }
+    }
+
+    fn signed(identity: &Keypair, data: &[u8]) -> StoredMessage {
+        let topic = TopicHash::from_raw("chat");
+        let (id, signed) = sign(identity, None, &topic, data).unwrap();
+        StoredMessage {
+            id: id.to_string(),
+            topic: topic.to_string(),
+            source: Some(identity.public().to_peer_id().to_base58()),
+            data: data.to_vec(),
+            received_at: now(),
+            signed: Some(signed),
+        }
+    }
+
+    fn served(history: &History, since: u64) -> Vec<String> {
+        let request = Request { topic: "chat".to_string(), since };
+        history.serve(&request).messages.into_iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn signed_messages_verify_only_as_signed() {
+        let identity = Keypair::generate_ed25519();
+        let original = signed(&identity, b"hello");
+        assert_eq!(original.verify(), Ok(identity.public().to_peer_id()));
+
+        let mut tampered = original.clone();
+        tampered.data = b"goodbye".to_vec();
+        assert_eq!(tampered.verify(), Err("bad_signature"));
+
+        let mut moved = original.clone();
+        moved.topic = "elsewhere".to_string();
+        assert_eq!(moved.verify(), Err("bad_signature"));
+
+        let mut renamed = original.clone();
+        renamed.id = "message-1".to_string();
+        assert_eq!(renamed.verify(), Err("bad_id"));
+
+        // Claiming someone else wrote it breaks the id, and then the key
+        let mut impersonated = original.clone();
+        let other = PeerId::random();
+        impersonated.source = Some(other.to_base58());
+        assert_eq!(impersonated.verify(), Err("bad_id"));
+        impersonated.id = message_id(&other, original.signed.as_ref().unwrap().seqno).to_string();
+        assert!(impersonated.verify().is_err());
+
+        let mut unsigned = original;
+        unsigned.signed = None;
+        assert_eq!(unsigned.verify(), Err("unsigned"));
+    }
+
+    #[test]
+    fn replayed_messages_are_not_dated_in_the_future() {
+        let mut temp = TempHistory::open(retention(10, 1024));
+        let identity = Keypair::generate_ed25519();
+        let (now, peer) = (now(), PeerId::random());
+
+        let mut early = signed(&identity, b"early");
+        early.received_at = now - 60;
+        let mut future = signed(&identity, b"from the future");
+        future.received_at = now + 365 * 24 * 3600;
+        let replayed = temp.history.replay(&peer, vec![future, early], |_| true);
+
+        let data: Vec<_> = replayed.iter().map(|message| message.data.as_slice()).collect();
+        assert_eq!(data, [&b"early"[..], b"from the future"]);
+        assert_eq!(replayed[0].received_at, now - 60);
+        assert!(replayed[1].received_at <= super::now());
+
+        // The next sync still asks for everything since about now
+        let newest = temp.history.logs["chat"].messages.back().unwrap().received_at;
+        assert!(newest <= super::now());
+
+        // Unsigned or refused messages are not logged at all
+        let unsigned = message(1, now, b"unsigned");
+        assert!(temp.history.replay(&peer, vec![unsigned, signed(&identity, b"refused")], |_| false).is_empty());
+        assert_eq!(served(&temp.history, 0).len(), 2);
+    }
+
+    #[test]
+    fn published_ids_keep_their_sequence_number() {
+        let identity = Keypair::generate_ed25519();
+        let topic = TopicHash::from_raw("chat");
+        let published = message_id(&identity.public().to_peer_id(), 42);
+        let (id, signed) = sign(&identity, Some(&published), &topic, b"hi").unwrap();
+        assert_eq!((id, signed.seqno), (published, 42));
+        assert!(sign(&identity, Some(&MessageId::from("not-ours")), &topic, b"hi").is_none());
+    }
+
+    #[test]
+    fn duplicates_are_logged_once_across_restarts() {
+        let mut temp = TempHistory::open(retention(10, 1024));
+        let now = now();
+        assert!(temp.history.insert(message(1, now, b"a")).unwrap());
+        assert!(!temp.history.insert(message(1, now, b"a")).unwrap());
+        assert!(temp.history.insert(message(2, now, b"b")).unwrap());
+
+        temp.reopen();
+        assert!(!temp.history.insert(message(2, now, b"b")).unwrap());
+        assert_eq!(served(&temp.history, 0), ["message-1", "message-2"]);
+        assert_eq!(temp.lines_on_disk("chat"), 2);
+    }
+
+    #[test]
+    fn retention_drops_the_oldest_messages() {
+        let now = now();
+        let mut temp = TempHistory::open(retention(3, 1024));
+        for n in 0..5 {
+            temp.history.insert(message(n, now, b"x")).unwrap();
+        }
+        assert_eq!(served(&temp.history, 0), ["message-2", "message-3", "message-4"]);
+
+        let mut temp = TempHistory::open(retention(10, 4));
+        for n in 0..3 {
+            temp.history.insert(message(n, now, b"ab")).unwrap();
+        }
+        assert_eq!(served(&temp.history, 0), ["message-1", "message-2"]);
+
+        // Expired messages are dropped, and a replayed one sorts by when it was first seen
+        let mut temp = TempHistory::open(retention(10, 1024));
+        temp.history.insert(message(0, now - 7200, b"old")).unwrap();
+        temp.history.insert(message(1, now, b"new")).unwrap();
+        temp.history.insert(message(2, now - 60, b"replayed")).unwrap();
+        assert_eq!(served(&temp.history, 0), ["message-2", "message-1"]);
+    }
+
+    #[test]
+    fn compaction_rewrites_files_that_are_mostly_pruned() {
+        let now = now();
+        let mut temp = TempHistory::open(retention(2, 1024));
+        for n in 0..60 {
+            temp.history.insert(message(n, now, b"x")).unwrap();
+        }
+        temp.history.expire();
+        assert_eq!(temp.lines_on_disk("chat"), 60, "compacted before pruned lines dominate");
+
+        for n in 60..70 {
+            temp.history.insert(message(n, now, b"x")).unwrap();
+        }
+        temp.history.expire();
+        assert_eq!(temp.lines_on_disk("chat"), 2);
+
+        temp.reopen();
+        assert_eq!(served(&temp.history, 0), ["message-68", "message-69"]);
+    }
+
+    #[test]
+    fn responses_are_capped_and_keep_the_newest() {
+        let now = now();
+        let mut temp = TempHistory::open(retention(2000, usize::MAX));
+        for n in 0..MAX_RESPONSE_MESSAGES + 1 {
+            temp.history.insert(message(n, now, b"x")).unwrap();
+        }
+        let ids = served(&temp.history, 0);
+        assert_eq!(ids.len(), MAX_RESPONSE_MESSAGES);
+        assert_eq!(ids.first().unwrap(), "message-1");
+        assert_eq!(ids.last().unwrap(), &format!("message-{}", MAX_RESPONSE_MESSAGES));
+
+        let mut temp = TempHistory::open(retention(2000, usize::MAX));
+        let chunk = vec![0; MAX_RESPONSE_BYTES / 4];
+        for n in 0..5 {
+            temp.history.insert(message(n, now, &chunk)).unwrap();
+        }
+        assert_eq!(served(&temp.history, 0), ["message-1", "message-2", "message-3", "message-4"]);
+
+        let mut temp = TempHistory::open(retention(10, 1024));
+        temp.history.insert(message(0, now - 600, b"x")).unwrap();
+        temp.history.insert(message(1, now, b"x")).unwrap();
+        assert_eq!(served(&temp.history, now - 300), ["message-1"]);
+    }
+}
@@ -0,0 +1,138 @@
+use std::{error::Error as StdError, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use futures::future::try_join_all;
+use rustls::ServerConfig;
+use tokio::net::TcpListener;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower_http::{
+    cors::{AllowOrigin, Any, CorsLayer},
+    limit::RequestBodyLimitLayer,
+    trace::TraceLayer,
+};
+
+type BoxError = Box<dyn StdError + Send + Sync + 'static>;
+
+/// Listener layout and the middleware shared by every HTTP route.
+#[derive(clap::Args, Debug, Clone)]
+pub struct HttpArgs {
+    /// Serve the web client and /signal together on this port instead of on the
+    /// separate web and signaling listeners; /metrics and /stats join them only
+    /// when --admin-token is set
+    #[arg(long)]
+    pub http_port: Option<u16>,
+
+    /// Bearer token required for /metrics and /stats
+    #[arg(long)]
+    pub admin_token: Option<String>,
+
+    /// Origin allowed to make cross-origin requests (repeatable; "*" allows any)
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    pub cors_origins: Vec<String>,
+
+    /// Largest request body accepted, in bytes
+    #[arg(long, default_value = "1048576")]
+    pub http_max_body: usize,
+
+    /// Requests handled at once per listener; further requests wait their turn
+    #[arg(long, default_value = "1024")]
+    pub http_max_concurrency: usize,
+}
+
+/// One socket and the routes served on it.
+pub struct Listener {
+    pub name: &'static str,
+    pub addr: SocketAddr,
+    pub router: Router,
+}
+
+/// Requires `Authorization: Bearer <token>` on every route of `router`.
+pub fn require_admin(router: Router, token: Option<String>) -> Router {
+    match token {
+        Some(token) => router.layer(middleware::from_fn_with_state(Arc::new(token), check_admin_token)),
+        None => router,
+    }
+}
+
+async fn check_admin_token(State(token): State<Arc<String>>, request: Request, next: Next) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Compare without an early exit so the token can't be guessed byte by byte
+    let authorized = presented.is_some_and(|presented| {
+        presented.len() == token.len()
+            && presented.bytes().zip(token.bytes()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+    });
+    if authorized {
+        next.run(request).await
+    } else {
+        (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")]).into_response()
+    }
+}
+
+/// CORS, request tracing and limits, applied to every listener.
+fn with_middleware(router: Router, args: &HttpArgs) -> Router {
+    let router = router
+        .layer(RequestBodyLimitLayer::new(args.http_max_body))
+        .layer(GlobalConcurrencyLimitLayer::new(args.http_max_concurrency));
+
+    let router = if args.cors_origins.is_empty() {
+        router
+    } else {
+        let origins = if args.cors_origins.iter().any(|origin| origin == "*") {
+            AllowOrigin::from(Any)
+        } else {
+            AllowOrigin::list(args.cors_origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok()))
+        };
+        router.layer(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods([Method::GET, Method::HEAD, Method::POST])
+                .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+                .max_age(Duration::from_secs(3600)),
+        )
+    };
+
+    router.layer(TraceLayer::new_for_http())
+}
+
+async fn serve(listener: Listener, args: HttpArgs, tls: Option<Arc<ServerConfig>>) -> Result<(), BoxError> {
+    let app = with_middleware(listener.router, &args).into_make_service_with_connect_info::<SocketAddr>();
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    println!("{} listening on {}://{}", listener.name, scheme, listener.addr);
+
+    match tls {
+        Some(tls) => {
+            axum_server::bind_rustls(listener.addr, RustlsConfig::from_config(tls))
+                .serve(app)
+                .await
+                .map_err(|e| format!("{} on {}: {}", listener.name, listener.addr, e))?;
+        }
+        None => {
+            let socket = TcpListener::bind(listener.addr)
+                .await
+                .map_err(|e| format!("{} cannot bind {}: {}", listener.name, listener.addr, e))?;
+            axum::serve(socket, app).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs every listener until one of them fails.
+pub async fn serve_all(
+    listeners: Vec<Listener>,
+    args: &HttpArgs,
+    tls: Option<Arc<ServerConfig>>,
+) -> Result<(), BoxError> {
+    try_join_all(listeners.into_iter().map(|listener| serve(listener, args.clone(), tls.clone()))).await?;
+    Ok(())
+}
@@ -0,0 +1,589 @@
+use libp2p::PeerId;
+use reqwest::{multipart, Client, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    error::Error as StdError,
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{mpsc, Semaphore};
+
+/// `pin/add` calls running against Kubo at once
+const MAX_CONCURRENT_PINS: usize = 4;
+/// Pins running or waiting for a turn; announcements beyond this are not pinned
+const MAX_QUEUED_PINS: usize = 256;
+/// CIDs tracked for `/pins`; the oldest not being pinned here are forgotten first
+const MAX_RECORDS: usize = 10_000;
+
+/// Command-line options for the Kubo RPC client.
+#[derive(clap::Args, Debug, Clone)]
+pub struct KuboArgs {
+    /// Kubo RPC API to pin and publish through (e.g., http://127.0.0.1:5001); disabled when unset
+    #[arg(long)]
+    pub kubo_api: Option<Url>,
+
+    /// Gossipsub topic on which CIDs are announced and pin status is reported
+    #[arg(long, default_value = "hippius/pins")]
+    pub kubo_topic: String,
+
+    /// Seconds a Kubo RPC call may take; pinning waits until Kubo has fetched the whole DAG
+    #[arg(long, default_value = "600")]
+    pub kubo_timeout: u64,
+
+    /// Peer whose announcements get pinned (repeatable); announcements from any peer do when unset
+    #[arg(long = "kubo-pin-from", value_name = "PEER_ID")]
+    pub kubo_pin_from: Vec<PeerId>,
+}
+
+#[derive(Debug)]
+pub enum KuboError {
+    Http(reqwest::Error),
+    /// Kubo answered with an error, e.g. an invalid CID
+    Api { status: StatusCode, message: String },
+}
+
+impl fmt::Display for KuboError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KuboError::Http(e) => write!(f, "Kubo RPC request failed: {}", e),
+            KuboError::Api { status, message } => write!(f, "Kubo returned {}: {}", status, message),
+        }
+    }
+}
+
+impl StdError for KuboError {}
+
+impl From<reqwest::Error> for KuboError {
+    fn from(e: reqwest::Error) -> Self {
+        KuboError::Http(e)
+    }
+}
+
+/// Body of a Kubo RPC error response.
+#[derive(Deserialize)]
+struct ApiError {
+    #[serde(rename = "Message")]
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Added {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Hash")]
+    pub cid: String,
+    /// Kubo reports the size as a decimal string
+    #[serde(rename = "Size")]
+    pub size: String,
+}
+
+#[derive(Deserialize)]
+struct Pinned {
+    #[serde(rename = "Pins")]
+    pins: Vec<String>,
+}
+
+/// Client for the parts of the Kubo RPC API used here. Every endpoint is
+/// called with POST, as Kubo rejects GET on its RPC port.
+#[derive(Clone)]
+pub struct KuboClient {
+    api: Url,
+    http: Client,
+}
+
+impl KuboClient {
+    pub fn new(api: Url, timeout: Duration) -> Result<Self, KuboError> {
+        let http = Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(timeout)
+            .build()?;
+        Ok(Self { api, http })
+    }
+
+    fn endpoint(&self, path: &str) -> Url {
+        let mut url = self.api.clone();
+        url.set_path(&format!("/api/v0/{}", path));
+        url
+    }
+
+    async fn call(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, KuboError> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await?;
+        let message = match serde_json::from_str::<ApiError>(&body) {
+            Ok(error) => error.message,
+            Err(_) => body.trim().to_string(),
+        };
+        Err(KuboError::Api { status, message })
+    }
+
+    /// Adds `data` as a file, pinned, and returns its CIDv1.
+    pub async fn add(&self, name: &str, data: Vec<u8>) -> Result<Added, KuboError> {
+        let form = multipart::Form::new().part("file", multipart::Part::bytes(data).file_name(name.to_string()));
+        let response = self
+            .call(
+                self.http
+                    .post(self.endpoint("add"))
+                    .query(&[("cid-version", "1"), ("pin", "true")])
+                    .multipart(form),
+            )
+            .await?;
+        // Kubo streams one JSON object per added entry; the single file is the last
+        let body = response.text().await?;
+        let last = body.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or_default();
+        serde_json::from_str(last).map_err(|e| KuboError::Api {
+            status: StatusCode::OK,
+            message: format!("unexpected add response: {}", e),
+        })
+    }
+
+    /// Pins `cid` recursively, fetching it from the IPFS network if needed.
+    pub async fn pin_add(&self, cid: &str) -> Result<Vec<String>, KuboError> {
+        let response = self
+            .call(self.http.post(self.endpoint("pin/add")).query(&[("arg", cid)]))
+            .await?;
+        Ok(response.json::<Pinned>().await?.pins)
+    }
+
+    pub async fn cat(&self, cid: &str) -> Result<Vec<u8>, KuboError> {
+        let response = self.call(self.http.post(self.endpoint("cat")).query(&[("arg", cid)])).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+/// Messages on the pin topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum PinMessage {
+    /// Content a peer has published, for everyone listening to pin
+    Announce { cid: String, name: String },
+    Pinned { cid: String, peer: String },
+    PinFailed { cid: String, peer: String, reason: String },
+}
+
+impl PinMessage {
+    pub fn cid(&self) -> &str {
+        match self {
+            PinMessage::Announce { cid, .. } | PinMessage::Pinned { cid, .. } | PinMessage::PinFailed { cid, .. } => cid,
+        }
+    }
+
+    /// Parses a message from the pin topic, rejecting anything without a valid CID.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let message: Self = serde_json::from_slice(data).ok()?;
+        message.cid().parse::<cid::Cid>().ok()?;
+        Some(message)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("pin message serializes")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PinStatus {
+    Pinning,
+    Pinned,
+    Failed(String),
+}
+
+impl fmt::Display for PinStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PinStatus::Pinning => write!(f, "pinning"),
+            PinStatus::Pinned => write!(f, "pinned"),
+            PinStatus::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+/// A CID as seen from this node: its own pin and the peers reporting theirs.
+#[derive(Debug, Default)]
+pub struct PinRecord {
+    pub name: Option<String>,
+    pub local: Option<PinStatus>,
+    pub pinned_by: HashSet<String>,
+}
+
+/// Pins announced CIDs through Kubo and tracks who holds what. Kubo calls
+/// run in the background; their outcomes come back as messages on the
+/// channel returned by `new`, to be recorded and published on the topic.
+pub struct Pinner {
+    client: KuboClient,
+    local_peer: String,
+    /// Publishers whose announcements are pinned; empty trusts everyone
+    trusted: HashSet<PeerId>,
+    records: BTreeMap<String, PinRecord>,
+    /// CIDs in `records`, oldest first
+    order: VecDeque<String>,
+    /// A permit per pin running or queued
+    queued: Arc<Semaphore>,
+    running: Arc<Semaphore>,
+    updates: mpsc::UnboundedSender<PinMessage>,
+}
+
+impl Pinner {
+    pub fn new(
+        client: KuboClient,
+        local_peer: PeerId,
+        trusted: HashSet<PeerId>,
+    ) -> (Self, mpsc::UnboundedReceiver<PinMessage>) {
+        let (updates, receiver) = mpsc::unbounded_channel();
+        let pinner = Self {
+            client,
+            local_peer: local_peer.to_base58(),
+            trusted,
+            records: BTreeMap::new(),
+            order: VecDeque::new(),
+            queued: Arc::new(Semaphore::new(MAX_QUEUED_PINS)),
+            running: Arc::new(Semaphore::new(MAX_CONCURRENT_PINS)),
+            updates,
+        };
+        (pinner, receiver)
+    }
+
+    pub fn client(&self) -> &KuboClient {
+        &self.client
+    }
+
+    pub fn records(&self) -> &BTreeMap<String, PinRecord> {
+        &self.records
+    }
+
+    /// Adds a local file to Kubo, which pins it as part of the add. Its
+    /// announcement and pin report arrive on the update channel once Kubo
+    /// has it.
+    pub fn publish(&self, name: String, data: Vec<u8>) {
+        let client = self.client.clone();
+        let updates = self.updates.clone();
+        let peer = self.local_peer.clone();
+        tokio::spawn(async move {
+            match client.add(&name, data).await {
+                Ok(added) => {
+                    println!("Added {} to Kubo as {} ({} bytes)", added.name, added.cid, added.size);
+                    // Reported as pinned first so the announcement does not pin it again
+                    let _ = updates.send(PinMessage::Pinned { cid: added.cid.clone(), peer });
+                    let _ = updates.send(PinMessage::Announce { cid: added.cid, name: added.name });
+                }
+                Err(e) => println!("Could not add {} to Kubo: {}", name, e),
+            }
+        });
+    }
+
+    /// Returns the record for `cid`, making room for it if needed.
+    fn record(&mut self, cid: &str) -> Option<&mut PinRecord> {
+        if !self.records.contains_key(cid) {
+            if self.records.len() >= MAX_RECORDS {
+                let records = &self.records;
+                let oldest = self
+                    .order
+                    .iter()
+                    .position(|cid| !matches!(records[cid].local, Some(PinStatus::Pinning)))?;
+                let evicted = self.order.remove(oldest).expect("found above");
+                self.records.remove(&evicted);
+            }
+            self.order.push_back(cid.to_string());
+        }
+        Some(self.records.entry(cid.to_string()).or_default())
+    }
+
+    /// Records a message `source` published on the topic and pins announced
+    /// CIDs not already pinned or being pinned here.
+    pub fn handle(&mut self, source: PeerId, message: PinMessage) {
+        // Peers report their own pins only; anything else is forged
+        if let PinMessage::Pinned { peer, .. } | PinMessage::PinFailed { peer, .. } = &message {
+            if *peer != source.to_base58() {
+                return;
+            }
+        }
+        let trusted = self.trusted.is_empty() || self.trusted.contains(&source);
+        let local = source.to_base58() == self.local_peer;
+        let (client, updates, peer) = (self.client.clone(), self.updates.clone(), self.local_peer.clone());
+        let (queued, running) = (self.queued.clone(), self.running.clone());
+        let Some(record) = self.record(message.cid()) else {
+            return;
+        };
+        match message {
+            PinMessage::Announce { cid, name } => {
+                record.name = Some(name);
+                if !trusted || matches!(record.local, Some(PinStatus::Pinning | PinStatus::Pinned)) {
+                    return;
+                }
+                let Ok(slot) = queued.try_acquire_owned() else {
+                    println!("Too many pins waiting for Kubo; not pinning {}", cid);
+                    return;
+                };
+                record.local = Some(PinStatus::Pinning);
+                tokio::spawn(async move {
+                    let _slot = slot;
+                    let _running = running.acquire().await.expect("the semaphore is never closed");
+                    let update = match client.pin_add(&cid).await {
+                        Ok(_) => PinMessage::Pinned { cid, peer },
+                        Err(e) => PinMessage::PinFailed { cid, peer, reason: e.to_string() },
+                    };
+                    let _ = updates.send(update);
+                });
+            }
+            PinMessage::Pinned { peer, .. } => {
+                if local {
+                    record.local = Some(PinStatus::Pinned);
+                }
+                record.pinned_by.insert(peer);
+            }
+            PinMessage::PinFailed { peer, reason, .. } => {
+                if local {
+                    record.local = Some(PinStatus::Failed(reason));
+                }
+                record.pinned_by.remove(&peer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Bytes,
+        extract::{Query, State},
+        http::{StatusCode as HttpStatus, Uri},
+        routing::post,
+        Json, Router,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    const CID: &str = "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku";
+
+    /// Requests seen by the mock, as (path and query, body).
+    type Seen = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    async fn record(State(seen): State<Seen>, uri: Uri, body: Bytes) {
+        seen.lock().unwrap().push((uri.to_string(), body.to_vec()));
+    }
+
+    /// Starts a stand-in for Kubo's RPC API on an ephemeral port.
+    async fn mock_kubo() -> (KuboClient, Seen) {
+        let seen = Seen::default();
+        let app = Router::new()
+            .route(
+                "/api/v0/add",
+                post(|state: State<Seen>, uri: Uri, body: Bytes| async move {
+                    record(state, uri, body).await;
+                    // Kubo sends a line per entry; only the last names the whole file
+                    format!(
+                        "{}\n{}\n",
+                        r#"{"Name":"hello.txt","Bytes":6}"#,
+                        serde_json::json!({ "Name": "hello.txt", "Hash": CID, "Size": "14" })
+                    )
+                }),
+            )
+            .route(
+                "/api/v0/pin/add",
+                post(|state: State<Seen>, uri: Uri, query: Query<HashMap<String, String>>| async move {
+                    record(state, uri, Bytes::new()).await;
+                    let cid = &query["arg"];
+                    if cid.parse::<cid::Cid>().is_err() {
+                        return Err((
+                            HttpStatus::INTERNAL_SERVER_ERROR,
+                            Json(serde_json::json!({
+                                "Message": format!("invalid path {:?}: invalid cid", cid),
+                                "Code": 0,
+                                "Type": "error",
+                            })),
+                        ));
+                    }
+                    Ok(Json(serde_json::json!({ "Pins": [cid] })))
+                }),
+            )
+            .route(
+                "/api/v0/cat",
+                post(|state: State<Seen>, uri: Uri| async move {
+                    record(state, uri, Bytes::new()).await;
+                    "hello\n"
+                }),
+            )
+            .with_state(seen.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (KuboClient::new(api, Duration::from_secs(5)).unwrap(), seen)
+    }
+
+    #[tokio::test]
+    async fn add_uploads_the_file_and_returns_its_cid() {
+        let (client, seen) = mock_kubo().await;
+
+        let added = client.add("hello.txt", b"hello\n".to_vec()).await.unwrap();
+        assert_eq!(added.cid, CID);
+        assert_eq!(added.name, "hello.txt");
+        assert_eq!(added.size, "14");
+
+        let seen = seen.lock().unwrap();
+        let (uri, body) = &seen[0];
+        assert_eq!(uri, "/api/v0/add?cid-version=1&pin=true");
+        let body = String::from_utf8_lossy(body);
+        assert!(body.contains(r#"name="file"; filename="hello.txt""#));
+        assert!(body.contains("hello\n"));
+    }
+
+    #[tokio::test]
+    async fn pin_add_and_cat_pass_the_cid() {
+        let (client, seen) = mock_kubo().await;
+
+        assert_eq!(client.pin_add(CID).await.unwrap(), vec![CID.to_string()]);
+        assert_eq!(client.cat(CID).await.unwrap(), b"hello\n");
+
+        let uris: Vec<_> = seen.lock().unwrap().iter().map(|(uri, _)| uri.clone()).collect();
+        assert_eq!(uris, [format!("/api/v0/pin/add?arg={}", CID), format!("/api/v0/cat?arg={}", CID)]);
+    }
+
+    #[tokio::test]
+    async fn api_errors_carry_kubos_message() {
+        let (client, _) = mock_kubo().await;
+
+        match client.pin_add("not-a-cid").await {
+            Err(KuboError::Api { status, message }) => {
+                assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+                assert_eq!(message, r#"invalid path "not-a-cid": invalid cid"#);
+            }
+            other => panic!("expected an API error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn announced_cids_are_pinned_and_reported() {
+        let (client, _) = mock_kubo().await;
+        let (local, publisher) = (PeerId::random(), PeerId::random());
+        let (mut pinner, mut updates) = Pinner::new(client, local, HashSet::new());
+
+        let announce = PinMessage::Announce { cid: CID.to_string(), name: "hello.txt".to_string() };
+        pinner.handle(publisher, announce.clone());
+        // A repeated announcement does not pin twice
+        pinner.handle(publisher, announce);
+
+        let update = updates.recv().await.unwrap();
+        assert!(matches!(&update, PinMessage::Pinned { cid, peer } if cid == CID && *peer == local.to_base58()));
+        pinner.handle(local, update);
+        assert!(updates.try_recv().is_err());
+
+        let record = &pinner.records()[CID];
+        assert_eq!(record.name.as_deref(), Some("hello.txt"));
+        assert!(matches!(record.local, Some(PinStatus::Pinned)));
+        assert!(record.pinned_by.contains(&local.to_base58()));
+    }
+
+    #[tokio::test]
+    async fn reports_only_count_from_the_peer_they_name() {
+        let (client, _) = mock_kubo().await;
+        let (local, honest, forger) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let (mut pinner, _updates) = Pinner::new(client, local, HashSet::new());
+
+        let pinned = |peer: PeerId| PinMessage::Pinned { cid: CID.to_string(), peer: peer.to_base58() };
+        pinner.handle(forger, pinned(honest));
+        pinner.handle(forger, pinned(local));
+        assert!(pinner.records().get(CID).is_none());
+
+        pinner.handle(honest, pinned(honest));
+        let failed = PinMessage::PinFailed { cid: CID.to_string(), peer: honest.to_base58(), reason: "gone".to_string() };
+        pinner.handle(forger, failed);
+        let record = &pinner.records()[CID];
+        assert_eq!(record.pinned_by, HashSet::from([honest.to_base58()]));
+        assert!(record.local.is_none());
+    }
+
+    #[tokio::test]
+    async fn only_trusted_publishers_get_pinned() {
+        let (client, seen) = mock_kubo().await;
+        let (trusted, stranger) = (PeerId::random(), PeerId::random());
+        let (mut pinner, mut updates) = Pinner::new(client, PeerId::random(), HashSet::from([trusted]));
+
+        let announce = PinMessage::Announce { cid: CID.to_string(), name: "hello.txt".to_string() };
+        pinner.handle(stranger, announce.clone());
+        assert!(pinner.records()[CID].local.is_none());
+        assert!(seen.lock().unwrap().is_empty());
+
+        pinner.handle(trusted, announce);
+        assert!(matches!(pinner.records()[CID].local, Some(PinStatus::Pinning)));
+        assert!(matches!(updates.recv().await.unwrap(), PinMessage::Pinned { .. }));
+    }
+
+    /// A Kubo whose pins never finish, counting the calls it got.
+    async fn stuck_kubo() -> (KuboClient, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/api/v0/pin/add",
+                post(|State(calls): State<Arc<AtomicUsize>>| async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    std::future::pending::<()>().await
+                }),
+            )
+            .with_state(calls.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (KuboClient::new(api, Duration::from_secs(60)).unwrap(), calls)
+    }
+
+    fn test_cid(n: usize) -> String {
+        let hash = crate::blockstore::multihash(0x12, &n.to_be_bytes()).unwrap();
+        cid::Cid::new_v1(0x55, hash).to_string()
+    }
+
+    #[tokio::test]
+    async fn pins_are_limited_and_queued() {
+        let (client, calls) = stuck_kubo().await;
+        let publisher = PeerId::random();
+        let (mut pinner, _updates) = Pinner::new(client, PeerId::random(), HashSet::new());
+
+        for n in 0..=MAX_QUEUED_PINS {
+            pinner.handle(publisher, PinMessage::Announce { cid: test_cid(n), name: n.to_string() });
+        }
+        let pinning = pinner.records().values().filter(|record| matches!(record.local, Some(PinStatus::Pinning)));
+        assert_eq!(pinning.count(), MAX_QUEUED_PINS);
+        assert!(pinner.records()[&test_cid(MAX_QUEUED_PINS)].local.is_none());
+
+        while calls.load(Ordering::SeqCst) < MAX_CONCURRENT_PINS {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), MAX_CONCURRENT_PINS);
+    }
+
+    #[tokio::test]
+    async fn oldest_records_are_forgotten_first() {
+        let (client, _) = stuck_kubo().await;
+        let (local, publisher) = (PeerId::random(), PeerId::random());
+        let (mut pinner, _updates) = Pinner::new(client, local, HashSet::new());
+
+        // The oldest record survives while it is being pinned here
+        pinner.handle(publisher, PinMessage::Announce { cid: CID.to_string(), name: "hello.txt".to_string() });
+        for n in 0..MAX_RECORDS {
+            pinner.handle(publisher, PinMessage::Pinned { cid: test_cid(n), peer: publisher.to_base58() });
+        }
+        assert_eq!(pinner.records().len(), MAX_RECORDS);
+        assert!(pinner.records().contains_key(CID));
+        assert!(!pinner.records().contains_key(&test_cid(0)));
+        assert!(pinner.records().contains_key(&test_cid(MAX_RECORDS - 1)));
+    }
+
+    #[test]
+    fn malformed_topic_messages_are_refused() {
+        assert!(PinMessage::decode(br#"{"type":"Announce","cid":"not-a-cid","name":"x"}"#).is_none());
+        assert!(PinMessage::decode(b"hello").is_none());
+        let announce = PinMessage::Announce { cid: CID.to_string(), name: "x".to_string() };
+        assert!(PinMessage::decode(&announce.encode()).is_some());
+    }
+}
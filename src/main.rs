@@ -1,51 +1,91 @@
 use futures_util::StreamExt;
-use std::env::args;
 use libp2p::{
     core::{
         transport::{Boxed, OrTransport, Transport},
         upgrade,
     },
-    gossipsub::{self, IdentTopic},
-    identity::{self, Keypair},
+    dns,
+    gossipsub::{self, IdentTopic, TopicHash},
+    identify,
+    identity::Keypair,
     mdns::{self, tokio::Behaviour as MdnsBehaviour},
     noise,
-    swarm::{NetworkBehaviour, SwarmEvent},
+    request_response,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
     tcp, websocket, yamux, PeerId, Swarm,
 };
-use rand::{rngs::ThreadRng, RngCore};
-use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     error::Error as StdError,
     fs,
-    hash::{Hash, Hasher},
-    path::PathBuf,
+    net::SocketAddr,
+    path::{Path, PathBuf},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::{
     io::AsyncBufReadExt,
-    sync::RwLock,
+    sync::mpsc,
 };
 
-type PeerMap = Arc<RwLock<HashMap<String, tokio::sync::mpsc::UnboundedSender<Message>>>>;
-
 mod monitoring;
 mod metrics_server;
+mod direct_message;
+mod file_transfer;
+mod blockstore;
+mod bitswap;
+mod kubo;
+mod history;
+mod access;
+mod group_key;
+mod rendezvous;
+mod dnsaddr;
+mod bootstrap;
 
-use monitoring::Monitoring;
+use access::{AccessControl, Capability, Rights};
+use bootstrap::Bootstrap;
+use bitswap::Bitswap;
+use blockstore::Blockstore;
+use direct_message::{Outbox, Receipt, SeenMessages};
+use file_transfer::{FileTransfer, TransferEvent};
+use group_key::GroupKeys;
+use history::{History, StoredMessage};
+use kubo::{KuboClient, PinMessage, Pinner};
+use monitoring::{Monitoring, TopicPeers};
+use rendezvous::Rendezvous;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "ServerBehaviourEvent")]
 struct ServerBehaviour {
-    gossipsub: gossipsub::Behaviour,
+    gossipsub: access::Gossipsub,
     mdns: MdnsBehaviour,
+    dm: direct_message::Behaviour,
+    files: file_transfer::Behaviour,
+    history: history::Behaviour,
+    capabilities: access::Behaviour,
+    group_keys: group_key::Behaviour,
+    /// Serves registrations on bootnodes; regular nodes run the client
+    rendezvous_server: Toggle<libp2p::rendezvous::server::Behaviour>,
+    rendezvous: Toggle<libp2p::rendezvous::client::Behaviour>,
+    identify: identify::Behaviour,
+    streams: libp2p_stream::Behaviour,
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 enum ServerBehaviourEvent {
     Gossipsub(gossipsub::Event),
     Mdns(mdns::Event),
+    DirectMessage(direct_message::Event),
+    FileTransfer(file_transfer::Event),
+    History(history::Event),
+    Capability(access::Event),
+    GroupKey(group_key::Event),
+    RendezvousServer(libp2p::rendezvous::server::Event),
+    Rendezvous(libp2p::rendezvous::client::Event),
+    Identify(identify::Event),
+    /// Raw protocol streams report nothing through the swarm
+    Streams,
 }
 
 impl From<gossipsub::Event> for ServerBehaviourEvent {
@@ -60,21 +100,116 @@ impl From<mdns::Event> for ServerBehaviourEvent {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum Message {
-    PeerMessage { from_peer: String, message: Vec<u8> },
-    Command { command: String, args: Vec<String> },
+impl From<direct_message::Event> for ServerBehaviourEvent {
+    fn from(event: direct_message::Event) -> Self {
+        ServerBehaviourEvent::DirectMessage(event)
+    }
+}
+
+impl From<file_transfer::Event> for ServerBehaviourEvent {
+    fn from(event: file_transfer::Event) -> Self {
+        ServerBehaviourEvent::FileTransfer(event)
+    }
+}
+
+impl From<history::Event> for ServerBehaviourEvent {
+    fn from(event: history::Event) -> Self {
+        ServerBehaviourEvent::History(event)
+    }
+}
+
+impl From<access::Event> for ServerBehaviourEvent {
+    fn from(event: access::Event) -> Self {
+        ServerBehaviourEvent::Capability(event)
+    }
+}
+
+impl From<group_key::Event> for ServerBehaviourEvent {
+    fn from(event: group_key::Event) -> Self {
+        ServerBehaviourEvent::GroupKey(event)
+    }
+}
+
+impl From<libp2p::rendezvous::server::Event> for ServerBehaviourEvent {
+    fn from(event: libp2p::rendezvous::server::Event) -> Self {
+        ServerBehaviourEvent::RendezvousServer(event)
+    }
+}
+
+impl From<libp2p::rendezvous::client::Event> for ServerBehaviourEvent {
+    fn from(event: libp2p::rendezvous::client::Event) -> Self {
+        ServerBehaviourEvent::Rendezvous(event)
+    }
+}
+
+impl From<identify::Event> for ServerBehaviourEvent {
+    fn from(event: identify::Event) -> Self {
+        ServerBehaviourEvent::Identify(event)
+    }
+}
+
+impl From<()> for ServerBehaviourEvent {
+    fn from(_: ()) -> Self {
+        ServerBehaviourEvent::Streams
+    }
 }
 
 struct P2pServer {
     swarm: Swarm<ServerBehaviour>,
-    peer_map: PeerMap,
+    identity: Keypair,
     topics: HashMap<String, IdentTopic>,
+    /// Topics published to without subscribing, with the last publish time
+    fanout: HashMap<TopicHash, Instant>,
+    mesh_n: usize,
+    fanout_ttl: Duration,
+    outbox: Outbox,
+    seen_messages: SeenMessages,
+    files: FileTransfer,
+    bitswap: Bitswap,
+    /// Capabilities for private topics, shared with gossipsub's subscription filter
+    access: AccessControl,
+    /// Keys of encrypted topics, and their envelopes waiting to reach members
+    group_keys: GroupKeys,
+    key_outbox: Outbox,
+    /// Redials bootnodes while short of peers, on regular nodes
+    bootstrap: Option<Bootstrap>,
+    /// Registration under our topics at bootnodes, on regular nodes
+    rendezvous: Option<Rendezvous>,
+    /// Topic message log, when --history is set
+    history: Option<History>,
+    /// Signatures of received gossip messages, logged alongside them
+    signatures: history::Signatures,
+    /// Pinning through Kubo, with its topic, when --kubo-api is set
+    kubo: Option<(Pinner, IdentTopic)>,
+    pin_updates: mpsc::UnboundedReceiver<PinMessage>,
     monitoring: Arc<Monitoring>,
 }
 
+fn report_transfers(events: Vec<TransferEvent>) {
+    for event in events {
+        match event {
+            TransferEvent::Started { root, name, chunks, have } => {
+                println!("Fetching {} as {}: {} of {} chunks already on disk", root, name, have, chunks)
+            }
+            TransferEvent::Progress { root, done, total } => {
+                println!("Fetching {}: {}/{} chunks ({}%)", root, done, total, done * 100 / total)
+            }
+            TransferEvent::Completed { root, path } => println!("Fetched {} to {}", root, path.display()),
+            TransferEvent::Failed { root, reason } => println!("Fetch of {} failed: {}", root, reason),
+        }
+    }
+}
+
+/// Application checks a message has to pass before gossipsub forwards it.
+fn validate_message(message: &gossipsub::Message) -> Result<(), &'static str> {
+    if message.data.is_empty() {
+        return Err("empty");
+    }
+    Ok(())
+}
+
 impl P2pServer {
-    async fn new(peer_map: PeerMap, is_bootnode: bool, args: &Args) -> std::result::Result<Self, Box<dyn StdError + Send + Sync + 'static>> {
+    async fn new(is_bootnode: bool, args: &Args, monitoring: Arc<Monitoring>) -> std::result::Result<Self, Box<dyn StdError + Send + Sync + 'static>> {
         // Create data directory if it doesn't exist
         let data_dir = if is_bootnode {
             PathBuf::from("data/bootnode")
@@ -106,28 +241,70 @@ impl P2pServer {
         let gossipsub_config = gossipsub::ConfigBuilder::default()
             .heartbeat_interval(Duration::from_secs(1))
             .validation_mode(gossipsub::ValidationMode::Strict)
+            .validate_messages()
             .build()
             .expect("Valid config");
+        let (mesh_n, fanout_ttl) = (gossipsub_config.mesh_n(), gossipsub_config.fanout_ttl());
 
-        let gossipsub = gossipsub::Behaviour::new(
+        let access = AccessControl::open(local_peer_id, data_dir.join("capabilities.json"))?;
+        let signatures = history::Signatures::default();
+        let gossipsub = access::Gossipsub::new(
             gossipsub::MessageAuthenticity::Signed(local_key.clone()),
             gossipsub_config,
+            access.clone(),
+            signatures.clone(),
         )?;
 
         // Create behaviour
         let behaviour = ServerBehaviour {
             gossipsub,
             mdns: mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)?,
+            dm: direct_message::behaviour(),
+            files: file_transfer::behaviour(),
+            history: history::behaviour(),
+            capabilities: access::behaviour(),
+            group_keys: group_key::behaviour(),
+            rendezvous_server: Toggle::from(is_bootnode.then(|| rendezvous::server(&args.rendezvous))),
+            rendezvous: Toggle::from((!is_bootnode).then(|| libp2p::rendezvous::client::Behaviour::new(local_key.clone()))),
+            // Kubo peers use identify to find out which of them speak Bitswap
+            identify: identify::Behaviour::new(
+                identify::Config::new("ipfs/0.1.0".to_string(), local_key.public())
+                    .with_agent_version(format!("hippius-libp2p/{}", env!("CARGO_PKG_VERSION"))),
+            ),
+            streams: libp2p_stream::Behaviour::new(),
+        };
+        let bitswap = Bitswap::new(behaviour.streams.new_control(), Blockstore::open(data_dir.join("blocks"))?);
+
+        let history = if args.history.history {
+            Some(History::open(data_dir.join("history"), (&args.history).into())?)
+        } else {
+            None
+        };
+
+        let (kubo, pin_updates) = match &args.kubo.kubo_api {
+            Some(api) => {
+                let client = KuboClient::new(api.clone(), Duration::from_secs(args.kubo.kubo_timeout))?;
+                let trusted = args.kubo.kubo_pin_from.iter().copied().collect();
+                let (pinner, updates) = Pinner::new(client, local_peer_id, trusted);
+                (Some((pinner, IdentTopic::new(&args.kubo.kubo_topic))), updates)
+            }
+            None => (None, mpsc::unbounded_channel().1),
+        };
+
+        // Resolve /dns, /dns4, /dns6 and /dnsaddr addresses when dialing
+        let (dns_config, dns_opts) = dnsaddr::resolver_config(&args.dns)?;
+        let dns_tcp = || {
+            dns::tokio::Transport::custom(tcp::tokio::Transport::new(tcp::Config::default()), dns_config.clone(), dns_opts.clone())
         };
 
         // Set up TCP transport
-        let tcp_transport = tcp::tokio::Transport::new(tcp::Config::default())
+        let tcp_transport = dns_tcp()
             .upgrade(upgrade::Version::V1)
             .authenticate(noise::Config::new(&local_key)?)
             .multiplex(yamux::Config::default());
 
         // Set up WebSocket transport
-        let ws_transport = websocket::WsConfig::new(tcp::tokio::Transport::new(tcp::Config::default()))
+        let ws_transport = websocket::WsConfig::new(dns_tcp())
             .upgrade(upgrade::Version::V1)
             .authenticate(noise::Config::new(&local_key)?)
             .multiplex(yamux::Config::default());
@@ -149,70 +326,120 @@ impl P2pServer {
             transport,
             behaviour,
             local_peer_id,
-            libp2p::swarm::Config::with_tokio_executor(),
+            // Direct messages open streams on demand, so idle connections are kept for a while
+            libp2p::swarm::Config::with_tokio_executor().with_idle_connection_timeout(Duration::from_secs(60)),
         );
 
-        // Listen on all supported protocols
-        swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse::<libp2p::Multiaddr>()?)?;
+        // Listen on all supported protocols; bootnodes keep a fixed TCP port so nodes can find them again after a restart
+        let tcp_port = if is_bootnode { args.bootnode_port } else { 0 };
+        swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{}", tcp_port).parse::<libp2p::Multiaddr>()?)?;
         swarm.listen_on("/ip4/0.0.0.0/tcp/0/ws".parse::<libp2p::Multiaddr>()?)?;
 
-        // If not a bootnode, connect to bootstrap nodes
-        if !is_bootnode {
-            let default_addresses = vec![
-                format!("/ip4/127.0.0.1/tcp/{}", args.bootnode_port).parse::<libp2p::Multiaddr>()?,
-                format!("/ip4/127.0.0.1/tcp/{}/ws", args.bootnode_port).parse::<libp2p::Multiaddr>()?,
-            ];
+        // If not a bootnode, connect to bootstrap nodes, and keep doing so while short of peers
+        let bootstrap = if is_bootnode {
+            None
+        } else {
+            let default_addresses = vec![format!("/ip4/127.0.0.1/tcp/{}", args.bootnode_port).parse::<libp2p::Multiaddr>()?];
 
             let bootstrap_addresses = if let Some(addr) = &args.bootnode_address {
-                vec![
-                    addr.parse::<libp2p::Multiaddr>()?,
-                    format!("{}/ws", addr).parse::<libp2p::Multiaddr>()?,
-                ]
+                let addr = addr.parse::<libp2p::Multiaddr>()?;
+                if let Some(libp2p::multiaddr::Protocol::Dnsaddr(_)) = addr.iter().next() {
+                    // A /dnsaddr entry can list several bootnodes, each dialed on its own
+                    let resolver = hickory_resolver::TokioAsyncResolver::tokio(dns_config.clone(), dns_opts.clone());
+                    match dnsaddr::expand(&resolver, addr.clone()).await {
+                        Ok(addresses) => {
+                            println!("Bootstrapping from {} address(es) listed in DNS", addresses.len());
+                            addresses
+                        }
+                        Err(e) => {
+                            // Dialing the name itself resolves it again on every retry
+                            eprintln!("Could not resolve bootnodes: {}", e);
+                            vec![addr]
+                        }
+                    }
+                } else {
+                    vec![addr]
+                }
             } else {
                 default_addresses
             };
 
-            for addr in bootstrap_addresses {
-                swarm.dial(addr)?;
+            Some(Bootstrap::new(&args.bootstrap, bootstrap_addresses))
+        };
+
+        let mut rendezvous = (!is_bootnode).then(|| Rendezvous::new(&args.rendezvous));
+        let mut topics = HashMap::new();
+        if let Some((_, topic)) = &kubo {
+            swarm.behaviour_mut().gossipsub.subscribe(topic)?;
+            topics.insert(args.kubo.kubo_topic.clone(), topic.clone());
+            if let (Some(rendezvous), Some(client)) = (&mut rendezvous, swarm.behaviour_mut().rendezvous.as_mut()) {
+                rendezvous.add_topic(client, &args.kubo.kubo_topic);
+            }
+            match args.kubo.kubo_pin_from.len() {
+                0 => println!("Pinning CIDs announced on {} through Kubo", args.kubo.kubo_topic),
+                n => println!("Pinning CIDs announced on {} by {} trusted peers through Kubo", args.kubo.kubo_topic, n),
             }
         }
 
-        let monitoring = Arc::new(Monitoring::new());
-
         Ok(Self { 
             swarm, 
-            peer_map,
-            topics: HashMap::new(),
+            identity: local_key,
+            topics,
+            fanout: HashMap::new(),
+            mesh_n,
+            fanout_ttl,
+            outbox: Outbox::default(),
+            seen_messages: SeenMessages::default(),
+            files: FileTransfer::new(data_dir.join("downloads")),
+            bitswap,
+            access,
+            group_keys: GroupKeys::open(
+                data_dir.join("group_keys.json"),
+                Duration::from_secs(args.group_key.group_key_grace),
+            )?,
+            key_outbox: Outbox::default(),
+            bootstrap,
+            rendezvous,
+            history,
+            signatures,
+            kubo,
+            pin_updates,
             monitoring,
         })
     }
 
-    async fn broadcast_message(&mut self, from_peer: String, message: Vec<u8>) -> std::result::Result<(), Box<dyn StdError + Send + Sync + 'static>> {
-        let msg = Message::PeerMessage {
-            from_peer,
-            message,
-        };
-        let msg_bytes = serde_json::to_vec(&msg)?;
-
-        let topics: Vec<_> = self.swarm.behaviour().gossipsub.topics().cloned().collect();
-        for topic in topics {
-            self.swarm
-                .behaviour_mut()
-                .gossipsub
-                .publish(topic, msg_bytes.clone())?;
-        }
-
-        Ok(())
-    }
-
     async fn handle_command(&mut self, command: &str, args: &[String]) -> std::result::Result<(), Box<dyn StdError + Send + Sync + 'static>> {
         match command {
             "/create-topic" | "/join-topic" if !args.is_empty() => {
                 let topic_name = &args[0];
+                if let Some(token) = args.get(1) {
+                    let capability = Capability::from_token(token)?;
+                    if capability.topic != *topic_name {
+                        println!("That capability is for {}", capability.topic);
+                        return Ok(());
+                    }
+                    self.access.add_own(capability)?;
+                    self.present_capabilities();
+                }
                 let topic = IdentTopic::new(topic_name);
+                if let Err(gossipsub::SubscriptionError::NotAllowed) = self.swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+                    println!("{} is private; join with a capability from its owner: /join-topic <topic> <token>", topic_name);
+                    return Ok(());
+                }
                 self.topics.insert(topic_name.clone(), topic.clone());
-                self.swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
                 println!("Subscribed to topic: {}", topic_name);
+                self.register_topic(topic_name);
+                let peers: Vec<PeerId> = self
+                    .swarm
+                    .behaviour()
+                    .gossipsub
+                    .all_peers()
+                    .filter(|(_, topics)| topics.contains(&&topic.hash()))
+                    .map(|(peer, _)| *peer)
+                    .collect();
+                for peer in peers {
+                    self.sync_history(peer, topic.hash());
+                }
             }
             "/send" if args.len() >= 2 => {
                 let topic_name = &args[0];
@@ -223,35 +450,664 @@ impl P2pServer {
                     println!("Not subscribed to topic: {}", topic_name);
                 }
             }
+            "/private-topic" if !args.is_empty() => {
+                let topic_name = access::private_topic(&self.peer_id(), &args[0]);
+                let topic = IdentTopic::new(&topic_name);
+                self.swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+                self.topics.insert(topic_name.clone(), topic);
+                println!("Created private topic: {}", topic_name);
+                println!("Let peers in with /grant {} <peer>", topic_name);
+            }
+            "/encrypted-topic" if !args.is_empty() => {
+                let topic_name = access::encrypted_topic(&self.peer_id(), &args[0]);
+                let topic = IdentTopic::new(&topic_name);
+                self.group_keys.create(&topic_name)?;
+                self.swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+                self.topics.insert(topic_name.clone(), topic);
+                println!("Created encrypted topic: {}", topic_name);
+                println!("Let peers in with /grant {} <peer>; each grant or /revoke rotates the key", topic_name);
+            }
+            "/revoke" if args.len() >= 2 => {
+                let topic_name = &args[0];
+                let member: PeerId = args[1].parse()?;
+                self.access.set_revoked(topic_name, member, true)?;
+                // Its subscription was let through when it connected; make it subscribe again
+                if self.swarm.disconnect_peer_id(member).is_ok() {
+                    println!("Disconnected {} so its subscriptions are checked again", member);
+                }
+                if access::is_encrypted(topic_name) {
+                    let envelopes = self.group_keys.set_member(&self.identity, topic_name, member, false)?;
+                    println!("Removed {} from {}; new key sent to {} member(s)", member, topic_name, envelopes.len());
+                    self.queue_keys(envelopes);
+                } else {
+                    println!("Removed {} from {}", member, topic_name);
+                }
+            }
+            "/grant" if args.len() >= 2 => {
+                let topic_name = &args[0];
+                let member: PeerId = args[1].parse()?;
+                let rights = match args.get(2).map(String::as_str) {
+                    None | Some("publish") => Rights::Publish,
+                    Some("subscribe") => Rights::Subscribe,
+                    Some(other) => {
+                        println!("Unknown right {}; use publish or subscribe", other);
+                        return Ok(());
+                    }
+                };
+                let expires = match args.get(3) {
+                    Some(hours) => match hours.parse::<u64>()?.checked_mul(3600).and_then(|secs| history::now().checked_add(secs)) {
+                        Some(expires) => Some(expires),
+                        None => {
+                            println!("{} hours is too far in the future; leave it out for no expiry", hours);
+                            return Ok(());
+                        }
+                    },
+                    None => None,
+                };
+                let capability = Capability::grant(&self.identity, topic_name, member, rights, expires)?;
+                self.access.set_revoked(topic_name, member, false)?;
+                println!("Granted {} on {} to {}. The member joins with:", rights, topic_name, member);
+                println!("/join-topic {} {}", topic_name, capability.to_token());
+                if access::is_encrypted(topic_name) {
+                    let envelopes = self.group_keys.set_member(&self.identity, topic_name, member, true)?;
+                    self.queue_keys(envelopes);
+                }
+            }
+            "/dm" if args.len() >= 2 => {
+                let peer: PeerId = args[0].parse()?;
+                let envelope = direct_message::seal(&self.identity, &peer, &args[1..].join(" "))?;
+                println!("Sending message {} to {}", envelope.id, peer);
+                self.outbox.push(peer, envelope);
+                self.flush_outbox();
+            }
+            "/share" if !args.is_empty() => {
+                let manifest = self.files.share(PathBuf::from(args.join(" "))).await?;
+                println!(
+                    "Sharing {} ({} bytes, {} chunks) as {}",
+                    manifest.name,
+                    manifest.size,
+                    manifest.chunks.len(),
+                    manifest.root()
+                );
+            }
+            "/fetch" if !args.is_empty() => {
+                let peers = match args.get(1) {
+                    Some(peer) => vec![peer.parse()?],
+                    None => self.swarm.connected_peers().copied().collect(),
+                };
+                let events = self.files.fetch(&mut self.swarm.behaviour_mut().files, args[0].clone(), peers).await;
+                report_transfers(events);
+            }
+            "/block-put" if !args.is_empty() => {
+                let data = tokio::fs::read(args.join(" ")).await?;
+                if data.len() > bitswap::MAX_BLOCK_SIZE {
+                    println!("Blocks are limited to {} bytes; use /share for larger files", bitswap::MAX_BLOCK_SIZE);
+                } else {
+                    let cid = self.bitswap.store().put_raw(&data)?;
+                    println!("Stored block {} ({} bytes)", cid, data.len());
+                }
+            }
+            "/block-get" if !args.is_empty() => {
+                let cid: cid::Cid = args[0].parse()?;
+                let bitswap = self.bitswap.clone();
+                tokio::spawn(async move {
+                    match bitswap.get(cid, Duration::from_secs(60)).await {
+                        Ok(data) => println!(
+                            "Block {} ({} bytes) is in {}",
+                            cid,
+                            data.len(),
+                            bitswap.store().dir().display()
+                        ),
+                        Err(e) => println!("Could not fetch block {}: {}", cid, e),
+                    }
+                });
+            }
+            "/publish" | "/cat" | "/pins" if self.kubo.is_none() => {
+                println!("Kubo is not configured; start with --kubo-api");
+            }
+            "/publish" if !args.is_empty() => {
+                let path = PathBuf::from(args.join(" "));
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "file".to_string());
+                let data = tokio::fs::read(&path).await?;
+                if let Some((pinner, _)) = &self.kubo {
+                    pinner.publish(name, data);
+                }
+            }
+            "/cat" if !args.is_empty() => {
+                if let Some((pinner, _)) = &self.kubo {
+                    let client = pinner.client().clone();
+                    let cid = args[0].clone();
+                    tokio::spawn(async move {
+                        match client.cat(&cid).await {
+                            Ok(data) => {
+                                let preview = String::from_utf8_lossy(&data[..data.len().min(1024)]).into_owned();
+                                println!("{} ({} bytes):\n{}", cid, data.len(), preview);
+                            }
+                            Err(e) => println!("Could not read {} from Kubo: {}", cid, e),
+                        }
+                    });
+                }
+            }
+            "/pins" => {
+                if let Some((pinner, _)) = &self.kubo {
+                    if pinner.records().is_empty() {
+                        println!("No CIDs announced yet");
+                    }
+                    for (cid, record) in pinner.records() {
+                        let local = record.local.as_ref().map_or("not pinned".to_string(), |status| status.to_string());
+                        println!(
+                            "  {} {} - here: {}, pinned by {} peer(s)",
+                            cid,
+                            record.name.as_deref().unwrap_or(""),
+                            local,
+                            record.pinned_by.len()
+                        );
+                    }
+                }
+            }
             _ => {
                 println!("Unknown command or invalid arguments");
                 println!("Available commands:");
                 println!("  /create-topic <topic>    - Create and join a new topic");
                 println!("  /join-topic <topic>      - Join an existing topic");
                 println!("  /send <topic> <message>  - Send a message to a topic");
+                println!("  /private-topic <name>    - Create a topic only peers you grant can use");
+                println!("  /encrypted-topic <name>  - Create a private topic whose messages only members can read");
+                println!("  /grant <topic> <peer> [publish|subscribe] [hours] - Issue a capability for a private topic");
+                println!("  /revoke <topic> <peer>   - Remove a member from a private topic, rotating the key if encrypted");
+                println!("  /join-topic <topic> <token> - Join a private topic with a capability");
+                println!("  /dm <peer> <message>     - Send an encrypted direct message to a peer");
+                println!("  /share <path>            - Offer a file to other peers");
+                println!("  /fetch <hash> [peer]     - Download a shared file, from one peer or all connected peers");
+                println!("  /block-put <path>        - Store a file as a raw IPFS block and print its CID");
+                println!("  /block-get <cid>         - Fetch an IPFS block over Bitswap, e.g. from Kubo");
+                println!("  /publish <path>          - Add a file to Kubo and announce its CID for pinning");
+                println!("  /cat <cid>               - Show the start of a file from Kubo");
+                println!("  /pins                    - List announced CIDs and who has pinned them");
             }
         }
         Ok(())
     }
 
     async fn broadcast_message_to_topic(&mut self, topic: IdentTopic, message: Vec<u8>) -> std::result::Result<(), Box<dyn StdError + Send + Sync + 'static>> {
-        self.swarm
-            .behaviour_mut()
-            .gossipsub
-            .publish(topic, message)?;
+        let topic_name = topic.to_string();
+        let message = if access::is_encrypted(&topic_name) {
+            self.group_keys.seal(&topic_name, &message)?
+        } else {
+            message
+        };
+        let message = self.access.wrap(&topic_name, &message)?;
+        let bytes = message.len();
+        let id = match self.swarm.behaviour_mut().gossipsub.publish(topic.clone(), message.clone()) {
+            Ok(id) => Some(id),
+            // Nobody is on the topic yet; the log still lets later joiners replay it
+            Err(gossipsub::PublishError::InsufficientPeers) if self.history.is_some() => {
+                println!("No peers on {} yet; the message is kept for history sync", topic);
+                None
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if self.history.is_some() {
+            // Replayed copies carry our signature like the gossiped ones
+            match history::sign(&self.identity, id.as_ref(), &topic.hash(), &message) {
+                Some((id, signed)) => self.log_message(&id, &topic.hash(), Some(self.peer_id()), &message, Some(signed)),
+                None => eprintln!("Could not sign message on {} for the history log", topic),
+            }
+        }
+        self.record_published(topic.hash(), bytes).await;
         Ok(())
     }
 
-    async fn start(&mut self) -> std::result::Result<(), Box<dyn StdError + Send + Sync + 'static>> {
-        // Start metrics server
-        let monitoring = self.monitoring.clone();
-        tokio::spawn(async move {
-            if let Err(e) = metrics_server::start_metrics_server(monitoring).await {
-                eprintln!("Metrics server error: {}", e);
+    async fn record_published(&mut self, topic: TopicHash, bytes: usize) {
+        self.monitoring.record_topic_published(topic.as_str(), bytes as u64).await;
+        if !self.topics.contains_key(topic.as_str()) {
+            self.fanout.insert(topic, Instant::now());
+        }
+    }
+
+    async fn handle_gossip_message(&mut self, source: PeerId, id: gossipsub::MessageId, message: gossipsub::Message) {
+        let topic = message.topic.to_string();
+        // Announcements on the pin topic must parse, so peers do not relay junk to Kubo users
+        let pin_message = match &self.kubo {
+            Some((_, pin_topic)) if message.topic == pin_topic.hash() => Some(PinMessage::decode(&message.data)),
+            _ => None,
+        };
+        let verdict = match pin_message {
+            Some(None) => validate_message(&message).and(Err("malformed")),
+            _ => validate_message(&message),
+        };
+        // Private topics only carry messages from publishers holding a capability
+        let verdict = verdict
+            .and_then(|()| self.access.unwrap(&topic, message.source, &message.data))
+            // Relays cannot decrypt, but can refuse payloads that were never sealed
+            .and_then(|payload| match access::is_encrypted(&topic) && !group_key::is_sealed(&payload) {
+                true => Err("unencrypted"),
+                false => Ok(payload),
+            });
+        let payload = match verdict {
+            Ok(payload) => payload,
+            Err(reason) => {
+                self.monitoring.record_topic_rejected(&topic, reason).await;
+                let _ = self.swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                    &id,
+                    &source,
+                    gossipsub::MessageAcceptance::Reject,
+                );
+                return;
             }
-        });
+        };
+
+        match self.swarm.behaviour_mut().gossipsub.report_message_validation_result(
+            &id,
+            &source,
+            gossipsub::MessageAcceptance::Accept,
+        ) {
+            Ok(true) => {
+                self.monitoring.record_topic_accepted(&topic).await;
+                // Gossipsub does not say whom it forwarded to: it sends to its mesh
+                // peers on the topic other than the one we got it from and the author
+                let forwarded = self
+                    .swarm
+                    .behaviour()
+                    .gossipsub
+                    .mesh_peers(&message.topic)
+                    .any(|peer| *peer != source && Some(*peer) != message.source);
+                if forwarded {
+                    self.monitoring.record_topic_forwarded(&topic).await;
+                }
+            }
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to forward message {}: {}", id, e),
+        }
+        let signed = self.signatures.take(&message);
+        self.log_message(&id, &message.topic, message.source, &message.data, signed);
+        self.monitoring.record_message_received(&source, message.data.len() as u64).await;
+        self.monitoring.record_topic_received(&topic, message.data.len() as u64).await;
+        if let (Some((pinner, _)), Some(pin_message)) = (&mut self.kubo, pin_message) {
+            if let (Some(pin_message), Some(author)) = (pin_message, message.source) {
+                pinner.handle(author, pin_message);
+            }
+            return;
+        }
+        let payload = match self.decrypt(&topic, payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                println!("Could not read message {} on {}: {}", id, topic, e);
+                return;
+            }
+        };
+        println!(
+            "Got message: {} with id: {} from peer: {:?}",
+            String::from_utf8_lossy(&payload),
+            id,
+            source
+        );
+    }
+
+    fn log_message(
+        &mut self,
+        id: &gossipsub::MessageId,
+        topic: &TopicHash,
+        source: Option<PeerId>,
+        data: &[u8],
+        signed: Option<history::Signed>,
+    ) {
+        if let Some(history) = &mut self.history {
+            let message = StoredMessage {
+                id: id.to_string(),
+                topic: topic.to_string(),
+                source: source.map(|peer| peer.to_base58()),
+                data: data.to_vec(),
+                received_at: history::now(),
+                signed,
+            };
+            if let Err(e) = history.insert(message) {
+                eprintln!("Failed to log message {}: {}", id, e);
+            }
+        }
+    }
+
+    fn sync_history(&mut self, peer: PeerId, topic: TopicHash) {
+        if let Some(history) = &mut self.history {
+            history.sync(&mut self.swarm.behaviour_mut().history, peer, &topic);
+        }
+    }
+
+    fn handle_history_event(&mut self, event: history::Event) {
+        let Some(history) = &mut self.history else {
+            // Without a log there is nothing to replay, but requesters still get an answer
+            if let request_response::Event::Message {
+                message: request_response::Message::Request { channel, .. },
+                ..
+            } = event
+            {
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .history
+                    .send_response(channel, history::Response { messages: Vec::new() });
+            }
+            return;
+        };
+        let access = &self.access;
+        let topics = &self.topics;
+        let unwrap = |message: &StoredMessage| {
+            let source = message.source.as_deref().and_then(|source| source.parse().ok());
+            access.unwrap(&message.topic, source, &message.data)
+        };
+        let group_keys = &self.group_keys;
+        let replayed = history.handle_event(
+            &mut self.swarm.behaviour_mut().history,
+            event,
+            |peer, topic| access.may_subscribe(topic, peer),
+            |message| topics.contains_key(&message.topic) && unwrap(message).is_ok(),
+        );
+        for message in replayed {
+            println!(
+                "Missed message on {}: {} with id: {} from peer: {}",
+                message.topic,
+                match unwrap(&message) {
+                    Ok(payload) if access::is_encrypted(&message.topic) => match group_keys.unseal(&message.topic, &payload) {
+                        Ok(plain) => String::from_utf8_lossy(&plain).into_owned(),
+                        Err(e) => format!("<{}>", e),
+                    },
+                    Ok(payload) => String::from_utf8_lossy(&payload).into_owned(),
+                    Err(reason) => format!("<{}>", reason),
+                },
+                message.id,
+                message.source.as_deref().unwrap_or("unknown")
+            );
+        }
+    }
+
+    /// Dials the bootnodes that are due and exports how connected we are.
+    async fn dial_bootnodes(&mut self) {
+        let Some(bootstrap) = &mut self.bootstrap else {
+            return;
+        };
+        let connected = self.swarm.connected_peers().count();
+        for dial in bootstrap.due(connected) {
+            let connection_id = dial.connection_id();
+            if let Err(e) = self.swarm.dial(dial) {
+                if bootstrap.failed(connection_id, &e) {
+                    self.monitoring.record_bootstrap_dial_failure().await;
+                }
+            }
+        }
+        let stats = bootstrap.update(connected);
+        self.monitoring.update_bootstrap(stats).await;
+    }
+
+    /// Registers under a newly joined topic at our rendezvous points.
+    /// Private and encrypted topics are left out: bootnodes would otherwise
+    /// hand out who their members are to anyone who asks.
+    fn register_topic(&mut self, topic_name: &str) {
+        if access::is_private(topic_name) {
+            return;
+        }
+        if let (Some(rendezvous), Some(client)) = (&mut self.rendezvous, self.swarm.behaviour_mut().rendezvous.as_mut()) {
+            rendezvous.add_topic(client, topic_name);
+        }
+    }
+
+    fn handle_rendezvous_event(&mut self, event: libp2p::rendezvous::client::Event) {
+        let local_peer_id = self.peer_id();
+        let Some(rendezvous) = &mut self.rendezvous else {
+            return;
+        };
+        for dial in rendezvous.handle_event(event, local_peer_id) {
+            let peer = dial.get_peer_id();
+            match self.swarm.dial(dial) {
+                Ok(()) => println!("Dialing {:?}, found through rendezvous", peer),
+                // Already connected or being dialed
+                Err(libp2p::swarm::DialError::DialPeerConditionFalse(_)) => {}
+                Err(e) => eprintln!("Failed to dial {:?}, found through rendezvous: {}", peer, e),
+            }
+        }
+    }
+
+    /// Opens a payload on an encrypted topic; others pass through.
+    fn decrypt(&self, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, group_key::GroupKeyError> {
+        if access::is_encrypted(topic) {
+            self.group_keys.unseal(topic, &payload)
+        } else {
+            Ok(payload)
+        }
+    }
+
+    fn queue_keys(&mut self, envelopes: Vec<(PeerId, direct_message::Envelope)>) {
+        for (member, envelope) in envelopes {
+            self.key_outbox.push(member, envelope);
+        }
+        self.flush_key_outbox();
+    }
+
+    fn flush_key_outbox(&mut self) {
+        for pending in self.key_outbox.due() {
+            let request_id = self.swarm.behaviour_mut().group_keys.send_request(&pending.to, group_key::KeyDelivery(pending.envelope.clone()));
+            self.key_outbox.sent(request_id, pending);
+        }
+    }
+
+    fn handle_group_key_event(&mut self, event: group_key::Event) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            } => {
+                let group_key::KeyDelivery(request) = request;
+                let receipt = match self.group_keys.receive(&self.identity, &peer, &request) {
+                    Ok((topic, epoch)) => {
+                        println!("Received key epoch {} for {}", epoch, topic);
+                        Receipt::Delivered { id: request.id }
+                    }
+                    Err(e) => {
+                        eprintln!("Rejected group key {} from {}: {}", request.id, peer, e);
+                        Receipt::Rejected { id: request.id, reason: e.to_string() }
+                    }
+                };
+                let _ = self.swarm.behaviour_mut().group_keys.send_response(channel, receipt);
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+            } => {
+                if self.key_outbox.answered(&request_id).is_none() {
+                    return;
+                }
+                match response {
+                    Receipt::Delivered { id } => self.group_keys.delivered(&id),
+                    Receipt::Rejected { id, reason } => {
+                        eprintln!("{} refused group key {}: {}", peer, id, reason);
+                        self.group_keys.undelivered(&id);
+                    }
+                }
+            }
+            request_response::Event::OutboundFailure { peer, request_id, error } => {
+                let retryable = !matches!(error, request_response::OutboundFailure::UnsupportedProtocols);
+                if let Some(pending) = self.key_outbox.failed(&request_id, retryable) {
+                    eprintln!("Giving up on sending a group key to {} until it reconnects: {}", peer, error);
+                    self.group_keys.undelivered(&pending.envelope.id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Hands our capabilities to every connected peer.
+    fn present_capabilities(&mut self) {
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer in peers {
+            self.present_capabilities_to(peer);
+        }
+    }
+
+    fn present_capabilities_to(&mut self, peer: PeerId) {
+        let own = self.access.own();
+        if !own.is_empty() {
+            self.swarm.behaviour_mut().capabilities.send_request(&peer, own);
+        }
+    }
+
+    fn handle_capability_event(&mut self, event: access::Event) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            } => {
+                let (admitted, learned) = self.access.present(peer, request);
+                let _ = self.swarm.behaviour_mut().capabilities.send_response(channel, admitted);
+                // The peer may have dropped what we presented before it joined our
+                // topic; answering in kind stops once neither side learns anything new
+                if learned {
+                    self.present_capabilities_to(peer);
+                }
+            }
+            // Our subscription reached the peer before our capability did; announce it again
+            request_response::Event::Message {
+                message: request_response::Message::Response { response, .. },
+                ..
+            } => {
+                for topic_name in response {
+                    let topic = IdentTopic::new(topic_name);
+                    let gossipsub = &mut self.swarm.behaviour_mut().gossipsub;
+                    if gossipsub.unsubscribe(&topic).is_ok_and(|was_subscribed| was_subscribed) {
+                        if let Err(e) = gossipsub.subscribe(&topic) {
+                            eprintln!("Failed to resubscribe to {}: {:?}", topic, e);
+                        }
+                    }
+                }
+            }
+            request_response::Event::OutboundFailure { peer, error, .. } => {
+                eprintln!("Could not present capabilities to {}: {}", peer, error);
+            }
+            _ => {}
+        }
+    }
+
+    /// Records the outcome of a Kubo call made here and tells the pin topic.
+    async fn handle_pin_update(&mut self, update: PinMessage) {
+        let local = *self.swarm.local_peer_id();
+        let Some((pinner, topic)) = &mut self.kubo else {
+            return;
+        };
+        match &update {
+            PinMessage::Announce { cid, name } => println!("Announcing {} ({}) for pinning", cid, name),
+            PinMessage::Pinned { cid, .. } => println!("Pinned {}", cid),
+            PinMessage::PinFailed { cid, reason, .. } => println!("Could not pin {}: {}", cid, reason),
+        }
+        pinner.handle(local, update.clone());
+        let topic = topic.clone();
+        if let Err(e) = self.broadcast_message_to_topic(topic, update.encode()).await {
+            println!("Could not report {} on the pin topic: {}", update.cid(), e);
+        }
+    }
+
+    fn flush_outbox(&mut self) {
+        for pending in self.outbox.due() {
+            let request_id = self.swarm.behaviour_mut().dm.send_request(&pending.to, pending.envelope.clone());
+            self.outbox.sent(request_id, pending);
+        }
+    }
+
+    async fn handle_direct_message(&mut self, event: direct_message::Event) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            } => {
+                let receipt = match direct_message::open(&self.identity, &peer, &request) {
+                    Ok(body) => {
+                        if self.seen_messages.first_time(peer, &request.id) {
+                            self.monitoring.record_message_received(&peer, request.ciphertext.len() as u64).await;
+                            println!("Direct message from {}: {}", peer, body);
+                        }
+                        Receipt::Delivered { id: request.id }
+                    }
+                    Err(e) => {
+                        eprintln!("Rejected direct message {} from {}: {}", request.id, peer, e);
+                        Receipt::Rejected { id: request.id, reason: e.to_string() }
+                    }
+                };
+                if self.swarm.behaviour_mut().dm.send_response(channel, receipt).is_err() {
+                    eprintln!("Connection to {} closed before the receipt was sent", peer);
+                }
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+            } => {
+                let Some(pending) = self.outbox.answered(&request_id) else { return };
+                match response {
+                    Receipt::Delivered { id } => {
+                        self.monitoring.record_message_sent(&peer, pending.envelope.ciphertext.len() as u64).await;
+                        println!("Message {} delivered to {}", id, peer);
+                    }
+                    Receipt::Rejected { id, reason } => {
+                        println!("Message {} rejected by {}: {}", id, peer, reason);
+                    }
+                }
+            }
+            request_response::Event::OutboundFailure { peer, request_id, error } => {
+                let retryable = !matches!(error, request_response::OutboundFailure::UnsupportedProtocols);
+                match self.outbox.failed(&request_id, retryable) {
+                    Some(pending) => println!(
+                        "Giving up on message {} to {} after {} attempts: {}",
+                        pending.envelope.id, peer, pending.attempts, error
+                    ),
+                    None => println!("Could not reach {} ({}), will retry", peer, error),
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                eprintln!("Direct message from {} failed: {}", peer, error);
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    /// Hands gossipsub's current mesh, fanout and subscriber counts to monitoring
+    /// for every topic we have joined or recently published to.
+    async fn refresh_topic_peers(&mut self) {
+        let fanout_ttl = self.fanout_ttl;
+        self.fanout.retain(|_, published| published.elapsed() < fanout_ttl);
+
+        let gossipsub = &self.swarm.behaviour().gossipsub;
+        let mut subscribers: HashMap<&TopicHash, usize> = HashMap::new();
+        for (_, topics) in gossipsub.all_peers() {
+            for topic in topics {
+                *subscribers.entry(topic).or_default() += 1;
+            }
+        }
+
+        let joined = self.topics.values().map(|topic| topic.hash());
+        let mut peers = HashMap::new();
+        for topic in joined.chain(self.fanout.keys().cloned()) {
+            let subscribed = subscribers.get(&topic).copied().unwrap_or_default();
+            // Gossipsub keeps its fanout private. Without peer scoring it tops the
+            // fanout up to mesh_n of the topic's subscribers every heartbeat until
+            // fanout_ttl after our last publish, or until we join the topic
+            let fanout = match self.fanout.contains_key(&topic) && !self.topics.contains_key(topic.as_str()) {
+                true => subscribed.min(self.mesh_n),
+                false => 0,
+            };
+            peers.insert(
+                topic.to_string(),
+                TopicPeers {
+                    mesh: gossipsub.mesh_peers(&topic).count(),
+                    fanout,
+                    subscribers: subscribed,
+                },
+            );
+        }
+        self.monitoring.update_topic_peers(peers).await;
+    }
 
+    async fn start(&mut self) -> std::result::Result<(), Box<dyn StdError + Send + Sync + 'static>> {
         let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+        let mut housekeeping = tokio::time::interval(Duration::from_secs(1));
 
         loop {
             tokio::select! {
@@ -269,6 +1125,22 @@ impl P2pServer {
                         }
                     }
                 }
+                Some(update) = self.pin_updates.recv() => {
+                    self.handle_pin_update(update).await;
+                }
+                _ = housekeeping.tick() => {
+                    self.dial_bootnodes().await;
+                    self.refresh_topic_peers().await;
+                    self.flush_outbox();
+                    self.flush_key_outbox();
+                    self.group_keys.expire();
+                    if let (Some(rendezvous), Some(client)) = (&mut self.rendezvous, self.swarm.behaviour_mut().rendezvous.as_mut()) {
+                        rendezvous.tick(client);
+                    }
+                    if let Some(history) = &mut self.history {
+                        history.expire();
+                    }
+                }
                 event = self.swarm.select_next_some() => match event {
                     SwarmEvent::Behaviour(ServerBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
                         for (peer_id, _) in list {
@@ -285,22 +1157,96 @@ impl P2pServer {
                         message_id: id,
                         message,
                     })) => {
-                        self.monitoring.record_message_received(&peer_id, message.data.len() as u64).await;
-                        println!(
-                            "Got message: {} with id: {} from peer: {:?}",
-                            String::from_utf8_lossy(&message.data),
-                            id,
-                            peer_id
-                        );
+                        self.handle_gossip_message(peer_id, id, message).await;
+                    }
+                    SwarmEvent::Behaviour(ServerBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+                        // A peer on one of our topics may hold messages sent before we joined
+                        if self.topics.contains_key(topic.as_str()) {
+                            self.sync_history(peer_id, topic);
+                        }
+                        self.refresh_topic_peers().await;
+                    }
+                    SwarmEvent::Behaviour(ServerBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { .. })) => {
+                        self.refresh_topic_peers().await;
+                    }
+                    SwarmEvent::Behaviour(ServerBehaviourEvent::History(event)) => {
+                        self.handle_history_event(event);
+                    }
+                    SwarmEvent::Behaviour(ServerBehaviourEvent::Capability(event)) => {
+                        self.handle_capability_event(event);
+                    }
+                    SwarmEvent::Behaviour(ServerBehaviourEvent::GroupKey(event)) => {
+                        self.handle_group_key_event(event);
+                    }
+                    SwarmEvent::Behaviour(ServerBehaviourEvent::DirectMessage(event)) => {
+                        self.handle_direct_message(event).await;
+                    }
+                    SwarmEvent::Behaviour(ServerBehaviourEvent::FileTransfer(event)) => {
+                        let events = self.files.handle_event(&mut self.swarm.behaviour_mut().files, event).await;
+                        report_transfers(events);
+                    }
+                    SwarmEvent::Behaviour(ServerBehaviourEvent::Identify(identify::Event::Received { peer_id, info })) => {
+                        if info.protocols.contains(&bitswap::PROTOCOL) {
+                            self.bitswap.add_peer(peer_id);
+                        }
+                        if info.protocols.contains(&rendezvous::PROTOCOL) {
+                            if let (Some(rendezvous), Some(client)) = (&mut self.rendezvous, self.swarm.behaviour_mut().rendezvous.as_mut()) {
+                                rendezvous.add_point(client, peer_id);
+                            }
+                        }
+                    }
+                    SwarmEvent::Behaviour(ServerBehaviourEvent::Rendezvous(event)) => {
+                        self.handle_rendezvous_event(event);
+                    }
+                    SwarmEvent::Behaviour(ServerBehaviourEvent::RendezvousServer(event)) => match event {
+                        libp2p::rendezvous::server::Event::PeerRegistered { peer, registration } => {
+                            println!("{} registered under {} for {}s", peer, registration.namespace, registration.ttl);
+                        }
+                        libp2p::rendezvous::server::Event::PeerNotRegistered { peer, namespace, error } => {
+                            eprintln!("Refused registration of {} under {}: {:?}", peer, namespace, error);
+                        }
+                        _ => {}
+                    },
+                    SwarmEvent::OutgoingConnectionError { connection_id, error, .. }
+                        if self.bootstrap.as_mut().is_some_and(|bootstrap| bootstrap.failed(connection_id, &error)) =>
+                    {
+                        self.monitoring.record_bootstrap_dial_failure().await;
                     }
                     SwarmEvent::NewListenAddr { address, .. } => {
                         println!("Listening on {:?}", address);
+                        // Rendezvous registrations carry our external addresses, so peers can dial what we listen on
+                        if self.rendezvous.is_some() && rendezvous::is_dialable(&address) {
+                            self.swarm.add_external_address(address);
+                        }
                     }
-                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-                        self.monitoring.record_peer_connected(peer_id, "direct").await;
+                    SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, num_established, .. } => {
+                        if let Some(bootstrap) = &mut self.bootstrap {
+                            bootstrap.connected(connection_id, peer_id, &endpoint);
+                        }
+                        if num_established.get() == 1 {
+                            self.monitoring.record_peer_connected(peer_id, "direct").await;
+                            self.present_capabilities_to(peer_id);
+                            let envelopes = self.group_keys.pending_for(&self.identity, peer_id);
+                            self.key_outbox.wake(&peer_id);
+                            self.queue_keys(envelopes);
+                        }
+                        self.outbox.wake(&peer_id);
+                        self.flush_outbox();
                     }
-                    SwarmEvent::ConnectionClosed { peer_id, .. } => {
+                    // Only a peer's last connection closing counts as losing the peer
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
                         self.monitoring.record_peer_disconnected(&peer_id).await;
+                        self.bitswap.remove_peer(&peer_id);
+                        self.access.forget_peer(&peer_id);
+                        if let Some(rendezvous) = &mut self.rendezvous {
+                            rendezvous.remove_point(&peer_id);
+                        }
+                        if let Some(bootstrap) = &mut self.bootstrap {
+                            bootstrap.disconnected(&peer_id);
+                        }
+                        if let Some(history) = &mut self.history {
+                            history.forget_peer(&peer_id);
+                        }
                     }
                     _ => {}
                 }
@@ -313,15 +1259,20 @@ impl P2pServer {
     }
 }
 
+mod rate_limit;
+mod http;
 mod signaling;
+mod tls;
+mod turn_config;
+mod turn_server;
 mod web_server;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Run mode: 'all' for all servers, 'bootnode' for bootnode only, 'node' for regular node, 'signaling' for signaling+web servers
+    /// Run mode: 'all' for all servers, 'bootnode' for bootnode only, 'node' for regular node, 'signaling' for signaling+web servers, 'turn' for the STUN/TURN server
     #[arg(long, default_value = "node")]
     mode: String,
 
@@ -333,13 +1284,88 @@ struct Args {
     #[arg(long, default_value = "3000")]
     web_port: u16,
 
+    /// Serve the web client from this directory instead of the copy built into the binary
+    #[arg(long)]
+    web_root: Option<PathBuf>,
+
     /// Port for bootnode
     #[arg(long, default_value = "4002")]
     bootnode_port: u16,
 
-    /// Bootnode address to connect to (e.g., /ip4/127.0.0.1/tcp/4002)
+    /// Bootnode address to connect to (e.g., /ip4/127.0.0.1/tcp/4002, /dns4/boot.example.com/tcp/4002 or /dnsaddr/bootstrap.hippius.network for a list kept in DNS)
     #[arg(long)]
     bootnode_address: Option<String>,
+
+    /// Shared secret for HMAC signaling registration tokens (signed challenges are always accepted)
+    #[arg(long)]
+    signaling_secret: Option<String>,
+
+    /// Largest signaling message accepted, in bytes
+    #[arg(long, default_value = "65536")]
+    signaling_max_message_size: usize,
+
+    /// Signaling messages per second allowed per connection (bursts of twice this)
+    #[arg(long, default_value = "20", value_parser = signaling::parse_rate)]
+    signaling_rate_limit: f64,
+
+    /// Signaling messages per second allowed per client IP (bursts of twice this)
+    #[arg(long, default_value = "50", value_parser = signaling::parse_rate)]
+    signaling_ip_rate_limit: f64,
+
+    /// Open signaling connections allowed per client IP
+    #[arg(long, default_value = "16", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    signaling_ip_connections: usize,
+
+    /// Outgoing messages buffered per signaling connection
+    #[arg(long, default_value = "64", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    signaling_queue_size: usize,
+
+    /// What to do when a signaling connection's outgoing queue is full
+    #[arg(long, value_enum, default_value = "drop")]
+    signaling_overflow: signaling::OverflowPolicy,
+
+    /// Seconds between server pings on each signaling connection
+    #[arg(long, default_value = "20", value_parser = clap::value_parser!(u64).range(1..))]
+    signaling_ping_interval: u64,
+
+    /// Seconds without any traffic (including pongs) before a signaling connection is dropped
+    #[arg(long, default_value = "60")]
+    signaling_idle_timeout: u64,
+
+    /// ICE server and TURN monitoring config served to browsers at /config/ice
+    #[arg(long, default_value = "config/turn_config.json")]
+    turn_config: String,
+
+    #[command(flatten)]
+    turn: turn_server::TurnArgs,
+
+    /// Address of the metrics listener (/metrics and /stats), unless --http-port and --admin-token are set
+    #[arg(long, default_value = "127.0.0.1:9091")]
+    metrics_addr: SocketAddr,
+
+    #[command(flatten)]
+    tls: tls::TlsArgs,
+
+    #[command(flatten)]
+    http: http::HttpArgs,
+
+    #[command(flatten)]
+    kubo: kubo::KuboArgs,
+
+    #[command(flatten)]
+    history: history::HistoryArgs,
+
+    #[command(flatten)]
+    group_key: group_key::GroupKeyArgs,
+
+    #[command(flatten)]
+    rendezvous: rendezvous::RendezvousArgs,
+
+    #[command(flatten)]
+    dns: dnsaddr::DnsArgs,
+
+    #[command(flatten)]
+    bootstrap: bootstrap::BootstrapArgs,
 }
 
 #[tokio::main]
@@ -349,61 +1375,169 @@ async fn main() -> std::result::Result<(), Box<dyn StdError + Send + Sync + 'sta
         .init();
 
     let args = Args::parse();
-    
+    if args.signaling_ping_interval >= args.signaling_idle_timeout {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                "--signaling-ping-interval must be shorter than --signaling-idle-timeout",
+            )
+            .exit();
+    }
+    let signaling_auth = signaling::SignalingAuth::new(args.signaling_secret.clone());
+    let signaling_limits = signaling::SignalingLimits {
+        max_message_size: args.signaling_max_message_size,
+        messages_per_sec: args.signaling_rate_limit,
+        burst: args.signaling_rate_limit * 2.0,
+        ip_messages_per_sec: args.signaling_ip_rate_limit,
+        ip_burst: args.signaling_ip_rate_limit * 2.0,
+        ip_connections: args.signaling_ip_connections,
+        outgoing_queue: args.signaling_queue_size,
+        overflow_policy: args.signaling_overflow,
+        ping_interval: Duration::from_secs(args.signaling_ping_interval),
+        idle_timeout: Duration::from_secs(args.signaling_idle_timeout),
+    };
+    let serves_web = matches!(args.mode.as_str(), "all" | "signaling" | "node");
+    // Modes with a web server hand this config to browsers, so refuse to start with a broken one
+    let turn_config = if serves_web {
+        let config = turn_config::TurnConfig::load(&args.turn_config, args.turn.turn_secret.is_some()).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        println!("Loaded ICE configuration from {}", args.turn_config);
+        Some(config)
+    } else {
+        None
+    };
+    // TURN servers from the config are handed out over signaling only, unless
+    // --turn-url names others
+    let config_turn = turn_config.as_ref().and_then(|config| config.turn.clone());
+    let ice_provider = signaling::IceProvider {
+        stun_urls: args.turn.stun_urls.clone(),
+        turn_urls: match &config_turn {
+            Some(turn) if args.turn.turn_urls.is_empty() => turn.urls.clone(),
+            _ => args.turn.advertised_turn_urls(),
+        },
+        turn_secret: args.turn.turn_secret.clone(),
+        static_credentials: config_turn.and_then(|turn| Some((turn.username?, turn.credential?))),
+        credential_ttl: Duration::from_secs(args.turn.turn_credential_ttl),
+    };
+    if let Some(root) = args.web_root.as_ref().filter(|root| !root.join("index.html").is_file()) {
+        eprintln!("--web-root {} has no index.html; point it at a copy of the web/ directory", root.display());
+        std::process::exit(1);
+    }
+    let mut client_config = turn_config.as_ref().map(|config| config.client_config()).unwrap_or_default();
+    // On a shared listener, signaling is reachable on the page's own origin
+    client_config.signaling_port = args.http.http_port.is_none().then_some(args.signaling_port);
+    let tls = tls::server_config(&args.tls)?;
+    let monitoring = Arc::new(Monitoring::new());
+    if let Some(log_file) = turn_config.as_ref().and_then(|config| config.monitoring.log_file()) {
+        monitoring
+            .open_webrtc_log(Path::new(log_file))
+            .await
+            .map_err(|e| format!("cannot open WebRTC stats log {}: {}", log_file, e))?;
+        println!("Logging WebRTC stats reports to {}", log_file);
+    }
+
+    // Every mode has metrics to report; modes with a web client also serve it and /signal
+    let metrics_routes = http::require_admin(metrics_server::router(monitoring.clone()), args.http.admin_token.clone());
+    let client_routes = serves_web.then(|| {
+        (
+            web_server::router(args.web_root.clone(), client_config),
+            signaling::router(signaling_auth, ice_provider, signaling_limits, monitoring.clone()),
+        )
+    });
+    let listeners = match args.http.http_port {
+        Some(port) => {
+            let mut listeners = Vec::new();
+            // The shared port is public, so metrics only join it behind the admin token
+            let mut router = if args.http.admin_token.is_some() {
+                metrics_routes
+            } else {
+                println!("No --admin-token: /metrics and /stats stay on {}", args.metrics_addr);
+                listeners.push(http::Listener {
+                    name: "Metrics server",
+                    addr: args.metrics_addr,
+                    router: metrics_routes,
+                });
+                axum::Router::new()
+            };
+            if let Some((web, signaling)) = client_routes {
+                router = router.merge(signaling).merge(web);
+            }
+            if args.http.admin_token.is_some() || serves_web {
+                listeners.push(http::Listener {
+                    name: "HTTP server",
+                    addr: SocketAddr::from(([0, 0, 0, 0], port)),
+                    router,
+                });
+            }
+            listeners
+        }
+        None => {
+            let mut listeners = vec![http::Listener {
+                name: "Metrics server",
+                addr: args.metrics_addr,
+                router: metrics_routes,
+            }];
+            if let Some((web, signaling)) = client_routes {
+                listeners.push(http::Listener {
+                    name: "Web server",
+                    addr: SocketAddr::from(([0, 0, 0, 0], args.web_port)),
+                    router: web,
+                });
+                listeners.push(http::Listener {
+                    name: "Signaling server",
+                    addr: SocketAddr::from(([0, 0, 0, 0], args.signaling_port)),
+                    router: signaling,
+                });
+            }
+            listeners
+        }
+    };
+    let http_servers = http::serve_all(listeners, &args.http, tls);
+
     match args.mode.as_str() {
         "all" => {
             println!("Starting all servers...");
-            println!("Web server: http://localhost:{}", args.web_port);
-            println!("Signaling server: ws://localhost:{}", args.signaling_port);
-            println!("Metrics server: http://localhost:9091");
-            
+
             // Start web server, signaling server, and bootnode
-            let peer_map = Arc::new(RwLock::new(HashMap::new()));
-            let mut bootnode = P2pServer::new(peer_map.clone(), true, &args).await?;
+            let mut bootnode = P2pServer::new(true, &args, monitoring.clone()).await?;
             println!("Bootnode: /ip4/127.0.0.1/tcp/{}", args.bootnode_port);
             println!("Bootnode PeerID: {}", bootnode.peer_id());
             
-            tokio::join!(
-                web_server::start_web_server(args.web_port),
-                signaling::start_signaling_server(args.signaling_port),
+            println!("STUN/TURN server: udp/{}", args.turn.turn_port);
+
+            tokio::try_join!(
+                http_servers,
+                turn_server::start_turn_server(args.turn.clone(), monitoring.clone()),
                 bootnode.start()
-            );
+            )?;
         }
         "signaling" => {
             println!("Starting signaling and web servers...");
-            println!("Web server: http://localhost:{}", args.web_port);
-            println!("Signaling server: ws://localhost:{}", args.signaling_port);
-            
-            tokio::join!(
-                web_server::start_web_server(args.web_port),
-                signaling::start_signaling_server(args.signaling_port)
-            );
+            http_servers.await?;
         }
         "bootnode" => {
             println!("Starting bootnode...");
-            let peer_map = Arc::new(RwLock::new(HashMap::new()));
-            let mut server = P2pServer::new(peer_map.clone(), true, &args).await?;
+            let mut server = P2pServer::new(true, &args, monitoring.clone()).await?;
             println!("Bootnode: /ip4/127.0.0.1/tcp/{}", args.bootnode_port);
             println!("Bootnode PeerID: {}", server.peer_id());
-            server.start().await?;
+            tokio::try_join!(http_servers, server.start())?;
         }
         "node" => {
             println!("Starting regular node with signaling and web servers...");
-            println!("Web server: http://localhost:{}", args.web_port);
-            println!("Signaling server: ws://localhost:{}", args.signaling_port);
             
-            let peer_map = Arc::new(RwLock::new(HashMap::new()));
-            let mut server = P2pServer::new(peer_map.clone(), false, &args).await?;
+            let mut server = P2pServer::new(false, &args, monitoring.clone()).await?;
             println!("Node PeerID: {}", server.peer_id());
             
-            tokio::join!(
-                web_server::start_web_server(args.web_port),
-                signaling::start_signaling_server(args.signaling_port),
-                server.start()
-            );
+            tokio::try_join!(http_servers, server.start())?;
+        }
+        "turn" => {
+            println!("Starting STUN/TURN server...");
+            tokio::try_join!(http_servers, turn_server::start_turn_server(args.turn.clone(), monitoring.clone()))?;
         }
         _ => {
-            println!("Invalid mode. Available modes: all, signaling, bootnode, node");
+            println!("Invalid mode. Available modes: all, signaling, bootnode, node, turn");
             std::process::exit(1);
         }
     }
@@ -2,25 +2,29 @@ use axum::{
     routing::get,
     Router,
     response::Json,
-    serve,
 };
-use std::net::SocketAddr;
-use serde_json::{json, Value};
+use serde_json::json;
 use crate::monitoring::Monitoring;
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use std::error::Error;
 
-pub async fn start_metrics_server(monitoring: Arc<Monitoring>) -> Result<(), Box<dyn Error + Send + Sync>> {
+/// `/metrics` for Prometheus, `/stats` as JSON and `/stats/topics` for
+/// per-topic gossipsub figures.
+pub fn router(monitoring: Arc<Monitoring>) -> Router {
     let handle = monitoring.get_prometheus_handle();
     
-    // Create router
-    let app = Router::new()
+    Router::new()
         .route("/metrics", get(move || async move { 
             handle.render()
         }))
+        .route("/stats/topics", get({
+            let monitoring = monitoring.clone();
+            move || async move { Json(monitoring.get_topic_stats().await) }
+        }))
         .route("/stats", get(move || async move {
             let (network, system, websocket) = monitoring.get_all_stats().await;
+            let turn = monitoring.get_turn_stats().await;
+            let webrtc = monitoring.get_webrtc_stats().await;
+            let bootstrap = monitoring.get_bootstrap_stats().await;
             
             Json(json!({
                 "network": {
@@ -42,16 +46,33 @@ pub async fn start_metrics_server(monitoring: Arc<Monitoring>) -> Result<(), Box
                     "active_connections": websocket.active_connections,
                     "total_connections": websocket.total_connections,
                     "messages_sent": websocket.messages_sent,
-                    "messages_received": websocket.messages_received
-                }
+                    "messages_received": websocket.messages_received,
+                    "bytes_sent": websocket.bytes_sent,
+                    "bytes_received": websocket.bytes_received,
+                    "messages_by_type": websocket.messages_by_type,
+                    "registrations": websocket.registrations,
+                    "forward_failures": websocket.forward_failures,
+                    "messages_rejected": websocket.messages_rejected,
+                    "rejections": websocket.rejections,
+                    "completed_sessions": websocket.completed_sessions,
+                    "total_session_secs": websocket.total_session_secs
+                },
+                "turn": {
+                    "active_allocations": turn.active_allocations,
+                    "total_allocations": turn.total_allocations,
+                    "rejected_allocations": turn.rejected_allocations,
+                    "bytes_to_peers": turn.bytes_to_peers,
+                    "bytes_from_peers": turn.bytes_from_peers,
+                    "dropped_packets": turn.dropped_packets,
+                    "allocations": turn.allocations
+                },
+                "webrtc": {
+                    "reports_received": webrtc.reports_received,
+                    "bytes_relayed": webrtc.bytes_relayed,
+                    "connections_by_type": webrtc.connections_by_type,
+                    "peers": webrtc.peers
+                },
+                "bootstrap": bootstrap
             }))
-        }));
-
-    // Start server
-    let addr = SocketAddr::from(([127, 0, 0, 1], 9091));
-    println!("Metrics server listening on http://127.0.0.1:9091");
-    let listener = TcpListener::bind(addr).await?;
-    serve(listener, app.into_make_service()).await?;
-
-    Ok(())
+        }))
 }
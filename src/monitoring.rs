@@ -1,12 +1,19 @@
 use std::{
     collections::HashMap,
+    io,
+    path::Path,
     sync::Arc,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
-use tokio::sync::RwLock;
-use serde::Serialize;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{Mutex, RwLock},
+};
+use serde::{Deserialize, Serialize};
 use metrics::{counter, gauge, histogram};
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use once_cell::sync::OnceCell;
 use libp2p::PeerId;
 use sysinfo::{System, SystemExt, CpuExt, DiskExt};
 
@@ -47,6 +54,147 @@ pub struct WebSocketStats {
     pub total_connections: u64,
     pub messages_sent: u64,
     pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_by_type: HashMap<String, u64>,
+    pub registrations: u64,
+    pub forward_failures: u64,
+    pub messages_rejected: u64,
+    pub rejections: HashMap<String, u64>,
+    pub completed_sessions: u64,
+    pub total_session_secs: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TurnStats {
+    pub active_allocations: usize,
+    pub total_allocations: u64,
+    pub rejected_allocations: u64,
+    pub bytes_to_peers: u64,
+    pub bytes_from_peers: u64,
+    pub dropped_packets: u64,
+    pub allocations: HashMap<String, AllocationStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AllocationStats {
+    pub relay_address: String,
+    pub created_at: u64,  // Unix timestamp
+    pub bytes_to_peers: u64,
+    pub bytes_from_peers: u64,
+    pub dropped_packets: u64,
+}
+
+/// Gossipsub traffic and peer counts for one topic.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TopicStats {
+    pub messages_published: u64,
+    pub messages_received: u64,
+    /// Received messages that passed validation and were handed back to
+    /// gossipsub to propagate
+    pub messages_accepted: u64,
+    /// Accepted messages gossipsub sent on to at least one mesh peer
+    pub messages_forwarded: u64,
+    pub messages_rejected: u64,
+    pub rejections: HashMap<String, u64>,
+    pub bytes_published: u64,
+    pub bytes_received: u64,
+    pub mesh_peers: usize,
+    /// Peers our messages go to on a topic we publish to without joining
+    pub fanout_peers: usize,
+    /// Connected peers that announced a subscription to the topic
+    pub subscribers: usize,
+}
+
+/// Current peer counts for a topic, as queried from gossipsub.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TopicPeers {
+    pub mesh: usize,
+    pub fanout: usize,
+    pub subscribers: usize,
+}
+
+/// How well a regular node is connected, as kept by its bootstrap manager.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BootstrapStats {
+    /// `isolated`, `degraded` (fewer peers than wanted) or `connected`
+    pub state: String,
+    pub connected_peers: usize,
+    pub min_peers: usize,
+    pub bootnodes: Vec<BootnodeStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BootnodeStatus {
+    pub address: String,
+    pub connected: bool,
+    /// Failed dials since the last success
+    pub failures: u32,
+    /// Seconds until the next dial, while one is scheduled
+    pub retry_in_secs: Option<u64>,
+}
+
+/// A browser's `getStats()` summary for one of its WebRTC connections.
+/// Byte and message counts are cumulative for the connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebRtcReport {
+    /// The remote end of the connection
+    pub peer: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    #[serde(default)]
+    pub messages_sent: u64,
+    #[serde(default)]
+    pub messages_received: u64,
+    #[serde(default)]
+    pub rtt_ms: Option<f64>,
+    /// ICE candidate types of the selected pair: host, srflx, prflx or relay
+    #[serde(default)]
+    pub local_candidate_type: Option<String>,
+    #[serde(default)]
+    pub remote_candidate_type: Option<String>,
+}
+
+impl WebRtcReport {
+    /// "turn" if either side relays, "stun" if either side is behind NAT,
+    /// otherwise "direct".
+    pub fn connection_type(&self) -> &'static str {
+        let types = [self.local_candidate_type.as_deref(), self.remote_candidate_type.as_deref()];
+        if types.contains(&Some("relay")) {
+            "turn"
+        } else if types.contains(&Some("srflx")) || types.contains(&Some("prflx")) {
+            "stun"
+        } else {
+            "direct"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebRtcStats {
+    pub reports_received: u64,
+    /// Bytes over TURN-relayed connections, in both directions, as each reporter saw them
+    pub bytes_relayed: u64,
+    pub connections_by_type: HashMap<String, usize>,
+    /// Per reporting browser peer, summed over its connections
+    pub peers: HashMap<String, PeerStats>,
+    /// Latest report per (reporter, remote) pair
+    #[serde(skip)]
+    connections: HashMap<(String, String), (WebRtcReport, Instant)>,
+}
+
+/// The process-wide Prometheus recorder. The `metrics` macros write to a
+/// single global recorder, so it is installed on first use and shared by every
+/// `Monitoring`; the exporter itself is served by the metrics router.
+fn prometheus_handle() -> PrometheusHandle {
+    static HANDLE: OnceCell<PrometheusHandle> = OnceCell::new();
+    HANDLE
+        .get_or_init(|| {
+            PrometheusBuilder::new()
+                .install_recorder()
+                .expect("failed to install Prometheus recorder")
+        })
+        .clone()
 }
 
 pub struct Monitoring {
@@ -54,17 +202,17 @@ pub struct Monitoring {
     network_stats: Arc<RwLock<NetworkStats>>,
     system_stats: Arc<RwLock<SystemStats>>,
     websocket_stats: Arc<RwLock<WebSocketStats>>,
+    turn_stats: Arc<RwLock<TurnStats>>,
+    webrtc_stats: Arc<RwLock<WebRtcStats>>,
+    topic_stats: RwLock<HashMap<String, TopicStats>>,
+    bootstrap_stats: RwLock<Option<BootstrapStats>>,
+    webrtc_log: Mutex<Option<File>>,
     prometheus_handle: Arc<PrometheusHandle>,
 }
 
 impl Monitoring {
     pub fn new() -> Self {
-        // Initialize Prometheus metrics exporter
-        let builder = PrometheusBuilder::new();
-        let handle = builder
-            .with_http_listener(([127, 0, 0, 1], 9091))
-            .install_recorder()
-            .expect("failed to install Prometheus recorder");
+        let handle = prometheus_handle();
 
         let monitoring = Self {
             start_time: SystemTime::now(),
@@ -88,7 +236,35 @@ impl Monitoring {
                 total_connections: 0,
                 messages_sent: 0,
                 messages_received: 0,
+                bytes_sent: 0,
+                bytes_received: 0,
+                messages_by_type: HashMap::new(),
+                registrations: 0,
+                forward_failures: 0,
+                messages_rejected: 0,
+                rejections: HashMap::new(),
+                completed_sessions: 0,
+                total_session_secs: 0.0,
+            })),
+            turn_stats: Arc::new(RwLock::new(TurnStats {
+                active_allocations: 0,
+                total_allocations: 0,
+                rejected_allocations: 0,
+                bytes_to_peers: 0,
+                bytes_from_peers: 0,
+                dropped_packets: 0,
+                allocations: HashMap::new(),
             })),
+            webrtc_stats: Arc::new(RwLock::new(WebRtcStats {
+                reports_received: 0,
+                bytes_relayed: 0,
+                connections_by_type: HashMap::new(),
+                peers: HashMap::new(),
+                connections: HashMap::new(),
+            })),
+            topic_stats: RwLock::new(HashMap::new()),
+            bootstrap_stats: RwLock::new(None),
+            webrtc_log: Mutex::new(None),
             prometheus_handle: Arc::new(handle),
         };
 
@@ -102,40 +278,22 @@ impl Monitoring {
     }
 
     fn start_background_tasks(&self) {
-        let network_stats = self.network_stats.clone();
         let system_stats = self.system_stats.clone();
-        let websocket_stats = self.websocket_stats.clone();
         let start_time = self.start_time;
 
-        // Update metrics every second
+        // Refresh time-derived gauges every second. Counters are only ever
+        // incremented where the event happens, never from these snapshots.
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(1));
             loop {
                 interval.tick().await;
 
-                // Update network metrics
-                let network = network_stats.read().await;
-                gauge!("p2p_connected_peers", network.connected_peers as f64);
-                counter!("p2p_messages_sent", network.messages_sent);
-                counter!("p2p_messages_received", network.messages_received);
-                counter!("p2p_bytes_sent", network.bytes_sent);
-                counter!("p2p_bytes_received", network.bytes_received);
-
-                // Update system metrics
                 let system = system_stats.read().await;
                 gauge!("system_cpu_usage", system.cpu_usage as f64);
                 gauge!("system_memory_usage", system.memory_usage);
                 gauge!("system_disk_usage", system.disk_usage);
                 gauge!("system_thread_count", system.thread_count as f64);
 
-                // Update WebSocket metrics
-                let ws = websocket_stats.read().await;
-                gauge!("ws_active_connections", ws.active_connections as f64);
-                counter!("ws_total_connections", ws.total_connections);
-                counter!("ws_messages_sent", ws.messages_sent);
-                counter!("ws_messages_received", ws.messages_received);
-
-                // Update uptime
                 if let Ok(duration) = start_time.elapsed() {
                     gauge!("uptime_seconds", duration.as_secs() as f64);
                 }
@@ -168,7 +326,6 @@ impl Monitoring {
 
     pub async fn record_peer_connected(&self, peer_id: PeerId, connection_type: &str) {
         let mut stats = self.network_stats.write().await;
-        stats.connected_peers += 1;
         stats.peer_connections.insert(
             peer_id.to_string(),
             PeerStats {
@@ -185,13 +342,14 @@ impl Monitoring {
                 latency_ms: 0.0,
             },
         );
+        stats.connected_peers = stats.peer_connections.len();
         gauge!("p2p_connected_peers", stats.connected_peers as f64);
     }
 
     pub async fn record_peer_disconnected(&self, peer_id: &PeerId) {
         let mut stats = self.network_stats.write().await;
-        stats.connected_peers -= 1;
         stats.peer_connections.remove(&peer_id.to_string());
+        stats.connected_peers = stats.peer_connections.len();
         gauge!("p2p_connected_peers", stats.connected_peers as f64);
     }
 
@@ -219,6 +377,86 @@ impl Monitoring {
         counter!("p2p_bytes_received", bytes);
     }
 
+    pub async fn record_topic_published(&self, topic: &str, bytes: u64) {
+        let mut stats = self.topic_stats.write().await;
+        let entry = stats.entry(topic.to_string()).or_default();
+        entry.messages_published += 1;
+        entry.bytes_published += bytes;
+        counter!("p2p_topic_messages_published", 1, "topic" => topic.to_string());
+        counter!("p2p_topic_bytes_published", bytes, "topic" => topic.to_string());
+    }
+
+    pub async fn record_topic_received(&self, topic: &str, bytes: u64) {
+        let mut stats = self.topic_stats.write().await;
+        let entry = stats.entry(topic.to_string()).or_default();
+        entry.messages_received += 1;
+        entry.bytes_received += bytes;
+        counter!("p2p_topic_messages_received", 1, "topic" => topic.to_string());
+        counter!("p2p_topic_bytes_received", bytes, "topic" => topic.to_string());
+    }
+
+    pub async fn record_topic_accepted(&self, topic: &str) {
+        let mut stats = self.topic_stats.write().await;
+        stats.entry(topic.to_string()).or_default().messages_accepted += 1;
+        counter!("p2p_topic_messages_accepted", 1, "topic" => topic.to_string());
+    }
+
+    pub async fn record_topic_forwarded(&self, topic: &str) {
+        let mut stats = self.topic_stats.write().await;
+        stats.entry(topic.to_string()).or_default().messages_forwarded += 1;
+        counter!("p2p_topic_messages_forwarded", 1, "topic" => topic.to_string());
+    }
+
+    pub async fn record_topic_rejected(&self, topic: &str, reason: &str) {
+        let mut stats = self.topic_stats.write().await;
+        let entry = stats.entry(topic.to_string()).or_default();
+        entry.messages_rejected += 1;
+        *entry.rejections.entry(reason.to_string()).or_insert(0) += 1;
+        counter!("p2p_topic_messages_rejected", 1, "topic" => topic.to_string(), "reason" => reason.to_string());
+    }
+
+    /// Replaces the mesh, fanout and subscriber gauges. Topics missing from
+    /// `peers` keep their counters but drop to zero peers.
+    pub async fn update_topic_peers(&self, peers: HashMap<String, TopicPeers>) {
+        let mut stats = self.topic_stats.write().await;
+        for topic in peers.keys() {
+            stats.entry(topic.clone()).or_default();
+        }
+        for (topic, entry) in stats.iter_mut() {
+            let current = peers.get(topic).copied().unwrap_or_default();
+            entry.mesh_peers = current.mesh;
+            entry.fanout_peers = current.fanout;
+            entry.subscribers = current.subscribers;
+            gauge!("p2p_topic_mesh_peers", current.mesh as f64, "topic" => topic.clone());
+            gauge!("p2p_topic_fanout_peers", current.fanout as f64, "topic" => topic.clone());
+            gauge!("p2p_topic_subscribers", current.subscribers as f64, "topic" => topic.clone());
+        }
+    }
+
+    pub async fn get_topic_stats(&self) -> HashMap<String, TopicStats> {
+        self.topic_stats.read().await.clone()
+    }
+
+    pub async fn update_bootstrap(&self, bootstrap: BootstrapStats) {
+        let state = match bootstrap.state.as_str() {
+            "isolated" => 0.0,
+            "degraded" => 1.0,
+            _ => 2.0,
+        };
+        gauge!("p2p_bootstrap_state", state);
+        gauge!("p2p_bootstrap_connected_bootnodes", bootstrap.bootnodes.iter().filter(|bootnode| bootnode.connected).count() as f64);
+        *self.bootstrap_stats.write().await = Some(bootstrap);
+    }
+
+    pub async fn record_bootstrap_dial_failure(&self) {
+        counter!("p2p_bootstrap_dial_failures", 1);
+    }
+
+    /// Regular nodes only; bootnodes do not bootstrap.
+    pub async fn get_bootstrap_stats(&self) -> Option<BootstrapStats> {
+        self.bootstrap_stats.read().await.clone()
+    }
+
     pub async fn record_websocket_connected(&self) {
         let mut stats = self.websocket_stats.write().await;
         stats.active_connections += 1;
@@ -227,27 +465,321 @@ impl Monitoring {
         counter!("ws_total_connections", 1);
     }
 
-    pub async fn record_websocket_disconnected(&self) {
+    pub async fn record_websocket_disconnected(&self, session_duration: Duration) {
         let mut stats = self.websocket_stats.write().await;
-        stats.active_connections -= 1;
+        stats.active_connections = stats.active_connections.saturating_sub(1);
+        stats.completed_sessions += 1;
+        stats.total_session_secs += session_duration.as_secs_f64();
         gauge!("ws_active_connections", stats.active_connections as f64);
+        histogram!("ws_session_duration_seconds", session_duration.as_secs_f64());
     }
 
-    pub async fn record_websocket_message(&self, is_outgoing: bool, _bytes: u64) {
+    pub async fn record_websocket_registration(&self) {
         let mut stats = self.websocket_stats.write().await;
+        stats.registrations += 1;
+        counter!("ws_registrations", 1);
+    }
+
+    pub async fn record_websocket_message(&self, is_outgoing: bool, message_type: &str, bytes: u64) {
+        let mut stats = self.websocket_stats.write().await;
+        *stats.messages_by_type.entry(message_type.to_string()).or_insert(0) += 1;
         if is_outgoing {
             stats.messages_sent += 1;
-            counter!("ws_messages_sent", 1);
+            stats.bytes_sent += bytes;
+            counter!("ws_messages_sent", 1, "type" => message_type.to_string());
+            counter!("ws_bytes_sent", bytes);
         } else {
             stats.messages_received += 1;
-            counter!("ws_messages_received", 1);
+            stats.bytes_received += bytes;
+            counter!("ws_messages_received", 1, "type" => message_type.to_string());
+            counter!("ws_bytes_received", bytes);
         }
     }
 
+    /// A signaling message could not be delivered to its target peer.
+    pub async fn record_websocket_forward_failure(&self, reason: &str) {
+        let mut stats = self.websocket_stats.write().await;
+        stats.forward_failures += 1;
+        counter!("ws_forward_failures", 1, "reason" => reason.to_string());
+    }
+
+    pub async fn record_websocket_rejection(&self, reason: &str) {
+        let mut stats = self.websocket_stats.write().await;
+        stats.messages_rejected += 1;
+        *stats.rejections.entry(reason.to_string()).or_insert(0) += 1;
+        counter!("ws_messages_rejected", 1, "reason" => reason.to_string());
+    }
+
+    pub async fn record_turn_allocation_created(&self, relay_address: &str) {
+        let mut stats = self.turn_stats.write().await;
+        stats.active_allocations += 1;
+        stats.total_allocations += 1;
+        stats.allocations.insert(
+            relay_address.to_string(),
+            AllocationStats {
+                relay_address: relay_address.to_string(),
+                created_at: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                bytes_to_peers: 0,
+                bytes_from_peers: 0,
+                dropped_packets: 0,
+            },
+        );
+        gauge!("turn_active_allocations", stats.active_allocations as f64);
+        counter!("turn_allocations_total", 1);
+    }
+
+    pub async fn record_turn_allocation_closed(&self, relay_address: &str) {
+        let mut stats = self.turn_stats.write().await;
+        stats.active_allocations -= 1;
+        stats.allocations.remove(relay_address);
+        gauge!("turn_active_allocations", stats.active_allocations as f64);
+    }
+
+    pub async fn record_turn_allocation_rejected(&self) {
+        let mut stats = self.turn_stats.write().await;
+        stats.rejected_allocations += 1;
+        counter!("turn_allocations_rejected", 1);
+    }
+
+    /// Bytes relayed through an allocation, in either direction.
+    pub async fn record_turn_relayed(&self, relay_address: &str, to_peer: bool, bytes: u64) {
+        let mut stats = self.turn_stats.write().await;
+        let direction = if to_peer {
+            stats.bytes_to_peers += bytes;
+            "to_peer"
+        } else {
+            stats.bytes_from_peers += bytes;
+            "from_peer"
+        };
+        if let Some(allocation) = stats.allocations.get_mut(relay_address) {
+            if to_peer {
+                allocation.bytes_to_peers += bytes;
+            } else {
+                allocation.bytes_from_peers += bytes;
+            }
+        }
+        counter!("turn_relayed_bytes", bytes, "direction" => direction);
+    }
+
+    /// A relayed packet was dropped because its allocation exceeded a quota.
+    pub async fn record_turn_dropped(&self, relay_address: &str, reason: &str) {
+        let mut stats = self.turn_stats.write().await;
+        stats.dropped_packets += 1;
+        if let Some(allocation) = stats.allocations.get_mut(relay_address) {
+            allocation.dropped_packets += 1;
+        }
+        counter!("turn_dropped_packets", 1, "reason" => reason.to_string());
+    }
+
+    pub async fn get_turn_stats(&self) -> TurnStats {
+        self.turn_stats.read().await.clone()
+    }
+
+    /// Appends every WebRTC report, as a JSON line, to `path`.
+    pub async fn open_webrtc_log(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).await?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path).await?;
+        *self.webrtc_log.lock().await = Some(file);
+        Ok(())
+    }
+
+    pub async fn record_webrtc_report(&self, reporter: &str, report: WebRtcReport) {
+        let now = Instant::now();
+        let connection_type = report.connection_type();
+        let mut stats = self.webrtc_stats.write().await;
+        let previous = stats
+            .connections
+            .insert((reporter.to_string(), report.peer.clone()), (report.clone(), now));
+
+        // Counts are cumulative; a smaller value means the connection was re-established
+        let (prev_sent, prev_received, elapsed) = match &previous {
+            Some((prev, at)) if prev.bytes_sent <= report.bytes_sent && prev.bytes_received <= report.bytes_received => {
+                (prev.bytes_sent, prev.bytes_received, Some(now.duration_since(*at)))
+            }
+            _ => (0, 0, None),
+        };
+        let sent = report.bytes_sent - prev_sent;
+        let received = report.bytes_received - prev_received;
+
+        stats.reports_received += 1;
+        if connection_type == "turn" {
+            stats.bytes_relayed += sent + received;
+        }
+        counter!("webrtc_reports_received", 1);
+        counter!("webrtc_bytes_sent", sent, "connection_type" => connection_type);
+        counter!("webrtc_bytes_received", received, "connection_type" => connection_type);
+        if let Some(rtt_ms) = report.rtt_ms {
+            histogram!("webrtc_rtt_seconds", rtt_ms / 1000.0);
+        }
+
+        Self::refresh_webrtc_peer(&mut stats, reporter);
+        Self::refresh_webrtc_connection_types(&mut stats);
+        drop(stats);
+
+        if let Some(file) = self.webrtc_log.lock().await.as_mut() {
+            let per_sec = |bytes: u64| elapsed.filter(|e| !e.is_zero()).map(|e| bytes as f64 / e.as_secs_f64());
+            let entry = serde_json::json!({
+                "timestamp": SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64,
+                "reporter": reporter,
+                "connection_type": connection_type,
+                "sent_bytes_per_sec": per_sec(sent),
+                "received_bytes_per_sec": per_sec(received),
+                "report": report,
+            });
+            if let Err(e) = file.write_all(format!("{}\n", entry).as_bytes()).await {
+                eprintln!("Failed to write WebRTC stats log: {}", e);
+            }
+        }
+    }
+
+    /// Drops a browser peer's connections once it leaves signaling.
+    pub async fn forget_webrtc_peer(&self, reporter: &str) {
+        let mut stats = self.webrtc_stats.write().await;
+        stats.connections.retain(|(from, _), _| from != reporter);
+        stats.peers.remove(reporter);
+        Self::refresh_webrtc_connection_types(&mut stats);
+    }
+
+    fn refresh_webrtc_peer(stats: &mut WebRtcStats, reporter: &str) {
+        let reports: Vec<&WebRtcReport> = stats
+            .connections
+            .iter()
+            .filter(|((from, _), _)| from == reporter)
+            .map(|(_, (report, _))| report)
+            .collect();
+        let rtts: Vec<f64> = reports.iter().filter_map(|report| report.rtt_ms).collect();
+        // Report the most constrained path the peer depends on
+        let connection_type = ["turn", "stun", "direct"]
+            .into_iter()
+            .find(|kind| reports.iter().any(|report| report.connection_type() == *kind))
+            .unwrap_or("direct");
+
+        let connected_since = stats.peers.get(reporter).map(|peer| peer.connected_since).unwrap_or_else(|| {
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+        });
+        let peer = PeerStats {
+            peer_id: reporter.to_string(),
+            connected_since,
+            messages_sent: reports.iter().map(|report| report.messages_sent).sum(),
+            messages_received: reports.iter().map(|report| report.messages_received).sum(),
+            bytes_sent: reports.iter().map(|report| report.bytes_sent).sum(),
+            bytes_received: reports.iter().map(|report| report.bytes_received).sum(),
+            connection_type: connection_type.to_string(),
+            latency_ms: if rtts.is_empty() { 0.0 } else { rtts.iter().sum::<f64>() / rtts.len() as f64 },
+        };
+        stats.peers.insert(reporter.to_string(), peer);
+    }
+
+    fn refresh_webrtc_connection_types(stats: &mut WebRtcStats) {
+        let mut by_type = HashMap::from([("direct".to_string(), 0), ("stun".to_string(), 0), ("turn".to_string(), 0)]);
+        for (report, _) in stats.connections.values() {
+            *by_type.entry(report.connection_type().to_string()).or_insert(0) += 1;
+        }
+        for (kind, count) in &by_type {
+            gauge!("webrtc_connections", *count as f64, "connection_type" => kind.clone());
+        }
+        stats.connections_by_type = by_type;
+    }
+
+    pub async fn get_webrtc_stats(&self) -> WebRtcStats {
+        self.webrtc_stats.read().await.clone()
+    }
+
     pub async fn get_all_stats(&self) -> (NetworkStats, SystemStats, WebSocketStats) {
-        let network = self.network_stats.read().await.clone();
+        let mut network = self.network_stats.read().await.clone();
+        network.uptime_secs = self.start_time.elapsed().map(|uptime| uptime.as_secs()).unwrap_or_default();
         let system = self.system_stats.read().await.clone();
         let websocket = self.websocket_stats.read().await.clone();
         (network, system, websocket)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The recorder is process-wide, so tests that read it must not interleave
+    static RECORDER: Mutex<()> = Mutex::const_new(());
+
+    fn exported(series: &str) -> f64 {
+        prometheus_handle()
+            .render()
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' ')?.parse().ok())
+            .unwrap_or(0.0)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counters_count_events_not_snapshots() {
+        let _guard = RECORDER.lock().await;
+        let monitoring = Monitoring::new();
+        let peer = PeerId::random();
+        let messages_before = exported("p2p_messages_sent");
+        let bytes_before = exported("p2p_bytes_sent");
+
+        monitoring.record_peer_connected(peer, "direct").await;
+        for _ in 0..3 {
+            monitoring.record_message_sent(&peer, 100).await;
+        }
+        // Give the once-a-second background task several chances to interfere
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        assert_eq!(exported("p2p_messages_sent") - messages_before, 3.0);
+        assert_eq!(exported("p2p_bytes_sent") - bytes_before, 300.0);
+        let (network, _, _) = monitoring.get_all_stats().await;
+        assert_eq!(network.messages_sent, 3);
+        assert_eq!(network.bytes_sent, 300);
+        assert_eq!(network.peer_connections[&peer.to_string()].messages_sent, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_gauge_follows_connected_peers() {
+        let _guard = RECORDER.lock().await;
+        // Installing the recorder twice used to panic
+        let _other = Monitoring::new();
+        let monitoring = Monitoring::new();
+        let (first, second) = (PeerId::random(), PeerId::random());
+
+        monitoring.record_peer_connected(first, "direct").await;
+        monitoring.record_peer_connected(second, "direct").await;
+        assert_eq!(exported("p2p_connected_peers"), 2.0);
+
+        // A repeated disconnect must not drive the count below the real peer set
+        monitoring.record_peer_disconnected(&first).await;
+        monitoring.record_peer_disconnected(&first).await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(exported("p2p_connected_peers"), 1.0);
+        let (network, _, _) = monitoring.get_all_stats().await;
+        assert_eq!(network.connected_peers, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn websocket_messages_are_counted_per_type() {
+        let _guard = RECORDER.lock().await;
+        let monitoring = Monitoring::new();
+        let offers_before = exported("ws_messages_sent{type=\"Offer\"}");
+        let bytes_before = exported("ws_bytes_sent");
+
+        monitoring.record_websocket_connected().await;
+        monitoring.record_websocket_message(true, "Offer", 40).await;
+        monitoring.record_websocket_message(true, "Offer", 60).await;
+        monitoring.record_websocket_message(false, "Answer", 10).await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+
+        assert_eq!(exported("ws_messages_sent{type=\"Offer\"}") - offers_before, 2.0);
+        assert_eq!(exported("ws_bytes_sent") - bytes_before, 100.0);
+        // Only the labelled series exists; no unlabelled running total
+        assert!(!prometheus_handle().render().lines().any(|line| line.starts_with("ws_messages_sent ")));
+
+        monitoring.record_websocket_disconnected(Duration::from_secs(1)).await;
+        let (_, _, websocket) = monitoring.get_all_stats().await;
+        assert_eq!(websocket.active_connections, 0);
+        assert_eq!(websocket.messages_sent, 2);
+        assert_eq!(exported("ws_active_connections"), 0.0);
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Classic token bucket: holds up to `capacity` tokens and refills at
+/// `refill_per_sec`. Each accepted event takes one token.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(refill_per_sec: f64, capacity: f64) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_sec,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_n(1.0)
+    }
+
+    /// Takes `n` tokens at once, e.g. one per byte when limiting bandwidth.
+    pub fn try_take_n(&mut self, n: f64) -> bool {
+        self.take_at(n, Instant::now())
+    }
+
+    fn take_at(&mut self, n: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= n {
+            self.tokens -= n;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// A set of token buckets keyed by e.g. client IP, shared between connections.
+#[derive(Clone)]
+pub struct KeyedRateLimiter<K> {
+    buckets: Arc<Mutex<HashMap<K, TokenBucket>>>,
+    refill_per_sec: f64,
+    capacity: f64,
+}
+
+impl<K: Eq + Hash + Clone + Send + 'static> KeyedRateLimiter<K> {
+    pub fn new(refill_per_sec: f64, capacity: f64) -> Self {
+        Self {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            refill_per_sec,
+            capacity,
+        }
+    }
+
+    pub fn try_take(&self, key: &K) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .entry(key.clone())
+            .or_insert_with(|| TokenBucket::new(self.refill_per_sec, self.capacity))
+            .try_take()
+    }
+
+    /// Periodically drop buckets that have refilled completely, since they
+    /// carry no state a fresh bucket wouldn't.
+    pub fn spawn_cleanup(&self, interval: Duration) {
+        let buckets = Arc::downgrade(&self.buckets);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let Some(buckets) = buckets.upgrade() else { break };
+                drop_full(&buckets, Instant::now());
+            }
+        });
+    }
+}
+
+fn drop_full<K>(buckets: &Mutex<HashMap<K, TokenBucket>>, now: Instant) {
+    buckets.lock().unwrap().retain(|_, bucket| !bucket.is_full(now));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills_over_time() {
+        let mut bucket = TokenBucket::new(2.0, 3.0);
+        let start = bucket.last_refill;
+        assert!((0..3).all(|_| bucket.take_at(1.0, start)));
+        assert!(!bucket.take_at(1.0, start), "burst exceeded capacity");
+
+        // Half a second at two tokens per second buys exactly one more
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take_at(1.0, later));
+        assert!(!bucket.take_at(1.0, later));
+    }
+
+    #[test]
+    fn bucket_never_refills_past_capacity() {
+        let mut bucket = TokenBucket::new(100.0, 5.0);
+        let start = bucket.last_refill;
+        assert!(bucket.take_at(5.0, start));
+        assert!(bucket.is_full(start + Duration::from_secs(60)));
+        assert_eq!(bucket.tokens, 5.0);
+        assert!(!bucket.take_at(6.0, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn keys_have_separate_buckets() {
+        let limiter = KeyedRateLimiter::new(1.0, 2.0);
+        assert!(limiter.try_take(&"a") && limiter.try_take(&"a"));
+        assert!(!limiter.try_take(&"a"));
+        assert!(limiter.try_take(&"b"), "one key's traffic limited another");
+    }
+
+    #[test]
+    fn cleanup_drops_only_refilled_buckets() {
+        let limiter = KeyedRateLimiter::new(1.0, 2.0);
+        let now = Instant::now();
+        {
+            let mut buckets = limiter.buckets.lock().unwrap();
+            let mut idle = TokenBucket::new(1.0, 2.0);
+            idle.last_refill = now;
+            assert!(idle.take_at(1.0, now));
+            let mut busy = idle.clone();
+            assert!(busy.take_at(1.0, now));
+            buckets.insert("idle", idle);
+            buckets.insert("busy", busy);
+        }
+
+        // One second later "idle" is full again while "busy" is still one short
+        drop_full(&limiter.buckets, now + Duration::from_secs(1));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("idle"));
+        assert!(buckets.contains_key("busy"));
+    }
+}
@@ -0,0 +1,213 @@
+use libp2p::{
+    multiaddr::Protocol,
+    rendezvous::{self, client, server, Cookie, Namespace},
+    swarm::dial_opts::DialOpts,
+    Multiaddr, PeerId, StreamProtocol,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+/// Bootnodes announce this through identify, which is how nodes pick their
+/// rendezvous points.
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/rendezvous/1.0.0");
+
+/// Command-line options for rendezvous registration and discovery.
+#[derive(clap::Args, Debug, Clone)]
+pub struct RendezvousArgs {
+    /// Shortest registration a bootnode accepts, in seconds
+    #[arg(long, default_value = "60")]
+    pub rendezvous_min_ttl: u64,
+
+    /// Longest registration a bootnode accepts, in seconds
+    #[arg(long, default_value = "86400")]
+    pub rendezvous_max_ttl: u64,
+
+    /// Seconds a node's registrations last; they are renewed halfway through
+    #[arg(long, default_value = "7200")]
+    pub rendezvous_ttl: u64,
+
+    /// Seconds between discovery rounds for subscribed topics
+    #[arg(long, default_value = "60")]
+    pub rendezvous_discover_interval: u64,
+
+    /// Most registrations asked for in one discovery request
+    #[arg(long, default_value = "100")]
+    pub rendezvous_discover_limit: u64,
+}
+
+pub fn server(args: &RendezvousArgs) -> server::Behaviour {
+    server::Behaviour::new(
+        server::Config::default()
+            .with_min_ttl(args.rendezvous_min_ttl)
+            .with_max_ttl(args.rendezvous_max_ttl),
+    )
+}
+
+/// Whether a listen address is worth registering: loopback and unspecified
+/// addresses would only lead peers back to themselves.
+pub fn is_dialable(address: &Multiaddr) -> bool {
+    match address.iter().next() {
+        Some(Protocol::Ip4(ip)) => !ip.is_loopback() && !ip.is_unspecified(),
+        Some(Protocol::Ip6(ip)) => !ip.is_loopback() && !ip.is_unspecified(),
+        _ => true,
+    }
+}
+
+/// Registers the local node under the name of each topic it is subscribed to
+/// with every rendezvous point it is connected to, and discovers the other
+/// peers registered under those names.
+pub struct Rendezvous {
+    ttl: Duration,
+    discover_interval: Duration,
+    discover_limit: u64,
+    namespaces: HashSet<Namespace>,
+    /// Rendezvous points and when we last registered with each
+    points: HashMap<PeerId, Instant>,
+    /// Where the last discovery left off, so the next only returns newer registrations
+    cookies: HashMap<(PeerId, Namespace), Cookie>,
+    last_discovery: Option<Instant>,
+}
+
+impl Rendezvous {
+    pub fn new(args: &RendezvousArgs) -> Self {
+        Self {
+            ttl: Duration::from_secs(args.rendezvous_ttl),
+            discover_interval: Duration::from_secs(args.rendezvous_discover_interval),
+            discover_limit: args.rendezvous_discover_limit,
+            namespaces: HashSet::new(),
+            points: HashMap::new(),
+            cookies: HashMap::new(),
+            last_discovery: None,
+        }
+    }
+
+    /// Starts registering under `topic` and looking for its peers. Topic
+    /// names longer than a namespace allows are skipped.
+    pub fn add_topic(&mut self, behaviour: &mut client::Behaviour, topic: &str) {
+        let Ok(namespace) = Namespace::new(topic.to_string()) else {
+            return;
+        };
+        if !self.namespaces.insert(namespace.clone()) {
+            return;
+        }
+        for point in self.points.keys() {
+            self.register(behaviour, *point, &namespace);
+            self.discover(behaviour, *point, &namespace);
+        }
+    }
+
+    /// A connected peer runs a rendezvous server.
+    pub fn add_point(&mut self, behaviour: &mut client::Behaviour, point: PeerId) {
+        if self.points.insert(point, Instant::now()).is_some() {
+            return;
+        }
+        for namespace in &self.namespaces {
+            self.register(behaviour, point, namespace);
+            self.discover(behaviour, point, namespace);
+        }
+    }
+
+    /// Registrations at a disconnected point lapse on their own; they are
+    /// made afresh if it comes back.
+    pub fn remove_point(&mut self, point: &PeerId) {
+        self.points.remove(point);
+        self.cookies.retain(|(at, _), _| at != point);
+    }
+
+    /// Renews registrations halfway through their TTL and runs a discovery
+    /// round when one is due; run periodically.
+    pub fn tick(&mut self, behaviour: &mut client::Behaviour) {
+        let now = Instant::now();
+        let renew: Vec<PeerId> = self
+            .points
+            .iter()
+            .filter(|(_, registered)| now.duration_since(**registered) >= self.ttl / 2)
+            .map(|(point, _)| *point)
+            .collect();
+        for point in renew {
+            self.points.insert(point, now);
+            for namespace in &self.namespaces {
+                self.register(behaviour, point, namespace);
+            }
+        }
+        if self.last_discovery.is_some_and(|at| now.duration_since(at) < self.discover_interval) {
+            return;
+        }
+        self.last_discovery = Some(now);
+        for point in self.points.keys() {
+            for namespace in &self.namespaces {
+                self.discover(behaviour, *point, namespace);
+            }
+        }
+    }
+
+    fn register(&self, behaviour: &mut client::Behaviour, point: PeerId, namespace: &Namespace) {
+        if let Err(e) = behaviour.register(namespace.clone(), point, Some(self.ttl.as_secs())) {
+            eprintln!("Could not register under {} with {}: {}", namespace, point, e);
+        }
+    }
+
+    fn discover(&self, behaviour: &mut client::Behaviour, point: PeerId, namespace: &Namespace) {
+        let cookie = self.cookies.get(&(point, namespace.clone())).cloned();
+        behaviour.discover(Some(namespace.clone()), cookie, Some(self.discover_limit), point);
+    }
+
+    /// Handles a client event and returns the discovered peers to dial.
+    pub fn handle_event(&mut self, event: client::Event, local_peer_id: PeerId) -> Vec<DialOpts> {
+        match event {
+            client::Event::Discovered {
+                rendezvous_node,
+                registrations,
+                cookie,
+            } => {
+                if let Some(namespace) = cookie.namespace() {
+                    self.cookies.insert((rendezvous_node, namespace.clone()), cookie.clone());
+                }
+                registrations
+                    .into_iter()
+                    .map(|registration| registration.record)
+                    .filter(|record| record.peer_id() != local_peer_id)
+                    .map(|record| {
+                        let addresses: Vec<Multiaddr> = record.addresses().to_vec();
+                        DialOpts::peer_id(record.peer_id()).addresses(addresses).build()
+                    })
+                    .collect()
+            }
+            client::Event::Registered { rendezvous_node, ttl, namespace } => {
+                println!("Registered under {} with {} for {}s", namespace, rendezvous_node, ttl);
+                Vec::new()
+            }
+            client::Event::RegisterFailed { rendezvous_node, namespace, error } => {
+                eprintln!("Rendezvous point {} refused our registration under {}: {:?}", rendezvous_node, namespace, error);
+                Vec::new()
+            }
+            client::Event::DiscoverFailed { rendezvous_node, namespace, error } => {
+                // A stale cookie is refused; start over from the beginning
+                if let (Some(namespace), rendezvous::ErrorCode::InvalidCookie) = (namespace, error) {
+                    self.cookies.remove(&(rendezvous_node, namespace));
+                } else {
+                    eprintln!("Discovery at {} failed: {:?}", rendezvous_node, error);
+                }
+                Vec::new()
+            }
+            client::Event::Expired { .. } => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_reachable_listen_addresses_are_registered() {
+        for address in ["/ip4/192.168.1.20/tcp/4001", "/ip6/2001:db8::1/tcp/4001/ws", "/dns4/node.example/tcp/4001"] {
+            assert!(is_dialable(&address.parse().unwrap()), "{}", address);
+        }
+        for address in ["/ip4/127.0.0.1/tcp/4001", "/ip4/0.0.0.0/tcp/4001", "/ip6/::1/tcp/4001/ws", "/ip6/::/tcp/4001"] {
+            assert!(!is_dialable(&address.parse().unwrap()), "{}", address);
+        }
+    }
+}
@@ -1,125 +1,1081 @@
-use futures_util::{SinkExt, StreamExt};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{mpsc, RwLock};
-use warp::{
-    ws::{Message, WebSocket},
-    Filter,
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    watch, RwLock,
+};
+use uuid::Uuid;
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
 };
 
+use crate::monitoring::{Monitoring, WebRtcReport};
+use crate::rate_limit::{KeyedRateLimiter, TokenBucket};
+use crate::turn_server::ephemeral_credentials;
+
 type PeerId = String;
-type PeerMap = Arc<RwLock<HashMap<PeerId, mpsc::UnboundedSender<Result<Message, warp::Error>>>>>;
+type PeerMap = Arc<RwLock<HashMap<PeerId, PeerEntry>>>;
+
+/// Prefix mixed into the bytes a client signs, so a registration signature
+/// cannot be replayed as a signature for anything else.
+const CHALLENGE_PREFIX: &str = "hippius-signaling-register:";
+/// How long a closing connection may take to flush its queue to the client
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A registered signaling session.
+struct PeerEntry {
+    connection_id: Uuid,
+    credential: Credential,
+    outbox: Outbox,
+}
+
+/// What to do when a connection's outgoing queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    /// Drop the message and tell the sender the target is busy
+    Drop,
+    /// Disconnect the connection that is not keeping up
+    Disconnect,
+}
+
+/// Resource limits applied to every signaling connection.
+#[derive(Debug, Clone)]
+pub struct SignalingLimits {
+    /// Largest text message accepted; larger ones are answered with an error.
+    /// Frames beyond four times this size close the socket outright.
+    pub max_message_size: usize,
+    pub messages_per_sec: f64,
+    pub burst: f64,
+    pub ip_messages_per_sec: f64,
+    pub ip_burst: f64,
+    /// Open connections allowed from one client IP
+    pub ip_connections: usize,
+    /// Messages buffered per connection before the overflow policy applies
+    pub outgoing_queue: usize,
+    pub overflow_policy: OverflowPolicy,
+    /// How often the server pings each client
+    pub ping_interval: Duration,
+    /// Connections that send nothing (not even a pong) for this long are dropped
+    pub idle_timeout: Duration,
+}
+
+/// Parses a messages-per-second limit, which must be a positive number.
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        Ok(_) => Err("must be greater than 0".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Sending half of a connection's bounded outgoing queue.
+#[derive(Clone)]
+struct Outbox {
+    tx: mpsc::Sender<(&'static str, Message)>,
+    /// The close frame to send once the queue is flushed, when closing
+    close: Arc<watch::Sender<Option<Message>>>,
+    policy: OverflowPolicy,
+}
+
+impl Outbox {
+    /// Queues a message without waiting. Returns false if it was not queued;
+    /// on a full queue the overflow policy has been applied already.
+    fn send(&self, msg: &SignalingMessage) -> bool {
+        let text = serde_json::to_string(msg).unwrap();
+        match self.tx.try_send((msg.kind(), Message::Text(text))) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                if self.policy == OverflowPolicy::Disconnect {
+                    self.close(close_code::POLICY, "outgoing queue full");
+                }
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Closes the connection this outbox belongs to, after the messages
+    /// already queued have been sent.
+    fn close(&self, code: u16, reason: &'static str) {
+        self.close_with(Some(CloseFrame { code, reason: reason.into() }));
+    }
+
+    /// Closes with the given frame, unless a close is already under way.
+    fn close_with(&self, frame: Option<CloseFrame<'static>>) {
+        self.close.send_if_modified(|close| {
+            let first = close.is_none();
+            if first {
+                *close = Some(Message::Close(frame));
+            }
+            first
+        });
+    }
+
+    fn send_error(&self, code: &str, message: &str, reference: Option<String>) -> bool {
+        self.send(&SignalingMessage::Error {
+            code: code.to_string(),
+            message: message.to_string(),
+            reference,
+        })
+    }
+}
+
+/// One entry of an `RTCConfiguration.iceServers` list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// Builds the ICE server list handed to each client after it registers,
+/// minting fresh TURN REST API credentials for that peer, or handing out the
+/// static ones from the TURN config when no shared secret is set.
+#[derive(Debug, Clone, Default)]
+pub struct IceProvider {
+    pub stun_urls: Vec<String>,
+    pub turn_urls: Vec<String>,
+    pub turn_secret: Option<String>,
+    /// Username and credential for `turn_urls` when there is no secret
+    pub static_credentials: Option<(String, String)>,
+    pub credential_ttl: Duration,
+}
+
+impl IceProvider {
+    fn ice_servers_for(&self, peer_id: &str) -> Vec<IceServer> {
+        let mut servers = Vec::new();
+        if !self.stun_urls.is_empty() {
+            servers.push(IceServer {
+                urls: self.stun_urls.clone(),
+                username: None,
+                credential: None,
+            });
+        }
+        if self.turn_urls.is_empty() {
+            return servers;
+        }
+        let credentials = match (&self.turn_secret, &self.static_credentials) {
+            (Some(secret), _) => Some(ephemeral_credentials(secret, peer_id, self.credential_ttl)),
+            (None, Some(credentials)) => Some(credentials.clone()),
+            (None, None) => None,
+        };
+        if let Some((username, credential)) = credentials {
+            servers.push(IceServer {
+                urls: self.turn_urls.clone(),
+                username: Some(username),
+                credential: Some(credential),
+            });
+        }
+        servers
+    }
+}
+
+/// State shared by all signaling connections.
+struct SignalingState {
+    peers: PeerMap,
+    auth: SignalingAuth,
+    ice: IceProvider,
+    limits: SignalingLimits,
+    ip_limiter: KeyedRateLimiter<IpAddr>,
+    /// Open connections per client IP
+    ip_connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    monitoring: Arc<Monitoring>,
+}
+
+/// Holds one of an IP's connection slots and gives it back when dropped,
+/// whether the upgrade failed or the connection ran its course.
+struct ConnectionSlot {
+    ip: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionSlot {
+    fn acquire(counts: &Arc<Mutex<HashMap<IpAddr, usize>>>, ip: IpAddr, limit: usize) -> Option<Self> {
+        let mut map = counts.lock().unwrap();
+        let count = map.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(Self { ip, counts: counts.clone() })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut map = self.counts.lock().unwrap();
+        if let Some(count) = map.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                map.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// What a peer used to prove ownership of its id. A later registration for the
+/// same id is only accepted if it presents the same credential.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Credential {
+    PublicKey(Vec<u8>),
+    Token,
+}
+
+/// A message as it appears on the wire: the tagged `SignalingMessage` plus an
+/// optional client-chosen request id, echoed back as `ref` in the `Ack`,
+/// `Registered` or `Error` that answers it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(flatten)]
+    message: SignalingMessage,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "payload")]
 enum SignalingMessage {
-    Register { peer_id: String },
+    Challenge {
+        nonce: String,
+    },
+    Register {
+        peer_id: String,
+        /// Base64 protobuf-encoded libp2p public key
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public_key: Option<String>,
+        /// Base64 signature over `CHALLENGE_PREFIX` followed by the nonce
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+        /// Hex HMAC-SHA256 of `<peer id>|<expires>` under the server secret
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        /// Unix time, in seconds, after which the token is no longer accepted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+    },
+    Registered {
+        peer_id: String,
+        #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+        reference: Option<String>,
+    },
+    /// ICE servers, including short-lived TURN credentials, for a registered client
+    IceServers {
+        ice_servers: Vec<IceServer>,
+        /// Seconds until the TURN credentials expire
+        ttl: u64,
+    },
+    /// A forwarded message was queued for its target
+    Ack {
+        #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+        reference: Option<String>,
+    },
+    Error {
+        code: String,
+        message: String,
+        #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+        reference: Option<String>,
+    },
     Offer { from: String, to: String, sdp: String },
     Answer { from: String, to: String, sdp: String },
     IceCandidate { from: String, to: String, candidate: String },
+    /// `getStats()` summary for one of the sender's WebRTC connections
+    Stats {
+        from: String,
+        #[serde(flatten)]
+        report: WebRtcReport,
+    },
+    /// A peer this client was signaling with has disconnected
+    PeerLeft {
+        peer_id: String,
+    },
+}
+
+impl SignalingMessage {
+    /// Message type name, as used for the `type` tag and metric labels.
+    fn kind(&self) -> &'static str {
+        match self {
+            SignalingMessage::Challenge { .. } => "Challenge",
+            SignalingMessage::Register { .. } => "Register",
+            SignalingMessage::Registered { .. } => "Registered",
+            SignalingMessage::IceServers { .. } => "IceServers",
+            SignalingMessage::Ack { .. } => "Ack",
+            SignalingMessage::Error { .. } => "Error",
+            SignalingMessage::Offer { .. } => "Offer",
+            SignalingMessage::Answer { .. } => "Answer",
+            SignalingMessage::IceCandidate { .. } => "IceCandidate",
+            SignalingMessage::Stats { .. } => "Stats",
+            SignalingMessage::PeerLeft { .. } => "PeerLeft",
+        }
+    }
+
+    /// The peer a client-originated message claims to come from.
+    fn sender(&self) -> Option<&str> {
+        match self {
+            SignalingMessage::Stats { from, .. } => Some(from),
+            _ => self.route().map(|(from, _)| from),
+        }
+    }
+
+    /// Sender and target of a message that is relayed between peers.
+    fn route(&self) -> Option<(&str, &str)> {
+        match self {
+            SignalingMessage::Offer { from, to, .. }
+            | SignalingMessage::Answer { from, to, .. }
+            | SignalingMessage::IceCandidate { from, to, .. } => Some((from, to)),
+            _ => None,
+        }
+    }
+}
+
+/// Registration policy for the signaling server.
+#[derive(Clone, Default)]
+pub struct SignalingAuth {
+    /// Shared secret for HMAC registration tokens. Signed challenges are always
+    /// accepted; tokens only when a secret is configured.
+    secret: Option<Arc<Vec<u8>>>,
+}
+
+impl SignalingAuth {
+    pub fn new(secret: Option<String>) -> Self {
+        Self {
+            secret: secret.map(|s| Arc::new(s.into_bytes())),
+        }
+    }
+
+    fn verify_token(&self, peer_id: &str, expires: Option<u64>, token: &str) -> Result<(), &'static str> {
+        let secret = self.secret.as_ref().ok_or("token authentication is not enabled")?;
+        let expires = expires.ok_or("token requires an expiry")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if expires <= now {
+            return Err("token has expired");
+        }
+        let token = hex::decode(token).map_err(|_| "token is not valid hex")?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+        mac.update(format!("{}|{}", peer_id, expires).as_bytes());
+        mac.verify_slice(&token).map_err(|_| "invalid token")
+    }
+
+    fn verify_signature(
+        &self,
+        peer_id: &str,
+        nonce: &str,
+        public_key: &str,
+        signature: &str,
+    ) -> Result<Vec<u8>, &'static str> {
+        let key_bytes = BASE64.decode(public_key).map_err(|_| "public key is not valid base64")?;
+        let signature = BASE64.decode(signature).map_err(|_| "signature is not valid base64")?;
+        let public_key = libp2p::identity::PublicKey::try_decode_protobuf(&key_bytes)
+            .map_err(|_| "public key is not a valid libp2p key")?;
+
+        if libp2p::PeerId::from_public_key(&public_key).to_string() != peer_id {
+            return Err("public key does not match peer id");
+        }
+        let signed = format!("{}{}", CHALLENGE_PREFIX, nonce);
+        if !public_key.verify(signed.as_bytes(), &signature) {
+            return Err("invalid signature");
+        }
+        Ok(key_bytes)
+    }
 }
 
-pub async fn start_signaling_server(port: u16) {
-    let peer_map = Arc::new(RwLock::new(HashMap::new()));
+/// `/signal`: the WebSocket endpoint browsers register and exchange SDP through.
+pub fn router(auth: SignalingAuth, ice: IceProvider, limits: SignalingLimits, monitoring: Arc<Monitoring>) -> Router {
+    let ip_limiter = KeyedRateLimiter::new(limits.ip_messages_per_sec, limits.ip_burst);
+    ip_limiter.spawn_cleanup(Duration::from_secs(60));
+
+    let state = Arc::new(SignalingState {
+        peers: Arc::new(RwLock::new(HashMap::new())),
+        auth,
+        ice,
+        limits,
+        ip_limiter,
+        ip_connections: Arc::new(Mutex::new(HashMap::new())),
+        monitoring,
+    });
+
+    Router::new().route("/signal", get(upgrade)).with_state(state)
+}
+
+async fn upgrade(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<Arc<SignalingState>>,
+) -> Response {
+    let Some(slot) = ConnectionSlot::acquire(&state.ip_connections, addr.ip(), state.limits.ip_connections) else {
+        state.monitoring.record_websocket_rejection("too_many_connections").await;
+        return (StatusCode::TOO_MANY_REQUESTS, "too many signaling connections from this address").into_response();
+    };
+    let socket_limit = state.limits.max_message_size * 4;
+    ws.max_message_size(socket_limit)
+        .max_frame_size(socket_limit)
+        .on_upgrade(move |socket| async move {
+            handle_connection(socket, addr, state).await;
+            drop(slot);
+        })
+}
+
+/// Per-socket state for one signaling client.
+struct Connection {
+    id: Uuid,
+    state: Arc<SignalingState>,
+    outbox: Outbox,
+    nonce: String,
+    registered_id: Option<PeerId>,
+    /// Peers this connection has sent signaling messages to; they are told
+    /// when it goes away
+    contacts: HashSet<PeerId>,
+}
+
+impl Connection {
+    async fn handle_text(&mut self, text: &str) {
+        // Pull the request id out first so malformed requests can still be correlated
+        let monitoring = &self.state.monitoring;
+        let bytes = text.len() as u64;
+        let value: serde_json::Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(e) => {
+                monitoring.record_websocket_message(false, "malformed", bytes).await;
+                monitoring.record_websocket_rejection("malformed").await;
+                self.outbox.send_error("malformed", &format!("invalid JSON: {}", e), None);
+                return;
+            }
+        };
+        let reference = value.get("id").and_then(|id| id.as_str()).map(String::from);
+
+        match serde_json::from_value::<Envelope>(value) {
+            Ok(envelope) => {
+                monitoring.record_websocket_message(false, envelope.message.kind(), bytes).await;
+                self.handle_message(envelope.message, envelope.id).await
+            }
+            Err(e) => {
+                monitoring.record_websocket_message(false, "malformed", bytes).await;
+                monitoring.record_websocket_rejection("malformed").await;
+                self.outbox.send_error("malformed", &format!("invalid message: {}", e), reference);
+            }
+        }
+    }
+
+    async fn handle_message(&mut self, message: SignalingMessage, reference: Option<String>) {
+        if let SignalingMessage::Register { peer_id, public_key, signature, token, expires } = message {
+            self.register(peer_id, public_key, signature, token, expires, reference).await;
+            return;
+        }
+
+        let Some(from) = message.sender() else {
+            // Server-to-client messages make no sense coming from a client
+            self.outbox.send_error("unsupported", "message type cannot be sent by clients", reference);
+            return;
+        };
+
+        let id = match self.registered_id.as_deref() {
+            None => {
+                self.state.monitoring.record_websocket_rejection("not_registered").await;
+                self.outbox.send_error("not_registered", "register before sending signaling messages", reference);
+                return;
+            }
+            Some(id) if id != from => {
+                self.state.monitoring.record_websocket_rejection("sender_mismatch").await;
+                self.outbox.send_error("sender_mismatch", "`from` does not match the registered peer id", reference);
+                return;
+            }
+            Some(id) => id.to_string(),
+        };
+
+        match message {
+            // Reports are kept per remote peer, so only peers this client has
+            // signaled with are accepted; anything else would grow without bound
+            SignalingMessage::Stats { report, .. } if !self.contacts.contains(&report.peer) => {
+                self.state.monitoring.record_websocket_rejection("unknown_peer").await;
+                self.outbox.send_error(
+                    "unknown_peer",
+                    &format!("no signaling exchange with {}", report.peer),
+                    reference,
+                );
+            }
+            SignalingMessage::Stats { report, .. } => {
+                self.state.monitoring.record_webrtc_report(&id, report).await;
+                self.outbox.send(&SignalingMessage::Ack { reference });
+            }
+            message => {
+                if let Some((_, to)) = message.route() {
+                    let to = to.to_string();
+                    self.forward(&to, message, reference).await;
+                }
+            }
+        }
+    }
+
+    async fn register(
+        &mut self,
+        id: String,
+        public_key: Option<String>,
+        signature: Option<String>,
+        token: Option<String>,
+        expires: Option<u64>,
+        reference: Option<String>,
+    ) {
+        if self.registered_id.is_some() {
+            self.outbox.send_error("already_registered", "connection is already registered", reference);
+            return;
+        }
 
-    let peer_map = warp::any().map(move || peer_map.clone());
+        let auth = &self.state.auth;
+        let credential = match (public_key, signature, token) {
+            (Some(public_key), Some(signature), _) => auth
+                .verify_signature(&id, &self.nonce, &public_key, &signature)
+                .map(Credential::PublicKey),
+            (_, _, Some(token)) => auth.verify_token(&id, expires, &token).map(|_| Credential::Token),
+            _ => Err("registration requires a signed challenge or a token"),
+        };
+        let credential = match credential {
+            Ok(credential) => credential,
+            Err(reason) => {
+                println!("Rejected registration for {}: {}", id, reason);
+                self.state.monitoring.record_websocket_rejection("unauthorized").await;
+                self.outbox.send_error("unauthorized", reason, reference);
+                return;
+            }
+        };
 
-    let signaling = warp::path("signal")
-        .and(warp::ws())
-        .and(peer_map)
-        .map(|ws: warp::ws::Ws, peer_map| {
-            ws.on_upgrade(move |socket| handle_connection(socket, peer_map))
+        let mut peers = self.state.peers.write().await;
+        if let Some(existing) = peers.get(&id) {
+            if existing.credential != credential {
+                drop(peers);
+                println!("Rejected duplicate registration for {}", id);
+                self.state.monitoring.record_websocket_rejection("peer_id_taken").await;
+                self.outbox
+                    .send_error("peer_id_taken", "peer id is registered with a different key", reference);
+                return;
+            }
+            // Same key reconnecting: the new session replaces the old one, and
+            // the old socket is closed so it cannot keep sending as this peer
+            existing
+                .outbox
+                .send_error("session_replaced", "peer id registered from another connection", None);
+            existing.outbox.close(close_code::NORMAL, "session replaced");
+        }
+        peers.insert(
+            id.clone(),
+            PeerEntry {
+                connection_id: self.id,
+                credential,
+                outbox: self.outbox.clone(),
+            },
+        );
+        drop(peers);
+
+        self.state.monitoring.record_websocket_registration().await;
+        self.outbox.send(&SignalingMessage::Registered { peer_id: id.clone(), reference });
+        self.outbox.send(&SignalingMessage::IceServers {
+            ice_servers: self.state.ice.ice_servers_for(&id),
+            ttl: self.state.ice.credential_ttl.as_secs(),
         });
+        println!("Peer registered: {}", id);
+        self.registered_id = Some(id);
+    }
+
+    /// Forwards a peer-to-peer signaling message to its target and tells the
+    /// sender whether it was queued.
+    async fn forward(&mut self, to: &str, msg: SignalingMessage, reference: Option<String>) {
+        let delivered = match self.state.peers.read().await.get(to) {
+            Some(peer) => peer.outbox.send(&msg),
+            None => {
+                self.state.monitoring.record_websocket_forward_failure("unknown_peer").await;
+                self.outbox
+                    .send_error("unknown_peer", &format!("peer {} is not registered", to), reference);
+                return;
+            }
+        };
+        if delivered {
+            self.contacts.insert(to.to_string());
+            self.outbox.send(&SignalingMessage::Ack { reference });
+        } else {
+            self.state.monitoring.record_websocket_forward_failure("queue_full").await;
+            self.outbox
+                .send_error("peer_busy", "target peer is not keeping up; message dropped", reference);
+        }
+    }
+
+    /// Removes the peer from the map, unless a newer session for the same key
+    /// has already taken its place, and tells its contacts it has left.
+    async fn unregister(&mut self) {
+        let Some(peer_id) = self.registered_id.take() else { return };
+
+        let mut peers = self.state.peers.write().await;
+        if peers.get(&peer_id).map(|entry| entry.connection_id) != Some(self.id) {
+            return;
+        }
+        peers.remove(&peer_id);
+        println!("Peer disconnected: {}", peer_id);
+        self.state.monitoring.forget_webrtc_peer(&peer_id).await;
 
-    println!("Starting WebRTC signaling server on port {}", port);
-    warp::serve(signaling).run(([0, 0, 0, 0], port)).await;
+        let left = SignalingMessage::PeerLeft { peer_id };
+        for contact in &self.contacts {
+            if let Some(peer) = peers.get(contact) {
+                peer.outbox.send(&left);
+            }
+        }
+    }
+}
+
+/// Sends one queued message, returning false once the socket is gone.
+async fn send_queued(
+    ws_tx: &mut SplitSink<WebSocket, Message>,
+    monitoring: &Monitoring,
+    kind: &'static str,
+    msg: Message,
+) -> bool {
+    let bytes = match &msg {
+        Message::Text(text) => text.len() as u64,
+        _ => 0,
+    };
+    if let Err(e) = ws_tx.send(msg).await {
+        eprintln!("Failed to send WebSocket message: {}", e);
+        return false;
+    }
+    monitoring.record_websocket_message(true, kind, bytes).await;
+    true
 }
 
-async fn handle_connection(ws: WebSocket, peer_map: PeerMap) {
+async fn handle_connection(ws: WebSocket, addr: SocketAddr, state: Arc<SignalingState>) {
     let (mut ws_tx, mut ws_rx) = ws.split();
-    let (tx, rx) = mpsc::unbounded_channel();
-    
-    let mut rx = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
-    
-    let peer_id = Arc::new(RwLock::new(String::new()));
-    let peer_id_clone = peer_id.clone();
-
-    // Forward messages from rx to websocket
-    tokio::task::spawn(async move {
-        while let Some(message) = rx.next().await {
-            if let Ok(msg) = message {
-                if let Err(e) = ws_tx.send(msg).await {
-                    eprintln!("Failed to send WebSocket message: {}", e);
+    let (tx, mut rx) = mpsc::channel(state.limits.outgoing_queue);
+
+
+    let (close, mut closing) = watch::channel(None);
+    let outbox = Outbox {
+        tx,
+        close: Arc::new(close),
+        policy: state.limits.overflow_policy,
+    };
+
+    let connected_at = Instant::now();
+    state.monitoring.record_websocket_connected().await;
+
+    let ip = addr.ip();
+    let mut bucket = TokenBucket::new(state.limits.messages_per_sec, state.limits.burst);
+
+    // Every connection gets a fresh nonce to sign before it may register
+    let mut nonce_bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    let nonce = BASE64.encode(nonce_bytes);
+    outbox.send(&SignalingMessage::Challenge { nonce: nonce.clone() });
+
+    let mut connection = Connection {
+        id: Uuid::new_v4(),
+        state: state.clone(),
+        outbox: outbox.clone(),
+        nonce,
+        registered_id: None,
+        contacts: HashSet::new(),
+    };
+
+    // Forward queued messages to the websocket and keep the client pinged
+    let ping_interval = state.limits.ping_interval;
+    let monitoring = state.monitoring.clone();
+    let mut reader_closing = closing.clone();
+    let writer = async move {
+        let mut ping = tokio::time::interval_at(Instant::now() + ping_interval, ping_interval);
+        loop {
+            let (kind, msg) = tokio::select! {
+                item = rx.recv() => match item {
+                    Some(item) => item,
+                    None => break,
+                },
+                _ = closing.changed() => {
+                    // Flush what was queued first, such as the reason for closing
+                    let frame = closing.borrow_and_update().clone();
+                    let flush = async {
+                        while let Ok((kind, msg)) = rx.try_recv() {
+                            if !send_queued(&mut ws_tx, &monitoring, kind, msg).await {
+                                return;
+                            }
+                        }
+                        if let Some(frame) = frame {
+                            let _ = ws_tx.send(frame).await;
+                        }
+                        // After a client's Close only the library's own reply may be
+                        // sent, which closing the sink flushes
+                        let _ = ws_tx.close().await;
+                    };
+                    let _ = tokio::time::timeout(CLOSE_TIMEOUT, flush).await;
                     break;
                 }
+                _ = ping.tick() => {
+                    if ws_tx.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            if !send_queued(&mut ws_tx, &monitoring, kind, msg).await {
+                break;
             }
         }
-    });
+    };
 
     // Handle incoming WebSocket messages
-    while let Some(result) = ws_rx.next().await {
-        match result {
-            Ok(msg) => {
-                if let Ok(text) = msg.to_str() {
-                    if let Ok(signal_msg) = serde_json::from_str::<SignalingMessage>(text) {
-                        match signal_msg {
-                            SignalingMessage::Register { peer_id: id } => {
-                                let mut peer_id = peer_id_clone.write().await;
-                                *peer_id = id.clone();
-                                peer_map.write().await.insert(id, tx.clone());
-                                println!("Peer registered: {}", peer_id);
-                            }
-                            SignalingMessage::Offer { from, to, sdp } => {
-                                if let Some(peer_tx) = peer_map.read().await.get(&to) {
-                                    let msg = SignalingMessage::Offer {
-                                        from,
-                                        to,
-                                        sdp,
-                                    };
-                                    let _ = peer_tx.send(Ok(Message::text(
-                                        serde_json::to_string(&msg).unwrap(),
-                                    )));
-                                }
-                            }
-                            SignalingMessage::Answer { from, to, sdp } => {
-                                if let Some(peer_tx) = peer_map.read().await.get(&to) {
-                                    let msg = SignalingMessage::Answer {
-                                        from,
-                                        to,
-                                        sdp,
-                                    };
-                                    let _ = peer_tx.send(Ok(Message::text(
-                                        serde_json::to_string(&msg).unwrap(),
-                                    )));
-                                }
-                            }
-                            SignalingMessage::IceCandidate { from, to, candidate } => {
-                                if let Some(peer_tx) = peer_map.read().await.get(&to) {
-                                    let msg = SignalingMessage::IceCandidate {
-                                        from,
-                                        to,
-                                        candidate,
-                                    };
-                                    let _ = peer_tx.send(Ok(Message::text(
-                                        serde_json::to_string(&msg).unwrap(),
-                                    )));
-                                }
-                            }
+    let idle_timeout = state.limits.idle_timeout;
+    let reader = async {
+        let mut last_seen = Instant::now();
+        loop {
+            let result = tokio::select! {
+                // A pending close wins over messages already waiting to be read
+                biased;
+                _ = reader_closing.changed() => {
+                    println!("Closing signaling connection from {}: outgoing queue full or session replaced", ip);
+                    break;
+                }
+                result = ws_rx.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(last_seen + idle_timeout) => {
+                    println!("Closing idle signaling connection from {}", ip);
+                    break;
+                }
+            };
+
+            match result {
+                Ok(msg) => {
+                    last_seen = Instant::now();
+                    match msg {
+                        Message::Close(frame) => {
+                            outbox.close_with(frame);
+                            break;
+                        }
+                        // Keepalive traffic is not subject to rate limits
+                        Message::Ping(_) | Message::Pong(_) => continue,
+                        _ => {}
+                    }
+
+                    if !bucket.try_take() || !state.ip_limiter.try_take(&ip) {
+                        state.monitoring.record_websocket_rejection("rate_limited").await;
+                        outbox.send_error("rate_limited", "too many messages; slow down", None);
+                        continue;
+                    }
+
+                    if let Message::Text(text) = &msg {
+                        if text.len() > state.limits.max_message_size {
+                            state.monitoring.record_websocket_rejection("message_too_large").await;
+                            outbox.send_error(
+                                "message_too_large",
+                                &format!("messages are limited to {} bytes", state.limits.max_message_size),
+                                None,
+                            );
+                            continue;
                         }
+                        connection.handle_text(text).await;
+                    } else {
+                        state.monitoring.record_websocket_rejection("unsupported").await;
+                        outbox.send_error("unsupported", "binary messages are not supported; send JSON text", None);
                     }
                 }
+                Err(e) => {
+                    eprintln!("WebSocket error: {}", e);
+                    break;
+                }
             }
-            Err(e) => {
-                eprintln!("WebSocket error: {}", e);
-                break;
+        }
+    };
+
+    // Whichever side finishes first takes the other down with it, except that
+    // a closing connection gets to flush its queue and send the close frame
+    tokio::pin!(writer);
+    tokio::select! {
+        _ = &mut writer => {}
+        _ = reader => {
+            if outbox.close.borrow().is_some() {
+                writer.await;
             }
         }
     }
 
-    // Remove peer from map when connection closes
-    let peer_id = peer_id_clone.read().await;
-    if !peer_id.is_empty() {
-        peer_map.write().await.remove(&*peer_id);
-        println!("Peer disconnected: {}", peer_id);
+    connection.unregister().await;
+    state.monitoring.record_websocket_disconnected(connected_at.elapsed()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+    use serde_json::{json, Value};
+    use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+    const SECRET: &str = "signaling test secret";
+
+    fn token(secret: &str, peer_id: &str, expires: u64) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}|{}", peer_id, expires).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn unix_now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn sign_challenge(key: &Keypair, nonce: &str) -> (String, String) {
+        let signature = key.sign(format!("{}{}", CHALLENGE_PREFIX, nonce).as_bytes()).unwrap();
+        (BASE64.encode(key.public().encode_protobuf()), BASE64.encode(signature))
+    }
+
+    #[test]
+    fn tokens_are_bound_to_peer_id_expiry_and_secret() {
+        let auth = SignalingAuth::new(Some(SECRET.to_string()));
+        let expires = unix_now() + 600;
+        let valid = token(SECRET, "alice", expires);
+        assert_eq!(auth.verify_token("alice", Some(expires), &valid), Ok(()));
+
+        assert_eq!(auth.verify_token("bob", Some(expires), &valid), Err("invalid token"));
+        assert_eq!(auth.verify_token("alice", Some(expires + 3600), &valid), Err("invalid token"));
+        let forged = token("another secret", "alice", expires);
+        assert_eq!(auth.verify_token("alice", Some(expires), &forged), Err("invalid token"));
+        assert_eq!(auth.verify_token("alice", Some(expires), "not hex"), Err("token is not valid hex"));
+        assert_eq!(auth.verify_token("alice", None, &valid), Err("token requires an expiry"));
+
+        let expired = unix_now() - 1;
+        assert_eq!(
+            auth.verify_token("alice", Some(expired), &token(SECRET, "alice", expired)),
+            Err("token has expired")
+        );
+
+        let no_secret = SignalingAuth::new(None);
+        assert!(no_secret.verify_token("alice", Some(expires), &valid).is_err());
+    }
+
+    #[test]
+    fn challenge_signatures_must_come_from_the_peer_ids_key() {
+        let auth = SignalingAuth::default();
+        let key = Keypair::generate_ed25519();
+        let peer_id = key.public().to_peer_id().to_string();
+        let (public_key, signature) = sign_challenge(&key, "nonce");
+        assert_eq!(
+            auth.verify_signature(&peer_id, "nonce", &public_key, &signature),
+            Ok(key.public().encode_protobuf())
+        );
+
+        assert_eq!(
+            auth.verify_signature(&peer_id, "another nonce", &public_key, &signature),
+            Err("invalid signature")
+        );
+        let other = Keypair::generate_ed25519().public().to_peer_id().to_string();
+        assert_eq!(
+            auth.verify_signature(&other, "nonce", &public_key, &signature),
+            Err("public key does not match peer id")
+        );
+        // A signature over the bare nonce could have been made for anything
+        let unprefixed = BASE64.encode(key.sign(b"nonce").unwrap());
+        assert_eq!(
+            auth.verify_signature(&peer_id, "nonce", &public_key, &unprefixed),
+            Err("invalid signature")
+        );
+        assert!(auth.verify_signature(&peer_id, "nonce", "%%", &signature).is_err());
+    }
+
+    type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    /// A test client on a signaling router served on an ephemeral port.
+    struct Client {
+        ws: Socket,
+        peer_id: String,
+        key: Keypair,
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr, key: Keypair) -> (Self, String) {
+            let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/signal", addr)).await.unwrap();
+            let peer_id = key.public().to_peer_id().to_string();
+            let mut client = Self { ws, peer_id, key };
+            let challenge = client.recv().await;
+            assert_eq!(challenge["type"], "Challenge");
+            let nonce = challenge["payload"]["nonce"].as_str().unwrap().to_string();
+            (client, nonce)
+        }
+
+        /// Connects and registers with a signed challenge.
+        async fn register(addr: SocketAddr, key: Keypair) -> Self {
+            let (mut client, nonce) = Self::connect(addr, key).await;
+            let (public_key, signature) = sign_challenge(&client.key, &nonce);
+            let peer_id = client.peer_id.clone();
+            client
+                .send(json!({
+                    "type": "Register",
+                    "payload": { "peer_id": peer_id, "public_key": public_key, "signature": signature },
+                }))
+                .await;
+            assert_eq!(client.recv().await["type"], "Registered");
+            assert_eq!(client.recv().await["type"], "IceServers");
+            client
+        }
+
+        async fn send(&mut self, message: Value) {
+            self.ws.send(tungstenite::Message::Text(message.to_string())).await.unwrap();
+        }
+
+        async fn next(&mut self) -> tungstenite::Message {
+            loop {
+                let message = tokio::time::timeout(Duration::from_secs(5), self.ws.next())
+                    .await
+                    .expect("no message within 5s")
+                    .expect("socket closed")
+                    .unwrap();
+                if !matches!(message, tungstenite::Message::Ping(_) | tungstenite::Message::Pong(_)) {
+                    return message;
+                }
+            }
+        }
+
+        async fn recv(&mut self) -> Value {
+            match self.next().await {
+                tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                other => panic!("expected a text message, got {:?}", other),
+            }
+        }
+
+        async fn offer(&mut self, to: &str, id: &str) {
+            let from = self.peer_id.clone();
+            self.send(json!({ "id": id, "type": "Offer", "payload": { "from": from, "to": to, "sdp": "v=0" } }))
+                .await;
+        }
+    }
+
+    async fn serve() -> SocketAddr {
+        let limits = SignalingLimits {
+            max_message_size: 65536,
+            messages_per_sec: 100.0,
+            burst: 100.0,
+            ip_messages_per_sec: 1000.0,
+            ip_burst: 1000.0,
+            ip_connections: 16,
+            outgoing_queue: 16,
+            overflow_policy: OverflowPolicy::Drop,
+            ping_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(60),
+        };
+        let auth = SignalingAuth::new(Some(SECRET.to_string()));
+        let app = router(auth, IceProvider::default(), limits, Arc::new(Monitoring::new()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap()
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn relays_reach_the_target_and_departures_reach_only_contacts() {
+        let addr = serve().await;
+        let mut alice = Client::register(addr, Keypair::generate_ed25519()).await;
+        let mut bob = Client::register(addr, Keypair::generate_ed25519()).await;
+        let mut carol = Client::register(addr, Keypair::generate_ed25519()).await;
+
+        let bob_id = bob.peer_id.clone();
+        alice.offer(&bob_id, "1").await;
+        assert_eq!(alice.recv().await, json!({ "type": "Ack", "payload": { "ref": "1" } }));
+        let offer = bob.recv().await;
+        assert_eq!(offer["type"], "Offer");
+        assert_eq!(offer["payload"]["from"], alice.peer_id.as_str());
+
+        // Stats are only taken for peers this client has signaled with
+        let stats = |from: &str, peer: &str| {
+            json!({
+                "id": "stats",
+                "type": "Stats",
+                "payload": { "from": from, "peer": peer, "bytes_sent": 1, "bytes_received": 1 },
+            })
+        };
+        let (alice_id, carol_id) = (alice.peer_id.clone(), carol.peer_id.clone());
+        alice.send(stats(&alice_id, &carol_id)).await;
+        assert_eq!(alice.recv().await["payload"]["code"], "unknown_peer");
+        alice.send(stats(&alice_id, &bob_id)).await;
+        assert_eq!(alice.recv().await["type"], "Ack");
+
+        // Nobody may relay in someone else's name
+        carol.offer(&bob_id, "2").await;
+        assert_eq!(carol.recv().await["type"], "Ack");
+        carol.send(json!({ "type": "Offer", "payload": { "from": alice_id, "to": bob_id, "sdp": "" } })).await;
+        assert_eq!(carol.recv().await["payload"]["code"], "sender_mismatch");
+        assert_eq!(bob.recv().await["payload"]["from"], carol_id.as_str());
+
+        alice.ws.close(None).await.unwrap();
+        let left = bob.recv().await;
+        assert_eq!(left, json!({ "type": "PeerLeft", "payload": { "peer_id": alice_id } }));
+        // Carol never signaled with Alice, so the next thing she hears is her own reply
+        carol.offer(&alice_id, "3").await;
+        assert_eq!(carol.recv().await["payload"]["code"], "unknown_peer");
+    }
+
+    #[tokio::test]
+    async fn same_key_takes_over_and_the_old_session_learns_why() {
+        let addr = serve().await;
+        let key = Keypair::generate_ed25519();
+        let mut first = Client::register(addr, key.clone()).await;
+        let mut second = Client::register(addr, key).await;
+
+        let replaced = first.recv().await;
+        assert_eq!(replaced["payload"]["code"], "session_replaced");
+        assert!(matches!(first.next().await, tungstenite::Message::Close(Some(_))));
+
+        // The new session is the one relays go to
+        let mut other = Client::register(addr, Keypair::generate_ed25519()).await;
+        let second_id = second.peer_id.clone();
+        other.offer(&second_id, "1").await;
+        assert_eq!(other.recv().await["type"], "Ack");
+        assert_eq!(second.recv().await["type"], "Offer");
+    }
+
+    #[tokio::test]
+    async fn a_registered_id_cannot_be_taken_with_another_credential() {
+        let addr = serve().await;
+        let owner = Client::register(addr, Keypair::generate_ed25519()).await;
+
+        let (mut intruder, _) = Client::connect(addr, Keypair::generate_ed25519()).await;
+        let expires = unix_now() + 600;
+        intruder
+            .send(json!({
+                "type": "Register",
+                "payload": { "peer_id": owner.peer_id, "token": token(SECRET, &owner.peer_id, expires), "expires": expires },
+            }))
+            .await;
+        assert_eq!(intruder.recv().await["payload"]["code"], "peer_id_taken");
+
+        let forged = token("guessed", "someone", expires);
+        intruder
+            .send(json!({ "type": "Register", "payload": { "peer_id": "someone", "token": forged, "expires": expires } }))
+            .await;
+        assert_eq!(intruder.recv().await["payload"]["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn binary_frames_are_refused_and_close_frames_echoed() {
+        let addr = serve().await;
+        let mut client = Client::register(addr, Keypair::generate_ed25519()).await;
+        client.ws.send(tungstenite::Message::Binary(vec![1, 2, 3])).await.unwrap();
+        assert_eq!(client.recv().await["payload"]["code"], "unsupported");
+
+        client.ws.send(tungstenite::Message::Close(None)).await.unwrap();
+        assert!(matches!(client.next().await, tungstenite::Message::Close(_)));
     }
 }
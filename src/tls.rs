@@ -0,0 +1,129 @@
+use std::{
+    error::Error as StdError,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+type BoxError = Box<dyn StdError + Send + Sync + 'static>;
+
+/// Serve the web, signaling and metrics listeners over TLS instead of
+/// relying on a reverse proxy.
+#[derive(clap::Args, Debug, Clone)]
+pub struct TlsArgs {
+    /// PEM certificate chain; enables HTTPS/WSS on the web, signaling and metrics listeners
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Seconds between checks for a renewed certificate or key
+    #[arg(long, default_value = "30")]
+    pub tls_reload_interval: u64,
+}
+
+/// Hands out the current certificate, swapped in place when the files on
+/// disk change so renewals need no restart.
+#[derive(Debug)]
+struct ReloadingCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl ReloadingCert {
+    fn watch(self: Arc<Self>, interval: Duration) {
+        let weak = Arc::downgrade(&self);
+        let mut seen = modified(&self.cert_path, &self.key_path);
+        drop(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(cert) = weak.upgrade() else { break };
+                let now = modified(&cert.cert_path, &cert.key_path);
+                if now == seen {
+                    continue;
+                }
+                // Remember this version even if it fails, so a half-written
+                // renewal is retried once the other file changes
+                seen = now;
+                match load_certified_key(&cert.cert_path, &cert.key_path) {
+                    Ok(key) => {
+                        *cert.current.write().unwrap() = Arc::new(key);
+                        println!("Reloaded TLS certificate from {}", cert.cert_path.display());
+                    }
+                    Err(e) => eprintln!("Keeping previous TLS certificate: {}", e),
+                }
+            }
+        });
+    }
+}
+
+fn modified(cert: &Path, key: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    (mtime(cert), mtime(key))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, BoxError> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate in {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", cert_path.display()).into());
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|e| format!("invalid private key in {}: {}", key_path.display(), e))?
+        .ok_or_else(|| format!("no private key found in {}", key_path.display()))?;
+
+    CertifiedKey::from_der(certs, key, &provider())
+        .map_err(|e| format!("{} does not fit {}: {}", key_path.display(), cert_path.display(), e).into())
+}
+
+/// Loads the configured certificate and starts watching it for changes.
+/// `None` when TLS is not configured.
+pub fn server_config(args: &TlsArgs) -> Result<Option<Arc<ServerConfig>>, BoxError> {
+    let (Some(cert_path), Some(key_path)) = (&args.tls_cert, &args.tls_key) else {
+        return Ok(None);
+    };
+    let cert = Arc::new(ReloadingCert {
+        cert_path: cert_path.clone(),
+        key_path: key_path.clone(),
+        current: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+    });
+    cert.clone().watch(Duration::from_secs(args.tls_reload_interval.max(1)));
+
+    let mut config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(cert);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Some(Arc::new(config)))
+}
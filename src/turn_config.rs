@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+use std::{error::Error as StdError, fmt, fs, path::Path};
+
+use crate::signaling::IceServer;
+
+const MIN_MONITORING_INTERVAL_MS: u64 = 100;
+/// Host of the example TURN server shipped in the docs
+const PLACEHOLDER_TURN_HOST: &str = "your-turn-server.com";
+const MAX_MONITORING_INTERVAL_MS: u64 = 3_600_000;
+
+/// `config/turn_config.json`: the ICE servers offered to browsers and how
+/// TURN bandwidth monitoring runs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TurnConfig {
+    pub stun: StunSection,
+    #[serde(default)]
+    pub turn: Option<TurnSection>,
+    #[serde(default)]
+    pub monitoring: MonitoringSection,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StunSection {
+    pub urls: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TurnSection {
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub credential: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MonitoringSection {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default)]
+    pub log_file: Option<String>,
+}
+
+fn default_interval_ms() -> u64 {
+    5000
+}
+
+impl Default for MonitoringSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_ms: default_interval_ms(),
+            log_file: None,
+        }
+    }
+}
+
+impl MonitoringSection {
+    /// Where stats reports are logged, if monitoring is on.
+    pub fn log_file(&self) -> Option<&str> {
+        self.log_file.as_deref().filter(|_| self.enabled)
+    }
+}
+
+/// What browsers get from `/config/ice`. The route is public, so it carries
+/// STUN URLs only; TURN servers and their credentials reach a client in the
+/// signaling `IceServers` message once it has registered.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientConfig {
+    pub ice_servers: Vec<IceServer>,
+    /// How often to post `getStats()` summaries; absent when monitoring is off
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats_interval_ms: Option<u64>,
+    /// Port of the signaling listener; absent when it shares the page's origin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signaling_port: Option<u16>,
+}
+
+/// Everything wrong with a config file, reported together so it can be fixed
+/// in one pass.
+#[derive(Debug)]
+pub struct ConfigError {
+    path: String,
+    problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid TURN config {}:", self.path)?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for ConfigError {}
+
+impl TurnConfig {
+    /// Reads and validates the config at `path`. With a TURN REST secret the
+    /// TURN servers get minted credentials, so static ones are not required.
+    pub fn load(path: impl AsRef<Path>, rest_secret: bool) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let fail = |problem: String| ConfigError {
+            path: path.display().to_string(),
+            problems: vec![problem],
+        };
+        let contents = fs::read_to_string(path)
+            .map_err(|e| fail(format!("cannot read file ({}); create it or pass --turn-config", e)))?;
+        let config: TurnConfig = serde_json::from_str(&contents).map_err(|e| fail(format!("not valid JSON for this schema: {}", e)))?;
+
+        let problems = config.problems(rest_secret);
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError {
+                path: path.display().to_string(),
+                problems,
+            })
+        }
+    }
+
+    fn problems(&self, rest_secret: bool) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.stun.urls.is_empty() {
+            problems.push("stun.urls must list at least one server, e.g. \"stun:stun.l.google.com:19302\"".to_string());
+        }
+        for url in &self.stun.urls {
+            check_url(&mut problems, "stun.urls", url, &["stun:", "stuns:"]);
+        }
+
+        if let Some(turn) = &self.turn {
+            if turn.urls.is_empty() {
+                problems.push("turn.urls must list at least one server, or remove the turn section".to_string());
+            }
+            for url in &turn.urls {
+                check_url(&mut problems, "turn.urls", url, &["turn:", "turns:"]);
+                if url.contains(PLACEHOLDER_TURN_HOST) {
+                    problems.push(format!("turn.urls entry {:?} is the example server; replace it with your own", url));
+                }
+            }
+            if !rest_secret {
+                if turn.username.as_deref().is_none_or(str::is_empty) {
+                    problems.push("turn.username is required for TURN servers unless --turn-secret is set".to_string());
+                }
+                if turn.credential.as_deref().is_none_or(str::is_empty) {
+                    problems.push("turn.credential is required for TURN servers unless --turn-secret is set".to_string());
+                }
+            }
+        }
+
+        let monitoring = &self.monitoring;
+        if !(MIN_MONITORING_INTERVAL_MS..=MAX_MONITORING_INTERVAL_MS).contains(&monitoring.interval_ms) {
+            problems.push(format!(
+                "monitoring.interval_ms is {}, must be between {} and {}",
+                monitoring.interval_ms, MIN_MONITORING_INTERVAL_MS, MAX_MONITORING_INTERVAL_MS
+            ));
+        }
+        if monitoring.enabled && monitoring.log_file.as_deref().is_none_or(str::is_empty) {
+            problems.push("monitoring.log_file is required when monitoring.enabled is true".to_string());
+        }
+
+        problems
+    }
+
+    pub fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            ice_servers: self.ice_servers(),
+            stats_interval_ms: self.monitoring.enabled.then_some(self.monitoring.interval_ms),
+            signaling_port: None,
+        }
+    }
+
+    /// The validated STUN servers in `RTCConfiguration.iceServers` form.
+    fn ice_servers(&self) -> Vec<IceServer> {
+        vec![IceServer {
+            urls: self.stun.urls.clone(),
+            username: None,
+            credential: None,
+        }]
+    }
+}
+
+fn check_url(problems: &mut Vec<String>, field: &str, url: &str, schemes: &[&str]) {
+    let Some(scheme) = schemes.iter().find(|scheme| url.starts_with(**scheme)) else {
+        problems.push(format!("{} entry {:?} must start with {}", field, url, schemes.join(" or ")));
+        return;
+    };
+    // host[:port][?transport=udp|tcp]
+    let rest = &url[scheme.len()..];
+    let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (host, port) = match authority.strip_prefix('[') {
+        // [ipv6]:port
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((host, rest)) => (host, rest.strip_prefix(':')),
+            None => ("", None),
+        },
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        problems.push(format!("{} entry {:?} has no host", field, url));
+    }
+    if let Some(port) = port {
+        if !matches!(port.parse::<u16>(), Ok(port) if port != 0) {
+            problems.push(format!("{} entry {:?} has an invalid port {:?}", field, url, port));
+        }
+    }
+    if !query.is_empty() && query != "transport=udp" && query != "transport=tcp" {
+        problems.push(format!("{} entry {:?} has an unsupported query {:?}, expected transport=udp or transport=tcp", field, url, query));
+    }
+}
@@ -0,0 +1,439 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::UdpSocket, sync::mpsc};
+use turn::{
+    auth::{generate_auth_key, AuthHandler},
+    relay::{relay_range::RelayAddressGeneratorRanges, RelayAddressGenerator},
+    server::{
+        config::{ConnConfig, ServerConfig},
+        Server,
+    },
+};
+use webrtc_util::{vnet::net::Net, Conn};
+
+use crate::monitoring::Monitoring;
+use crate::rate_limit::TokenBucket;
+
+/// Command-line options for the built-in STUN/TURN server.
+#[derive(clap::Args, Debug, Clone)]
+pub struct TurnArgs {
+    /// UDP port for the built-in STUN/TURN server
+    #[arg(long, default_value = "3478")]
+    pub turn_port: u16,
+
+    /// Public IP address handed to clients as the relay address
+    #[arg(long, default_value = "127.0.0.1")]
+    pub turn_public_ip: IpAddr,
+
+    /// TURN authentication realm
+    #[arg(long, default_value = "hippius")]
+    pub turn_realm: String,
+
+    /// Static TURN credentials as USER=PASSWORD (repeatable)
+    #[arg(long = "turn-user", value_name = "USER=PASSWORD")]
+    pub turn_users: Vec<String>,
+
+    /// Lowest UDP port used for relay allocations
+    #[arg(long, default_value = "49152")]
+    pub turn_min_port: u16,
+
+    /// Highest UDP port used for relay allocations
+    #[arg(long, default_value = "65535")]
+    pub turn_max_port: u16,
+
+    /// Maximum simultaneous relay allocations (0 for unlimited)
+    #[arg(long, default_value = "100")]
+    pub turn_max_allocations: usize,
+
+    /// Relay bandwidth per allocation in bytes per second; excess packets are dropped (0 for unlimited)
+    #[arg(long, default_value = "0")]
+    pub turn_allocation_bandwidth: u64,
+
+    /// Total bytes an allocation may relay before it stops forwarding (0 for unlimited)
+    #[arg(long, default_value = "0")]
+    pub turn_allocation_quota: u64,
+
+    /// Shared secret for time-limited TURN REST API credentials, issued to
+    /// signaling clients and accepted by the built-in TURN server
+    #[arg(long)]
+    pub turn_secret: Option<String>,
+
+    /// TURN URLs advertised to signaling clients (repeatable; defaults to the
+    /// built-in server when --turn-secret is set)
+    #[arg(long = "turn-url", value_name = "URL")]
+    pub turn_urls: Vec<String>,
+
+    /// STUN URLs advertised to signaling clients (repeatable)
+    #[arg(long = "stun-url", value_name = "URL", default_value = "stun:stun.l.google.com:19302")]
+    pub stun_urls: Vec<String>,
+
+    /// Lifetime of issued TURN credentials in seconds
+    #[arg(long, default_value = "86400")]
+    pub turn_credential_ttl: u64,
+}
+
+impl TurnArgs {
+    /// TURN URLs to hand out: the configured ones, or the built-in server's.
+    pub fn advertised_turn_urls(&self) -> Vec<String> {
+        if !self.turn_urls.is_empty() || self.turn_secret.is_none() {
+            return self.turn_urls.clone();
+        }
+        vec![format!("turn:{}:{}?transport=udp", self.turn_public_ip, self.turn_port)]
+    }
+}
+
+/// Time-limited credentials following the TURN REST API scheme: the username
+/// is `<expiry unix time>:<user>` and the password is the base64 HMAC-SHA1 of
+/// that username under the shared secret.
+pub fn ephemeral_credentials(secret: &str, user: &str, ttl: Duration) -> (String, String) {
+    let expiry = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default() + ttl;
+    let username = format!("{}:{}", expiry.as_secs(), user);
+    let password = rest_api_password(secret, &username);
+    (username, password)
+}
+
+fn rest_api_password(secret: &str, username: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(username.as_bytes());
+    BASE64.encode(mac.finalize().into_bytes())
+}
+
+/// Long-term credentials: a fixed set of users, plus REST API usernames when
+/// a shared secret is configured.
+struct CredentialAuthHandler {
+    keys: HashMap<String, Vec<u8>>,
+    secret: Option<String>,
+}
+
+impl CredentialAuthHandler {
+    fn rest_api_key(&self, username: &str, realm: &str) -> Result<Vec<u8>, String> {
+        let secret = self.secret.as_deref().ok_or("unknown user")?;
+        let expiry = username
+            .split(':')
+            .next()
+            .and_then(|expiry| expiry.parse::<u64>().ok())
+            .ok_or("unknown user")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if expiry < now {
+            return Err("expired credentials".to_string());
+        }
+        Ok(generate_auth_key(username, realm, &rest_api_password(secret, username)))
+    }
+}
+
+impl AuthHandler for CredentialAuthHandler {
+    fn auth_handle(&self, username: &str, realm: &str, src_addr: SocketAddr) -> Result<Vec<u8>, turn::Error> {
+        if let Some(key) = self.keys.get(username) {
+            return Ok(key.clone());
+        }
+        self.rest_api_key(username, realm).map_err(|reason| {
+            println!("TURN authentication failed for {} from {}: {}", username, src_addr, reason);
+            turn::Error::Other(reason)
+        })
+    }
+}
+
+/// Per-allocation limits applied to relayed traffic.
+#[derive(Debug, Clone, Copy)]
+struct AllocationQuota {
+    bytes_per_sec: u64,
+    total_bytes: u64,
+}
+
+/// Allocates relay sockets from a port range, refusing new allocations past
+/// the configured maximum and wrapping each socket so its traffic is metered.
+struct QuotaRelayGenerator {
+    inner: RelayAddressGeneratorRanges,
+    max_allocations: usize,
+    active: Arc<AtomicUsize>,
+    quota: AllocationQuota,
+    monitoring: Arc<Monitoring>,
+}
+
+#[async_trait]
+impl RelayAddressGenerator for QuotaRelayGenerator {
+    fn validate(&self) -> Result<(), turn::Error> {
+        self.inner.validate()
+    }
+
+    async fn allocate_conn(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), turn::Error> {
+        let reserved = self.active.fetch_add(1, Ordering::SeqCst);
+        if self.max_allocations > 0 && reserved >= self.max_allocations {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            self.monitoring.record_turn_allocation_rejected().await;
+            return Err(turn::Error::Other("allocation quota reached".to_string()));
+        }
+
+        let (conn, relay_addr) = match self.inner.allocate_conn(use_ipv4, requested_port).await {
+            Ok(allocated) => allocated,
+            Err(e) => {
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+
+        let relay_address = relay_addr.to_string();
+        self.monitoring.record_turn_allocation_created(&relay_address).await;
+        println!("TURN allocation created: {}", relay_address);
+
+        let limiter = (self.quota.bytes_per_sec > 0).then(|| {
+            let rate = self.quota.bytes_per_sec as f64;
+            Mutex::new(TokenBucket::new(rate, rate))
+        });
+        let conn = MeteredRelayConn {
+            inner: conn,
+            relay_address,
+            limiter,
+            total_quota: self.quota.total_bytes,
+            relayed: AtomicU64::new(0),
+            active: self.active.clone(),
+            closed: AtomicBool::new(false),
+            monitoring: self.monitoring.clone(),
+        };
+        Ok((Arc::new(conn), relay_addr))
+    }
+}
+
+/// Relay socket of a single allocation. Counts traffic in both directions and
+/// drops packets once the allocation exceeds its bandwidth or byte quota.
+struct MeteredRelayConn {
+    inner: Arc<dyn Conn + Send + Sync>,
+    relay_address: String,
+    limiter: Option<Mutex<TokenBucket>>,
+    total_quota: u64,
+    relayed: AtomicU64,
+    active: Arc<AtomicUsize>,
+    closed: AtomicBool,
+    monitoring: Arc<Monitoring>,
+}
+
+impl MeteredRelayConn {
+    /// Checks a packet of `len` bytes against the quotas. Returns the reason
+    /// it must be dropped, if any.
+    fn admit(&self, len: usize) -> Option<&'static str> {
+        let len = len as u64;
+        if self.total_quota > 0 && self.relayed.load(Ordering::Relaxed) + len > self.total_quota {
+            return Some("quota");
+        }
+        if let Some(limiter) = &self.limiter {
+            if !limiter.lock().unwrap().try_take_n(len as f64) {
+                return Some("bandwidth");
+            }
+        }
+        self.relayed.fetch_add(len, Ordering::Relaxed);
+        None
+    }
+
+    /// Frees the allocation slot. Returns false if it was already freed.
+    fn release(&self) -> bool {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        true
+    }
+}
+
+#[async_trait]
+impl Conn for MeteredRelayConn {
+    async fn connect(&self, addr: SocketAddr) -> webrtc_util::Result<()> {
+        self.inner.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        self.inner.recv(buf).await
+    }
+
+    /// Packets arriving from peers, to be relayed to the client
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        loop {
+            let (n, addr) = self.inner.recv_from(buf).await?;
+            match self.admit(n) {
+                None => {
+                    self.monitoring.record_turn_relayed(&self.relay_address, false, n as u64).await;
+                    return Ok((n, addr));
+                }
+                Some(reason) => self.monitoring.record_turn_dropped(&self.relay_address, reason).await,
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        self.inner.send(buf).await
+    }
+
+    /// Packets from the client, relayed out to a peer
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc_util::Result<usize> {
+        if let Some(reason) = self.admit(buf.len()) {
+            self.monitoring.record_turn_dropped(&self.relay_address, reason).await;
+            // UDP semantics: a dropped datagram is not an error for the sender
+            return Ok(buf.len());
+        }
+        let n = self.inner.send_to(buf, target).await?;
+        self.monitoring.record_turn_relayed(&self.relay_address, true, n as u64).await;
+        Ok(n)
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        if self.release() {
+            self.monitoring.record_turn_allocation_closed(&self.relay_address).await;
+            println!("TURN allocation closed: {}", self.relay_address);
+        }
+        self.inner.close().await
+    }
+}
+
+impl Drop for MeteredRelayConn {
+    fn drop(&mut self) {
+        // Free the slot even if the allocation went away without close()
+        if self.release() {
+            let monitoring = self.monitoring.clone();
+            let relay_address = self.relay_address.clone();
+            if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                handle.spawn(async move {
+                    monitoring.record_turn_allocation_closed(&relay_address).await;
+                });
+            }
+        }
+    }
+}
+
+fn parse_users(users: &[String], realm: &str) -> Result<HashMap<String, Vec<u8>>, String> {
+    users
+        .iter()
+        .map(|entry| {
+            let (user, password) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("invalid --turn-user '{}': expected USER=PASSWORD", entry))?;
+            Ok((user.to_string(), generate_auth_key(user, realm, password)))
+        })
+        .collect()
+}
+
+/// Runs the STUN binding responder and TURN relay (RFC 5766 allocations,
+/// permissions and channel bindings) on a single UDP port.
+pub async fn start_turn_server(args: TurnArgs, monitoring: Arc<Monitoring>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let keys = parse_users(&args.turn_users, &args.turn_realm)?;
+    if keys.is_empty() && args.turn_secret.is_none() {
+        println!("No --turn-user or --turn-secret configured: STUN works, but TURN allocations will be refused");
+    }
+
+    let conn = Arc::new(UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], args.turn_port))).await?);
+
+    let relay_addr_generator = QuotaRelayGenerator {
+        inner: RelayAddressGeneratorRanges {
+            relay_address: args.turn_public_ip,
+            min_port: args.turn_min_port,
+            max_port: args.turn_max_port,
+            max_retries: 10,
+            address: "0.0.0.0".to_string(),
+            net: Arc::new(Net::new(None)),
+        },
+        max_allocations: args.turn_max_allocations,
+        active: Arc::new(AtomicUsize::new(0)),
+        quota: AllocationQuota {
+            bytes_per_sec: args.turn_allocation_bandwidth,
+            total_bytes: args.turn_allocation_quota,
+        },
+        monitoring,
+    };
+
+    let (close_tx, mut close_rx) = mpsc::channel(16);
+    let server = Server::new(ServerConfig {
+        conn_configs: vec![ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(relay_addr_generator),
+        }],
+        realm: args.turn_realm.clone(),
+        auth_handler: Arc::new(CredentialAuthHandler {
+            keys,
+            secret: args.turn_secret.clone(),
+        }),
+        channel_bind_timeout: Duration::from_secs(600),
+        alloc_close_notify: Some(close_tx),
+    })
+    .await?;
+
+    println!(
+        "Starting STUN/TURN server on udp/{} (relay address {})",
+        args.turn_port, args.turn_public_ip
+    );
+
+    // The server runs on its own tasks; stay here for as long as it is up
+    while let Some(info) = close_rx.recv().await {
+        println!("TURN allocation for {} ended ({})", info.username, info.five_tuple);
+    }
+
+    server.close().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "north-wind";
+    const REALM: &str = "hippius";
+
+    fn handler(secret: Option<&str>) -> CredentialAuthHandler {
+        CredentialAuthHandler {
+            keys: HashMap::new(),
+            secret: secret.map(String::from),
+        }
+    }
+
+    fn client() -> SocketAddr {
+        "127.0.0.1:50000".parse().unwrap()
+    }
+
+    #[test]
+    fn rest_api_password_matches_known_vector() {
+        // base64(HMAC-SHA1("north-wind", "1700000000:alice")), as coturn computes it
+        assert_eq!(rest_api_password(SECRET, "1700000000:alice"), "Oko4dt8u/EbTRjRUJWQDFm/zTCc=");
+        assert_eq!(rest_api_password(SECRET, "1700000000:bob"), "MdroMDBrNzlxdGm99JYZ1wMs1Hw=");
+    }
+
+    #[test]
+    fn issued_credentials_authenticate_until_they_expire() {
+        let (username, password) = ephemeral_credentials(SECRET, "alice", Duration::from_secs(600));
+        let (expiry, user) = username.split_once(':').unwrap();
+        assert_eq!(user, "alice");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!((now + 599..=now + 601).contains(&expiry.parse::<u64>().unwrap()));
+
+        let key = handler(Some(SECRET)).auth_handle(&username, REALM, client()).unwrap();
+        assert_eq!(key, generate_auth_key(&username, REALM, &password));
+        // A password minted under another secret yields a key the client cannot match
+        let (_, forged) = ephemeral_credentials("guessed", "alice", Duration::from_secs(600));
+        assert_ne!(key, generate_auth_key(&username, REALM, &forged));
+
+        let expired = format!("{}:alice", now - 1);
+        assert!(handler(Some(SECRET)).auth_handle(&expired, REALM, client()).is_err());
+        assert!(handler(Some(SECRET)).auth_handle("alice", REALM, client()).is_err());
+        assert!(handler(None).auth_handle(&username, REALM, client()).is_err());
+    }
+}
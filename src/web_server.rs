@@ -1,8 +1,231 @@
-use warp::Filter;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
-pub async fn start_web_server(port: u16) {
-    let web_dir = warp::fs::dir("web");
-    
-    println!("Starting web server on port {}", port);
-    warp::serve(web_dir).run(([0, 0, 0, 0], port)).await;
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{self, header, HeaderMap, HeaderValue, StatusCode, Uri},
+    middleware,
+    response::{Json, Response},
+    routing::get,
+    Router,
+};
+use percent_encoding::percent_decode_str;
+use rust_embed::RustEmbed;
+use sha2::{Digest, Sha256};
+
+use crate::turn_config::ClientConfig;
+
+const INDEX: &str = "index.html";
+
+/// Keeps browsers from framing the app or loading anything but our own
+/// scripts and styles. Signaling runs on another port, so any ws/wss origin is allowed.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'self'; script-src 'self'; style-src 'self'; \
+     img-src 'self' data:; connect-src 'self' ws: wss:; object-src 'none'; base-uri 'self'; \
+     frame-ancestors 'none'";
+
+/// Compressed variants kept before the cache is reset.
+const MAX_CACHED_VARIANTS: usize = 256;
+
+#[derive(RustEmbed)]
+#[folder = "web/"]
+struct EmbeddedAssets;
+
+/// Where static files come from: compiled into the binary, or a directory
+/// given with `--web-root`.
+enum AssetSource {
+    Embedded,
+    Dir(PathBuf),
+}
+
+impl AssetSource {
+    fn load(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        match self {
+            AssetSource::Embedded => EmbeddedAssets::get(path).map(|file| file.data),
+            AssetSource::Dir(root) => std::fs::read(root.join(path)).ok().map(Cow::Owned),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Best encoding the client accepts, preferring brotli.
+    fn negotiate(accept_encoding: Option<&str>) -> Option<Self> {
+        let accepted: Vec<&str> = accept_encoding?
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let name = parts.next()?;
+                let refused = parts.any(|param| param.replace(' ', "") == "q=0");
+                (!refused).then_some(name)
+            })
+            .collect();
+        if accepted.contains(&"br") {
+            Some(Encoding::Brotli)
+        } else if accepted.contains(&"gzip") {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 9, 22);
+                writer.write_all(data).expect("writing to a Vec cannot fail");
+                drop(writer);
+                out
+            }
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data).expect("writing to a Vec cannot fail");
+                encoder.finish().expect("writing to a Vec cannot fail")
+            }
+        }
+    }
+}
+
+/// Compressed bodies keyed by ETag and encoding
+type CompressedCache = HashMap<(String, &'static str), Bytes>;
+
+struct StaticFiles {
+    source: AssetSource,
+    compressed: Mutex<CompressedCache>,
+}
+
+impl StaticFiles {
+    fn respond(&self, tail: &str, accept_encoding: Option<&str>, if_none_match: Option<&str>) -> Response {
+        let Some(path) = sanitize(tail) else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+        let path = if path.is_empty() { INDEX.to_string() } else { path };
+
+        let (path, data) = match self.source.load(&path) {
+            Some(data) => (path, data),
+            // Client-side routes have no extension; anything else really is missing
+            None if !path.rsplit('/').next().unwrap_or_default().contains('.') => match self.source.load(INDEX) {
+                Some(data) => (INDEX.to_string(), data),
+                None => return status(StatusCode::NOT_FOUND),
+            },
+            None => return status(StatusCode::NOT_FOUND),
+        };
+
+        let etag = format!("\"{}\"", hex::encode(&Sha256::digest(&data)[..16]));
+        let mime = mime_guess::from_path(&path).first_or_octet_stream();
+        // Asset names carry no content hash, so only HTML is forced to revalidate every time
+        let cache_control = if path.ends_with(".html") { "no-cache" } else { "public, max-age=3600" };
+
+        let builder = http::Response::builder()
+            .header(header::ETAG, &etag)
+            .header(header::CACHE_CONTROL, cache_control)
+            .header(header::VARY, "Accept-Encoding");
+
+        if if_none_match.is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*")) {
+            return builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap();
+        }
+
+        let builder = builder.header(header::CONTENT_TYPE, mime.as_ref());
+        let compressible = matches!(mime.type_().as_str(), "text")
+            || matches!(mime.subtype().as_str(), "javascript" | "json" | "xml" | "svg");
+        match Encoding::negotiate(accept_encoding).filter(|_| compressible) {
+            Some(encoding) => {
+                let body = self.compressed(&etag, encoding, &data);
+                builder
+                    .header(header::CONTENT_ENCODING, encoding.name())
+                    .body(Body::from(body))
+                    .unwrap()
+            }
+            None => builder.body(Body::from(data.into_owned())).unwrap(),
+        }
+    }
+
+    fn compressed(&self, etag: &str, encoding: Encoding, data: &[u8]) -> Bytes {
+        let key = (etag.to_string(), encoding.name());
+        if let Some(body) = self.compressed.lock().unwrap().get(&key) {
+            return body.clone();
+        }
+        let body = Bytes::from(encoding.compress(data));
+        let mut cache = self.compressed.lock().unwrap();
+        if cache.len() >= MAX_CACHED_VARIANTS {
+            cache.clear();
+        }
+        cache.insert(key, body.clone());
+        body
+    }
+}
+
+/// Decodes a request path and refuses anything that could escape the web root.
+fn sanitize(tail: &str) -> Option<String> {
+    let decoded = percent_decode_str(tail).decode_utf8().ok()?;
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            s if s.contains('\\') || s.contains('\0') => return None,
+            s => segments.push(s),
+        }
+    }
+    Some(segments.join("/"))
+}
+
+fn status(code: StatusCode) -> Response {
+    http::Response::builder().status(code).body(Body::empty()).unwrap()
+}
+
+async fn security_headers(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static(CONTENT_SECURITY_POLICY));
+    headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(header::REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+    response
+}
+
+async fn static_file(State(files): State<Arc<StaticFiles>>, uri: Uri, headers: HeaderMap) -> Response {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    files.respond(uri.path(), header(header::ACCEPT_ENCODING), header(header::IF_NONE_MATCH))
+}
+
+/// The web client from `web_root`, or from the copy of `web/` built into the
+/// binary when no root is given, plus its ICE configuration. Unknown paths
+/// fall through to the static files, so this router supplies the fallback.
+pub fn router(web_root: Option<PathBuf>, client_config: ClientConfig) -> Router {
+    let source = match web_root {
+        Some(root) => {
+            println!("Serving web assets from {}", root.display());
+            AssetSource::Dir(root)
+        }
+        None => AssetSource::Embedded,
+    };
+    let files = Arc::new(StaticFiles {
+        source,
+        compressed: Mutex::new(HashMap::new()),
+    });
+
+    Router::new()
+        // Validated ICE configuration from config/turn_config.json
+        .route("/config/ice", get(move || async move { Json(client_config) }))
+        .fallback(get(static_file))
+        .with_state(files)
+        .layer(middleware::map_response(security_headers))
 }
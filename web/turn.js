@@ -1,124 +1,74 @@
+// Periodically summarises a peer connection's getStats() and hands the summary
+// to `report`, which posts it to the signaling server for aggregation and logging.
 class TURNMonitor {
-    constructor(peerConnection, config) {
+    constructor(peerConnection, intervalMs, report) {
         this.pc = peerConnection;
-        this.config = config;
-        this.stats = {
-            bytesReceived: 0,
-            bytesSent: 0,
-            timestamp: Date.now()
-        };
-        this.logFile = config.monitoring.log_file;
+        this.intervalMs = intervalMs;
+        this.report = report;
+        this.timer = null;
     }
 
-    async startMonitoring() {
-        if (!this.config.monitoring.enabled) return;
-        
-        setInterval(async () => {
-            const stats = await this.pc.getStats();
-            let currentStats = {
-                bytesReceived: 0,
-                bytesSent: 0,
-                timestamp: Date.now()
-            };
-
-            stats.forEach(report => {
-                if (report.type === 'transport') {
-                    currentStats.bytesReceived += report.bytesReceived || 0;
-                    currentStats.bytesSent += report.bytesSent || 0;
-                }
-            });
-
-            // Calculate bandwidth
-            const duration = (currentStats.timestamp - this.stats.timestamp) / 1000;
-            const receivedBandwidth = (currentStats.bytesReceived - this.stats.bytesReceived) / duration;
-            const sentBandwidth = (currentStats.bytesSent - this.stats.bytesSent) / duration;
-
-            // Log bandwidth usage
-            const logEntry = {
-                timestamp: new Date().toISOString(),
-                receivedBandwidth: `${(receivedBandwidth / 1024).toFixed(2)} KB/s`,
-                sentBandwidth: `${(sentBandwidth / 1024).toFixed(2)} KB/s`,
-                totalReceived: `${(currentStats.bytesReceived / (1024 * 1024)).toFixed(2)} MB`,
-                totalSent: `${(currentStats.bytesSent / (1024 * 1024)).toFixed(2)} MB`
-            };
-
-            console.log('TURN Bandwidth Usage:', logEntry);
-            this.logToFile(logEntry);
-
-            this.stats = currentStats;
-        }, this.config.monitoring.interval_ms);
+    startMonitoring() {
+        if (!this.intervalMs || this.timer) return;
+
+        this.timer = setInterval(async () => {
+            if (this.pc.connectionState === 'closed') {
+                this.stopMonitoring();
+                return;
+            }
+            const summary = await this.summarize();
+            if (summary) this.report(summary);
+        }, this.intervalMs);
     }
 
-    logToFile(entry) {
-        // In a real implementation, you'd want to send this to your server
-        // for logging. Here we just use console.log as an example.
-        const logEntry = `${entry.timestamp} - RX: ${entry.receivedBandwidth} - TX: ${entry.sentBandwidth}`;
-        console.log(logEntry);
+    stopMonitoring() {
+        clearInterval(this.timer);
+        this.timer = null;
     }
-}
 
-class TURNManager {
-    constructor() {
-        this.loadConfig();
-    }
+    async summarize() {
+        const stats = await this.pc.getStats();
+        const summary = {
+            bytes_sent: 0,
+            bytes_received: 0,
+            messages_sent: 0,
+            messages_received: 0,
+            rtt_ms: null,
+            local_candidate_type: null,
+            remote_candidate_type: null
+        };
 
-    async loadConfig() {
-        try {
-            const response = await fetch('/config/turn_config.json');
-            this.config = await response.json();
-        } catch (error) {
-            console.error('Failed to load TURN config:', error);
-            // Fallback to default configuration
-            this.config = {
-                stun: {
-                    urls: ['stun:stun.l.google.com:19302']
+        let selectedPairId = null;
+        stats.forEach(report => {
+            if (report.type === 'transport') {
+                summary.bytes_sent += report.bytesSent || 0;
+                summary.bytes_received += report.bytesReceived || 0;
+                selectedPairId = selectedPairId || report.selectedCandidatePairId;
+            } else if (report.type === 'data-channel') {
+                summary.messages_sent += report.messagesSent || 0;
+                summary.messages_received += report.messagesReceived || 0;
+            }
+        });
+
+        // Firefox has no transport report; fall back to the nominated pair
+        if (!selectedPairId) {
+            stats.forEach(report => {
+                if (report.type === 'candidate-pair' && report.nominated && report.state === 'succeeded') {
+                    selectedPairId = report.id;
+                    summary.bytes_sent = summary.bytes_sent || report.bytesSent || 0;
+                    summary.bytes_received = summary.bytes_received || report.bytesReceived || 0;
                 }
-            };
-        }
-    }
-
-    getIceServers() {
-        const iceServers = [];
-
-        // Add STUN servers
-        if (this.config.stun) {
-            iceServers.push({
-                urls: this.config.stun.urls
-            });
-        }
-
-        // Add TURN servers if configured
-        if (this.config.turn) {
-            iceServers.push({
-                urls: this.config.turn.urls,
-                username: this.config.turn.username,
-                credential: this.config.turn.credential
             });
         }
 
-        return iceServers;
-    }
-
-    createPeerConnection() {
-        const config = {
-            iceServers: this.getIceServers(),
-            iceTransportPolicy: 'all', // Use 'relay' to force TURN
-            bundlePolicy: 'max-bundle',
-            rtcpMuxPolicy: 'require',
-            enableDtlsSrtp: true
-        };
+        const pair = selectedPairId && stats.get(selectedPairId);
+        if (!pair) return null;
 
-        const pc = new RTCPeerConnection(config);
-        
-        // Create and start bandwidth monitor
-        if (this.config.monitoring?.enabled) {
-            const monitor = new TURNMonitor(pc, this.config);
-            monitor.startMonitoring();
+        if (pair.currentRoundTripTime !== undefined) {
+            summary.rtt_ms = pair.currentRoundTripTime * 1000;
         }
-
-        return pc;
+        summary.local_candidate_type = stats.get(pair.localCandidateId)?.candidateType || null;
+        summary.remote_candidate_type = stats.get(pair.remoteCandidateId)?.candidateType || null;
+        return summary;
     }
 }
-
-// Export for use in webrtc.js
-window.TURNManager = TURNManager;
@@ -1,11 +1,30 @@
 class TURNManager {
     constructor() {
         this.config = {
-            iceServers: [
-                { urls: 'stun:stun.l.google.com:19302' },
-                // Add your TURN servers here for production
-            ]
+            iceServers: []
         };
+        this.statsIntervalMs = null;
+        // Signaling shares the page's origin unless the server names its own port
+        this.signalingPort = 8001;
+    }
+
+    // STUN servers from config/turn_config.json, served by the web server.
+    // TURN servers and their credentials arrive over signaling after registration.
+    async load() {
+        try {
+            const response = await fetch('/config/ice');
+            if (!response.ok) throw new Error(`HTTP ${response.status}`);
+            const { iceServers, statsIntervalMs, signalingPort } = await response.json();
+            this.setIceServers(iceServers);
+            this.statsIntervalMs = statsIntervalMs || null;
+            this.signalingPort = signalingPort || null;
+        } catch (error) {
+            console.error('Failed to load ICE configuration:', error);
+        }
+    }
+
+    setIceServers(iceServers) {
+        this.config = { ...this.config, iceServers };
     }
 
     createPeerConnection() {
@@ -13,14 +32,72 @@ class TURNManager {
     }
 }
 
+const BASE58_ALPHABET = '123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz';
+
+function base58Encode(bytes) {
+    const digits = [0];
+    for (const byte of bytes) {
+        let carry = byte;
+        for (let i = 0; i < digits.length; i++) {
+            carry += digits[i] << 8;
+            digits[i] = carry % 58;
+            carry = (carry / 58) | 0;
+        }
+        while (carry > 0) {
+            digits.push(carry % 58);
+            carry = (carry / 58) | 0;
+        }
+    }
+    let output = '';
+    for (let i = 0; i < bytes.length && bytes[i] === 0; i++) output += '1';
+    for (let i = digits.length - 1; i >= 0; i--) output += BASE58_ALPHABET[digits[i]];
+    return output;
+}
+
+function base64Encode(bytes) {
+    return btoa(String.fromCharCode(...bytes));
+}
+
+// Ed25519 identity whose peer ID matches what libp2p derives for the same key,
+// so the signaling server can verify we own the ID we register with.
+class PeerIdentity {
+    static async generate() {
+        const keyPair = await crypto.subtle.generateKey({ name: 'Ed25519' }, false, ['sign', 'verify']);
+        const raw = new Uint8Array(await crypto.subtle.exportKey('raw', keyPair.publicKey));
+        // Protobuf-encoded libp2p PublicKey { Type: Ed25519, Data: raw }
+        const publicKey = new Uint8Array([0x08, 0x01, 0x12, raw.length, ...raw]);
+        // Identity multihash of the encoded key
+        const peerId = base58Encode(new Uint8Array([0x00, publicKey.length, ...publicKey]));
+        return new PeerIdentity(keyPair.privateKey, publicKey, peerId);
+    }
+
+    constructor(privateKey, publicKey, peerId) {
+        this.privateKey = privateKey;
+        this.publicKey = publicKey;
+        this.peerId = peerId;
+    }
+
+    async sign(text) {
+        const data = new TextEncoder().encode(text);
+        return new Uint8Array(await crypto.subtle.sign({ name: 'Ed25519' }, this.privateKey, data));
+    }
+}
+
 class P2PChat {
     constructor() {
-        this.peer_id = 'peer_' + Math.random().toString(36).substr(2, 9);
         this.peers = new Map(); // peer_id -> RTCPeerConnection
         this.dataChannels = new Map(); // peer_id -> RTCDataChannel
         this.ws = null;
         this.turnManager = new TURNManager();
-        
+
+        this.initialize();
+    }
+
+    async initialize() {
+        this.identity = await PeerIdentity.generate();
+        this.peer_id = this.identity.peerId;
+        await this.turnManager.load();
+
         this.initializeUI();
         this.connectToSignalingServer();
     }
@@ -49,15 +126,15 @@ class P2PChat {
     }
 
     connectToSignalingServer() {
-        this.ws = new WebSocket('ws://localhost:8000/signal');
+        // Signaling uses TLS whenever the page has it
+        const scheme = location.protocol === 'https:' ? 'wss' : 'ws';
+        const port = this.turnManager.signalingPort;
+        const host = port ? `${location.hostname || 'localhost'}:${port}` : location.host;
+        this.ws = new WebSocket(`${scheme}://${host}/signal`);
 
         this.ws.onopen = () => {
             this.statusElement.textContent = 'Connected to signaling server';
             this.statusElement.classList.add('connected');
-            this.ws.send(JSON.stringify({
-                type: 'Register',
-                payload: { peer_id: this.peer_id }
-            }));
         };
 
         this.ws.onclose = () => {
@@ -71,8 +148,40 @@ class P2PChat {
         };
     }
 
+    async register({ nonce }) {
+        const signature = await this.identity.sign(`hippius-signaling-register:${nonce}`);
+        this.ws.send(JSON.stringify({
+            type: 'Register',
+            payload: {
+                peer_id: this.peer_id,
+                public_key: base64Encode(this.identity.publicKey),
+                signature: base64Encode(signature)
+            }
+        }));
+    }
+
     async handleSignalingMessage(message) {
         switch (message.type) {
+            case 'Challenge':
+                await this.register(message.payload);
+                break;
+            case 'Registered':
+                this.statusElement.textContent = 'Registered with signaling server';
+                break;
+            case 'IceServers':
+                this.turnManager.setIceServers(message.payload.ice_servers);
+                break;
+            case 'Ack':
+                break;
+            case 'PeerLeft':
+                this.handlePeerLeft(message.payload);
+                break;
+            case 'Error':
+                console.error('Signaling error:', message.payload);
+                if (message.payload.code === 'unknown_peer') {
+                    this.addMessage('System', message.payload.message);
+                }
+                break;
             case 'Offer':
                 await this.handleOffer(message.payload);
                 break;
@@ -112,9 +221,21 @@ class P2PChat {
     async createPeerConnection(peerId) {
         const pc = this.turnManager.createPeerConnection();
         this.setupPeerConnection(pc, peerId);
+        this.monitorPeerConnection(pc, peerId);
         return pc;
     }
 
+    monitorPeerConnection(pc, peerId) {
+        const monitor = new TURNMonitor(pc, this.turnManager.statsIntervalMs, (summary) => {
+            if (this.ws?.readyState !== WebSocket.OPEN) return;
+            this.ws.send(JSON.stringify({
+                type: 'Stats',
+                payload: { from: this.peer_id, peer: peerId, ...summary }
+            }));
+        });
+        monitor.startMonitoring();
+    }
+
     async handleOffer({ from, sdp }) {
         const peerConnection = await this.createPeerConnection(from);
         this.peers.set(from, peerConnection);
@@ -153,6 +274,16 @@ class P2PChat {
         }
     }
 
+    handlePeerLeft({ peer_id }) {
+        const peerConnection = this.peers.get(peer_id);
+        if (peerConnection && peerConnection.connectionState !== 'connected') {
+            peerConnection.close();
+            this.peers.delete(peer_id);
+            this.dataChannels.delete(peer_id);
+        }
+        this.updatePeerStatus(peer_id, 'left signaling');
+    }
+
     setupPeerConnection(peerConnection, peerId) {
         peerConnection.onicecandidate = (event) => {
             if (event.candidate) {